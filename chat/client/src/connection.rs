@@ -0,0 +1,233 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use crate::models::ClientMessage;
+use crate::{Error, Event, ServerEvent, Session};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Clone)]
+pub struct Sender {
+    outgoing: mpsc::UnboundedSender<String>,
+}
+
+impl Sender {
+    pub fn send(&self, message: &ClientMessage) -> Result<(), Error> {
+        let text = serde_json::to_string(message)?;
+        self.outgoing.send(text).map_err(|_| Error::Closed)
+    }
+
+    pub fn send_text(&self, recipient: &str, content: &str) -> Result<(), Error> {
+        self.send(&ClientMessage {
+            msg_type: "message".to_string(),
+            recipient: recipient.to_string(),
+            content: Some(content.to_string()),
+            ..Default::default()
+        })
+    }
+
+    pub fn send_file(&self, recipient: &str, filename: &str, data: Vec<u8>) -> Result<(), Error> {
+        self.send(&ClientMessage {
+            msg_type: "file".to_string(),
+            recipient: recipient.to_string(),
+            filename: Some(filename.to_string()),
+            size: Some(data.len()),
+            data: Some(data),
+            ..Default::default()
+        })
+    }
+}
+
+pub struct Connection {
+    sender: Sender,
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Connection {
+    pub fn sender(&self) -> &Sender {
+        &self.sender
+    }
+
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for Connection {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+pub(crate) fn open(session: Session) -> Connection {
+    let (outgoing, queue) = mpsc::unbounded_channel();
+    let (events, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(run(session, queue, events));
+    Connection {
+        sender: Sender { outgoing },
+        events: receiver,
+    }
+}
+
+async fn connect(session: &Session) -> Result<Socket, String> {
+    let url = session.websocket_url().map_err(|error| error.to_string())?;
+    match tokio_tungstenite::connect_async(url.as_str()).await {
+        Ok((socket, _)) => Ok(socket),
+        Err(tungstenite::Error::Http(response)) if response.status().as_u16() == 401 => {
+            session.relogin().await.map_err(|error| error.to_string())?;
+            let url = session.websocket_url().map_err(|error| error.to_string())?;
+            let (socket, _) = tokio_tungstenite::connect_async(url.as_str()).await.map_err(|error| error.to_string())?;
+            Ok(socket)
+        },
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+async fn serve(mut socket: Socket, queue: &mut mpsc::UnboundedReceiver<String>, events: &mpsc::UnboundedSender<Event>) -> Option<(String, Option<Duration>)> {
+    let mut reconnect_after = None;
+    loop {
+        tokio::select! {
+            text = queue.recv() => match text {
+                Some(text) => if let Err(error) = socket.send(Message::Text(text)).await {
+                    return Some((error.to_string(), reconnect_after));
+                },
+                None => {
+                    let _ = socket.close(None).await;
+                    return None;
+                },
+            },
+            frame = socket.next() => match frame {
+                Some(Ok(Message::Text(text))) => {
+                    let event = Event::parse(&text);
+                    if let Event::Server(ServerEvent::ServerShutdown { reconnect_after: seconds, .. }) = &event {
+                        reconnect_after = Some(Duration::from_secs(*seconds));
+                    }
+                    if events.send(event).is_err() {
+                        let _ = socket.close(None).await;
+                        return None;
+                    }
+                },
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = frame
+                        .map(|frame| format!("{} {}", u16::from(frame.code), frame.reason))
+                        .unwrap_or_else(|| "connection closed".to_string());
+                    return Some((reason, reconnect_after));
+                },
+                Some(Ok(_)) => {},
+                Some(Err(error)) => return Some((error.to_string(), reconnect_after)),
+                None => return Some(("connection closed".to_string(), reconnect_after)),
+            },
+        }
+    }
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+async fn run(session: Session, mut queue: mpsc::UnboundedReceiver<String>, events: mpsc::UnboundedSender<Event>) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let delay = match connect(&session).await {
+            Ok(socket) => {
+                backoff = INITIAL_BACKOFF;
+                if events.send(Event::Connected).is_err() {
+                    return;
+                }
+                let Some((reason, delay)) = serve(socket, &mut queue, &events).await else {
+                    return;
+                };
+                if events.send(Event::Disconnected { reason }).is_err() {
+                    return;
+                }
+                delay
+            },
+            Err(reason) => {
+                if events.send(Event::Disconnected { reason }).is_err() {
+                    return;
+                }
+                None
+            },
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay.unwrap_or(backoff)) => {},
+            _ = events.closed() => return,
+        }
+        backoff = next_backoff(backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::Client;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(next_backoff(INITIAL_BACKOFF), Duration::from_secs(1));
+        assert_eq!(next_backoff(Duration::from_secs(20)), MAX_BACKOFF);
+        assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
+    }
+
+    async fn next(connection: &mut Connection) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), connection.next_event()).await
+            .expect("timed out waiting for an event")
+            .expect("connection closed")
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_server_shutdown_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (received, mut messages) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            if let Some(Ok(Message::Text(text))) = socket.next().await {
+                received.send(text).unwrap();
+            }
+            let shutdown = r#"{"type":"server_shutdown","message":"restart","reconnectAfter":0}"#;
+            socket.send(Message::Text(shutdown.to_string())).await.unwrap();
+            socket.close(None).await.unwrap();
+            while socket.next().await.is_some() {}
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while socket.next().await.is_some() {}
+        });
+
+        let session = Session {
+            client: Client::new(&format!("http://{}", address)).unwrap(),
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+            token: Default::default(),
+        };
+        let mut connection = open(session);
+        assert!(matches!(next(&mut connection).await, Event::Connected));
+
+        connection.sender().send_text("public", "hello").unwrap();
+        let sent = tokio::time::timeout(Duration::from_secs(5), messages.recv()).await.unwrap().unwrap();
+        let sent: serde_json::Value = serde_json::from_str(&sent).unwrap();
+        assert_eq!(sent["type"], "message");
+        assert_eq!(sent["content"], "hello");
+
+        let event = next(&mut connection).await;
+        assert!(matches!(event, Event::Server(ServerEvent::ServerShutdown { reconnect_after: 0, .. })), "{:?}", event);
+        assert!(matches!(next(&mut connection).await, Event::Disconnected { .. }));
+        let started = tokio::time::Instant::now();
+        assert!(matches!(next(&mut connection).await, Event::Connected));
+        assert!(started.elapsed() < INITIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Url(url::ParseError),
+    Http(reqwest::Error),
+    Server { status: u16, message: String },
+    Json(serde_json::Error),
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Url(error) => write!(f, "invalid server url: {}", error),
+            Error::Http(error) => write!(f, "request failed: {}", error),
+            Error::Server { status, message } => write!(f, "server returned {}: {}", status, message),
+            Error::Json(error) => write!(f, "invalid message: {}", error),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<url::ParseError> for Error {
+    fn from(error: url::ParseError) -> Self {
+        Error::Url(error)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Http(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
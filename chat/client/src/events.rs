@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::Deserialize;
+use serde_json::Value;
+use crate::models::Profile;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    #[serde(rename_all = "camelCase")]
+    Public {
+        id: String,
+        from: String,
+        content: String,
+        #[serde(default)]
+        mentions: Vec<String>,
+        reply_to: Option<String>,
+        thread_id: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Room {
+        id: String,
+        room: String,
+        from: String,
+        content: String,
+        #[serde(default)]
+        mentions: Vec<String>,
+        reply_to: Option<String>,
+        thread_id: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Group {
+        id: String,
+        group: String,
+        from: String,
+        content: String,
+        #[serde(default)]
+        mentions: Vec<String>,
+        reply_to: Option<String>,
+        thread_id: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Private {
+        id: String,
+        from: String,
+        to: String,
+        content: String,
+        #[serde(default)]
+        mentions: Vec<String>,
+        reply_to: Option<String>,
+        thread_id: Option<String>,
+    },
+    Encrypted {
+        id: String,
+        from: String,
+        to: String,
+        ciphertext: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    File {
+        from: String,
+        to: String,
+        file_id: String,
+        filename: String,
+        content_type: String,
+        size: usize,
+        width: Option<u32>,
+        height: Option<u32>,
+        thumbnail_id: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Reaction {
+        message_id: String,
+        emoji: String,
+        username: String,
+        action: String,
+        reactions: BTreeMap<String, usize>,
+    },
+    Mention {
+        from: String,
+        conversation: String,
+        content: String,
+        timestamp: u64,
+    },
+    UserConnected {
+        username: String,
+    },
+    UserDisconnected {
+        username: String,
+    },
+    UserAway {
+        username: String,
+        message: String,
+    },
+    UserBack {
+        username: String,
+    },
+    UserRenamed {
+        old: String,
+        new: String,
+    },
+    RoomJoined {
+        room: String,
+        username: String,
+    },
+    RoomLeft {
+        room: String,
+        username: String,
+    },
+    GroupUpdated {
+        id: String,
+        owner: String,
+        participants: BTreeSet<String>,
+        removed: Option<String>,
+    },
+    ProfileUpdated {
+        username: String,
+        profile: Profile,
+    },
+    CommandResult {
+        command: String,
+        result: Value,
+    },
+    CommandError {
+        command: String,
+        message: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    ServerShutdown {
+        message: String,
+        reconnect_after: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected,
+    Disconnected { reason: String },
+    Server(ServerEvent),
+    Unknown(Value),
+}
+
+impl Event {
+    pub fn parse(text: &str) -> Event {
+        match serde_json::from_str::<Value>(text) {
+            Ok(value) => match ServerEvent::deserialize(&value) {
+                Ok(event) => Event::Server(event),
+                Err(_) => Event::Unknown(value),
+            },
+            Err(_) => Event::Unknown(Value::String(text.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_events() {
+        let event = Event::parse(r#"{"type":"private","id":"1","from":"alice","to":"bob","content":"hi","replyTo":"0"}"#);
+        match event {
+            Event::Server(ServerEvent::Private { from, to, content, reply_to, mentions, .. }) => {
+                assert_eq!((from.as_str(), to.as_str(), content.as_str()), ("alice", "bob", "hi"));
+                assert_eq!(reply_to.as_deref(), Some("0"));
+                assert!(mentions.is_empty());
+            },
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        let event = Event::parse(r#"{"type":"server_shutdown","message":"bye","reconnectAfter":5}"#);
+        assert!(matches!(event, Event::Server(ServerEvent::ServerShutdown { reconnect_after: 5, .. })));
+    }
+
+    #[test]
+    fn keeps_unrecognised_payloads() {
+        let event = Event::parse(r#"{"type":"typing","username":"alice"}"#);
+        assert!(matches!(event, Event::Unknown(Value::Object(_))));
+
+        let event = Event::parse(r#"{"type":"private","from":"alice"}"#);
+        assert!(matches!(event, Event::Unknown(Value::Object(_))));
+
+        match Event::parse("not json") {
+            Event::Unknown(Value::String(text)) => assert_eq!(text, "not json"),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+}
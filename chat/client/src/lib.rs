@@ -0,0 +1,143 @@
+#[path = "../../src/models.rs"]
+pub mod models;
+mod connection;
+mod error;
+mod events;
+
+pub use connection::{Connection, Sender};
+pub use error::Error;
+pub use events::{Event, ServerEvent};
+
+use std::sync::{Arc, Mutex};
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+use url::Url;
+use models::*;
+
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        let mut base = Url::parse(base_url)?;
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(Client { http: reqwest::Client::new(), base })
+    }
+
+    fn url(&self, path: &str) -> Result<Url, Error> {
+        Ok(self.base.join(path)?)
+    }
+
+    async fn check(response: Response) -> Result<Response, Error> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await?;
+        let message = serde_json::from_str::<ErrorMessage>(&body).map(|error| error.message).unwrap_or(body);
+        Err(Error::Server { status: status.as_u16(), message })
+    }
+
+    async fn parse<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
+        let body = Self::check(response).await?.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    pub async fn signup(&self, username: &str, password: &str) -> Result<String, Error> {
+        let user = User {
+            username: username.to_string(),
+            password: password.to_string(),
+            is_bot: false,
+            profile: Profile::default(),
+            owner: None,
+        };
+        let response = self.http.post(self.url("signup")?).json(&user).send().await?;
+        Ok(Self::parse::<SignupResponse>(response).await?.message)
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<String, Error> {
+        let info = LoginInfo {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        let response = self.http.post(self.url("login")?).json(&info).send().await?;
+        Ok(Self::parse::<LoginResponse>(response).await?.token)
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> Result<Session, Error> {
+        let token = self.authenticate(username, password).await?;
+        Ok(Session {
+            client: self.clone(),
+            username: username.to_string(),
+            password: password.to_string(),
+            token: Arc::new(Mutex::new(token)),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Session {
+    client: Client,
+    username: String,
+    password: String,
+    token: Arc<Mutex<String>>,
+}
+
+impl Session {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn token(&self) -> String {
+        self.token.lock().unwrap().clone()
+    }
+
+    pub async fn relogin(&self) -> Result<(), Error> {
+        let token = self.client.authenticate(&self.username, &self.password).await?;
+        *self.token.lock().unwrap() = token;
+        Ok(())
+    }
+
+    async fn get(&self, path: &str) -> Result<Response, Error> {
+        let url = self.client.url(path)?;
+        let response = self.client.http.get(url.clone()).query(&HistoryRequest { token: self.token() }).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Client::check(response).await;
+        }
+        self.relogin().await?;
+        let response = self.client.http.get(url).query(&HistoryRequest { token: self.token() }).send().await?;
+        Client::check(response).await
+    }
+
+    pub async fn history(&self) -> Result<Vec<String>, Error> {
+        let history: HistoryResponse = Client::parse(self.get("history").await?).await?;
+        Ok(history.messages)
+    }
+
+    pub async fn online_users(&self) -> Result<Vec<String>, Error> {
+        let online: OnlineUsersResponse = Client::parse(self.get("online_users").await?).await?;
+        Ok(online.users)
+    }
+
+    pub async fn download(&self, file_id: &str) -> Result<Vec<u8>, Error> {
+        let response = self.get(&format!("download/{}", file_id)).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    fn websocket_url(&self) -> Result<Url, Error> {
+        let mut url = self.client.url("ws/")?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        let _ = url.set_scheme(scheme);
+        url.query_pairs_mut().append_pair("token", &self.token());
+        Ok(url)
+    }
+
+    pub fn connect(&self) -> Connection {
+        connection::open(self.clone())
+    }
+}
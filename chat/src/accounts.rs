@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::web;
+use serde_json::json;
+use crate::config::DeletionPolicy;
+use crate::groups;
+use crate::history;
+use crate::uploads;
+use crate::websocket::{Disconnect, Renamed};
+use crate::models::*;
+use crate::AppState;
+use crate::cluster;
+
+pub const DELETED_USER: &str = "deleted-user";
+
+pub fn is_reserved(username: &str) -> bool {
+    username.starts_with(DELETED_USER) || username == "public" || username.starts_with('#') || groups::is_group(username)
+}
+
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.chars().count() <= 32
+        && username.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        && !is_reserved(username)
+}
+
+fn rename_key<V>(map: &mut HashMap<String, V>, old: &str, new: &str) {
+    if let Some(value) = map.remove(old) {
+        map.insert(new.to_string(), value);
+    }
+}
+
+fn rename_line(line: &str, old: &str, new: &str) -> String {
+    for prefix in ["", "До ", "Від "] {
+        if let Some(rest) = line.strip_prefix(&format!("{}{}: ", prefix, old)) {
+            return format!("{}{}: {}", prefix, new, rest);
+        }
+    }
+    if let Some((room, rest)) = line.split_once("] ") {
+        if let Some(rest) = rest.strip_prefix(&format!("{}: ", old)).filter(|_| room.starts_with('[')) {
+            return format!("{}] {}: {}", room, new, rest);
+        }
+    }
+    line.to_string()
+}
+
+fn rename_entry(entry: &mut HistoryEntry, old: &str, new: &str) {
+    entry.line = rename_line(&entry.line, old, new);
+    if entry.from == old {
+        entry.from = new.to_string();
+    }
+    if entry.conversation == old {
+        entry.conversation = new.to_string();
+    }
+}
+
+pub fn disconnect(app_state: &web::Data<AppState>, username: &str, reason: &str) {
+    disconnect_sessions(app_state, username, None, reason);
+}
+
+pub fn disconnect_other_sessions(app_state: &web::Data<AppState>, username: &str, token: &str, reason: &str) {
+    disconnect_sessions(app_state, username, Some(token), reason);
+}
+
+fn disconnect_sessions(app_state: &web::Data<AppState>, username: &str, kept_token: Option<&str>, reason: &str) {
+    let kept = |token: Option<&str>| kept_token.is_some() && token == kept_token;
+    if let Some(addr) = app_state.connections.lock().unwrap().get(username).filter(|addr| !kept(addr.token())) {
+        addr.do_send(Disconnect { reason: reason.to_string() });
+    }
+    app_state.event_streams.lock().unwrap().retain(|_, stream| stream.owner != username || kept(Some(&stream.token)));
+}
+
+pub fn revoke_api_keys(app_state: &web::Data<AppState>, username: &str) -> usize {
+    let revoked = {
+        let mut api_keys = app_state.api_keys.lock().unwrap();
+        let before = api_keys.len();
+        api_keys.retain(|_, owner| owner != username);
+        before - api_keys.len()
+    };
+    disconnect(app_state, username, "Ключ API відкликано");
+    revoked
+}
+
+pub fn rename(app_state: &web::Data<AppState>, old: &str, new: &str) -> Result<(), String> {
+    rename_references(app_state, old, new)?;
+    history::touch(app_state);
+    Ok(())
+}
+
+fn anonymous_name() -> String {
+    format!("{}-{}", DELETED_USER, &uuid::Uuid::new_v4().simple().to_string()[..12])
+}
+
+fn rename_references(app_state: &web::Data<AppState>, old: &str, new: &str) -> Result<(), String> {
+    {
+        let mut users = app_state.users.lock().unwrap();
+        if users.contains_key(new) {
+            return Err("Такий користувач вже існує".to_string());
+        }
+        let mut sessions = app_state.sessions.lock().unwrap();
+        let mut api_keys = app_state.api_keys.lock().unwrap();
+        let mut event_streams = app_state.event_streams.lock().unwrap();
+        let mut connections = app_state.connections.lock().unwrap();
+        let mut messages = app_state.messages.lock().unwrap();
+        let mut room_logs = app_state.room_logs.lock().unwrap();
+        let mut read_cursors = app_state.read_cursors.lock().unwrap();
+        let mut webhooks = app_state.webhooks.lock().unwrap();
+        let mut rooms = app_state.rooms.lock().unwrap();
+        let mut away = app_state.away.lock().unwrap();
+        let mut unread = app_state.unread.lock().unwrap();
+        let mut mentions = app_state.mentions.lock().unwrap();
+        let mut message_index = app_state.message_index.lock().unwrap();
+        let mut groups = app_state.groups.lock().unwrap();
+        let mut privacy = app_state.privacy.lock().unwrap();
+        let mut key_bundles = app_state.key_bundles.lock().unwrap();
+        let mut uploads = app_state.uploads.lock().unwrap();
+
+        if let Some(mut user) = users.remove(old) {
+            user.username = new.to_string();
+            users.insert(new.to_string(), user);
+        }
+        for user in users.values_mut() {
+            if user.owner.as_deref() == Some(old) {
+                user.owner = Some(new.to_string());
+            }
+        }
+        for owner in sessions.values_mut().chain(api_keys.values_mut()) {
+            if owner == old {
+                *owner = new.to_string();
+            }
+        }
+        for stream in event_streams.values_mut() {
+            if stream.owner == old {
+                stream.owner = new.to_string();
+            }
+        }
+        rename_key(&mut connections, old, new);
+        if let Some(addr) = connections.get(new) {
+            addr.do_send(Renamed { username: new.to_string() });
+        }
+        rename_key(&mut messages, old, new);
+        for entry in messages.values_mut().flatten() {
+            rename_entry(entry, old, new);
+        }
+        for entry in room_logs.values_mut().flatten() {
+            rename_entry(entry, old, new);
+        }
+        rename_key(&mut read_cursors, old, new);
+        for webhook in webhooks.values_mut() {
+            if webhook.owner == old {
+                webhook.owner = new.to_string();
+            }
+            if webhook.mention.as_deref() == Some(old) {
+                webhook.mention = Some(new.to_string());
+            }
+        }
+        for members in rooms.values_mut() {
+            if members.remove(old) {
+                members.insert(new.to_string());
+            }
+        }
+        rename_key(&mut away, old, new);
+        rename_key(&mut unread, old, new);
+        for conversations in unread.values_mut() {
+            rename_key(conversations, old, new);
+        }
+        rename_key(&mut mentions, old, new);
+        for mention in mentions.values_mut().flatten() {
+            if mention.from == old {
+                mention.from = new.to_string();
+            }
+            if mention.conversation == old {
+                mention.conversation = new.to_string();
+            }
+        }
+        for message in message_index.values_mut() {
+            if message.from == old {
+                message.from = new.to_string();
+            }
+            if message.recipient == old {
+                message.recipient = new.to_string();
+            }
+            for users in message.reactions.values_mut() {
+                if users.remove(old) {
+                    users.insert(new.to_string());
+                }
+            }
+        }
+        for group in groups.values_mut() {
+            if group.owner == old {
+                group.owner = new.to_string();
+            }
+            if group.participants.remove(old) {
+                group.participants.insert(new.to_string());
+            }
+            for entry in group.messages.iter_mut() {
+                rename_entry(entry, old, new);
+            }
+        }
+        rename_key(&mut privacy, old, new);
+        for settings in privacy.values_mut() {
+            if settings.blocked.remove(old) {
+                settings.blocked.insert(new.to_string());
+            }
+            if settings.contacts.remove(old) {
+                settings.contacts.insert(new.to_string());
+            }
+        }
+        rename_key(&mut key_bundles, old, new);
+        for record in uploads.values_mut() {
+            if record.owner == old {
+                record.owner = new.to_string();
+            }
+        }
+    }
+
+    let event = json!({
+        "type": "user_renamed",
+        "old": old,
+        "new": new
+    }).to_string();
+    cluster::broadcast(app_state, &event);
+    Ok(())
+}
+
+fn purge_authored(app_state: &web::Data<AppState>, username: &str) {
+    for history in app_state.messages.lock().unwrap().values_mut() {
+        history.retain(|entry| entry.from != username);
+    }
+    for log in app_state.room_logs.lock().unwrap().values_mut() {
+        log.retain(|entry| entry.from != username);
+    }
+    for group in app_state.groups.lock().unwrap().values_mut() {
+        group.messages.retain(|entry| entry.from != username);
+    }
+    {
+        let mut index = app_state.message_index.lock().unwrap();
+        index.retain(|_, message| message.from != username && message.recipient != username);
+        for message in index.values_mut() {
+            for users in message.reactions.values_mut() {
+                users.remove(username);
+            }
+            message.reactions.retain(|_, users| !users.is_empty());
+        }
+    }
+    for mentions in app_state.mentions.lock().unwrap().values_mut() {
+        mentions.retain(|mention| mention.from != username);
+    }
+    for conversations in app_state.unread.lock().unwrap().values_mut() {
+        conversations.remove(username);
+    }
+}
+
+pub fn delete(app_state: &web::Data<AppState>, username: &str) {
+    history::touch(app_state);
+    disconnect(app_state, username, "Обліковий запис видалено");
+    app_state.sessions.lock().unwrap().retain(|_, owner| owner != username);
+    app_state.api_keys.lock().unwrap().retain(|_, owner| owner != username);
+    app_state.messages.lock().unwrap().remove(username);
+    app_state.read_cursors.lock().unwrap().remove(username);
+    app_state.webhooks.lock().unwrap().retain(|_, webhook| webhook.owner != username);
+    {
+        let mut rooms = app_state.rooms.lock().unwrap();
+        for members in rooms.values_mut() {
+            members.remove(username);
+        }
+        rooms.retain(|_, members| !members.is_empty());
+    }
+    app_state.away.lock().unwrap().remove(username);
+    app_state.unread.lock().unwrap().remove(username);
+    app_state.mentions.lock().unwrap().remove(username);
+    {
+        let mut groups = app_state.groups.lock().unwrap();
+        for group in groups.values_mut() {
+            group.participants.remove(username);
+            if group.owner == username {
+                if let Some(next_owner) = group.participants.iter().next() {
+                    group.owner = next_owner.clone();
+                }
+            }
+        }
+        groups.retain(|_, group| !group.participants.is_empty());
+    }
+    {
+        let mut privacy = app_state.privacy.lock().unwrap();
+        privacy.remove(username);
+        for settings in privacy.values_mut() {
+            settings.blocked.remove(username);
+            settings.contacts.remove(username);
+        }
+    }
+    app_state.key_bundles.lock().unwrap().remove(username);
+    let bots: Vec<String> = {
+        let mut users = app_state.users.lock().unwrap();
+        users.remove(username);
+        users.values_mut()
+            .filter(|user| user.owner.as_deref() == Some(username))
+            .map(|user| {
+                user.owner = None;
+                user.username.clone()
+            })
+            .collect()
+    };
+    for bot in bots.iter() {
+        revoke_api_keys(app_state, bot);
+    }
+
+    let owned_files: Vec<String> = app_state.uploads.lock().unwrap().iter()
+        .filter(|(_, record)| record.owner == username)
+        .map(|(file_id, _)| file_id.clone())
+        .collect();
+
+    match app_state.config.account_deletion {
+        DeletionPolicy::Purge => purge_authored(app_state, username),
+        DeletionPolicy::Anonymize => {
+            if let Err(error) = rename_references(app_state, username, &anonymous_name()) {
+                tracing::warn!(%username, %error, "failed to anonymize deleted account");
+            }
+        },
+    }
+
+    let retention = app_state.config.deleted_files_retention;
+    if retention.is_zero() {
+        for file_id in owned_files.iter() {
+            uploads::release(app_state, file_id);
+        }
+    } else {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        uploads::schedule_release(app_state, &owned_files, now + retention.as_secs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::history;
+    use crate::sse;
+
+    fn user(username: &str) -> User {
+        User {
+            username: username.to_string(),
+            password: "secret".to_string(),
+            is_bot: false,
+            profile: Profile::default(),
+            owner: None,
+        }
+    }
+
+    fn state(policy: DeletionPolicy) -> web::Data<AppState> {
+        let mut config = Config::from_env();
+        config.account_deletion = policy;
+        let app_state = crate::test_state(config);
+        for username in ["alice", "bob"] {
+            app_state.users.lock().unwrap().insert(username.to_string(), user(username));
+        }
+        app_state
+    }
+
+    #[actix_web::test]
+    async fn purge_keeps_messages_sent_to_the_deleted_account() {
+        let app_state = state(DeletionPolicy::Purge);
+        history::push(&app_state, "alice", HistoryEntry::new("bob", "alice", "До bob: привіт".to_string()));
+        history::push(&app_state, "alice", HistoryEntry::new("bob", "bob", "Від bob: вітаю".to_string()));
+
+        delete(&app_state, "bob");
+        let lines: Vec<String> = app_state.messages.lock().unwrap()["alice"].iter().map(|entry| entry.line.clone()).collect();
+        assert_eq!(lines, ["До bob: привіт"]);
+    }
+
+    #[actix_web::test]
+    async fn rename_refuses_taken_names() {
+        let app_state = state(DeletionPolicy::Anonymize);
+        assert_eq!(rename(&app_state, "alice", "bob"), Err("Такий користувач вже існує".to_string()));
+        assert_eq!(rename(&app_state, "alice", "carol"), Ok(()));
+        let users = app_state.users.lock().unwrap();
+        assert!(users.contains_key("carol") && users.contains_key("bob") && !users.contains_key("alice"));
+    }
+
+    #[actix_web::test]
+    async fn rename_moves_every_reference_together() {
+        let app_state = state(DeletionPolicy::Anonymize);
+        app_state.sessions.lock().unwrap().insert("token".to_string(), "alice".to_string());
+        app_state.rooms.lock().unwrap().insert("#dev".to_string(), ["alice".to_string(), "bob".to_string()].into());
+        app_state.privacy.lock().unwrap().entry("bob".to_string()).or_default().contacts.insert("alice".to_string());
+        history::push(&app_state, "bob", HistoryEntry::new("alice", "alice", "Від alice: привіт".to_string()));
+
+        rename(&app_state, "alice", "carol").unwrap();
+        assert_eq!(app_state.sessions.lock().unwrap()["token"], "carol");
+        assert!(app_state.rooms.lock().unwrap()["#dev"].contains("carol"));
+        assert!(app_state.privacy.lock().unwrap()["bob"].contacts.contains("carol"));
+        let entry = app_state.messages.lock().unwrap()["bob"][0].clone();
+        assert_eq!((entry.from.as_str(), entry.line.as_str()), ("carol", "Від carol: привіт"));
+    }
+
+    #[actix_web::test]
+    async fn anonymize_keeps_deleted_accounts_distinct() {
+        let app_state = state(DeletionPolicy::Anonymize);
+        app_state.users.lock().unwrap().insert("carol".to_string(), user("carol"));
+        for author in ["alice", "carol"] {
+            history::push(&app_state, "bob", HistoryEntry::new(author, author, format!("Від {}: привіт", author)));
+        }
+
+        delete(&app_state, "alice");
+        delete(&app_state, "carol");
+        let authors: Vec<String> = app_state.messages.lock().unwrap()["bob"].iter().map(|entry| entry.from.clone()).collect();
+        assert!(authors.iter().all(|author| author.starts_with(DELETED_USER) && !is_valid_username(author)));
+        assert_ne!(authors[0], authors[1]);
+    }
+
+    #[actix_web::test]
+    async fn password_change_keeps_the_calling_session() {
+        let app_state = state(DeletionPolicy::Anonymize);
+        let _current = sse::open(&app_state, "alice", "current");
+        let _other = sse::open(&app_state, "alice", "other");
+        let _bob = sse::open(&app_state, "bob", "bob-token");
+
+        disconnect_other_sessions(&app_state, "alice", "current", "Пароль змінено, підключіться знову");
+        let mut remaining: Vec<String> = app_state.event_streams.lock().unwrap().values().map(|stream| stream.token.clone()).collect();
+        remaining.sort();
+        assert_eq!(remaining, ["bob-token", "current"]);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Duration;
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use awc::ClientResponse;
+use futures_util::Stream;
+use serde_json::{json, Value};
+use crate::config::Config;
+use crate::transcripts::MAX_IMPORT_BYTES;
+
+const USAGE: &str = "usage:
+  chat                                         start the server
+  chat export [--user NAME | --room NAME] [--format json|html] [--output FILE]
+  chat import --file FILE
+
+common options:
+  --server URL    server to talk to (default: http://$CHAT_BIND_ADDRESS)
+  --token TOKEN   session token or API key (default: $CHAT_TOKEN)";
+
+fn usage_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n\n{}", message, USAGE))
+}
+
+fn parse_options(args: &[String]) -> io::Result<HashMap<String, String>> {
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let name = arg.strip_prefix("--").ok_or_else(|| usage_error(&format!("unexpected argument '{}'", arg)))?;
+        let value = args.next().ok_or_else(|| usage_error(&format!("missing value for --{}", name)))?;
+        options.insert(name.to_string(), value.clone());
+    }
+    Ok(options)
+}
+
+async fn read_response<S>(mut response: ClientResponse<S>) -> io::Result<Vec<u8>>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let body = response.body().limit(MAX_IMPORT_BYTES).await.map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        let message = serde_json::from_slice::<Value>(&body).ok()
+            .and_then(|error| error["message"].as_str().map(|message| message.to_string()))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).to_string());
+        return Err(io::Error::other(format!("server returned {}: {}", response.status(), message)));
+    }
+    Ok(body.to_vec())
+}
+
+async fn export(client: &awc::Client, server: &str, token: &str, options: &HashMap<String, String>) -> io::Result<()> {
+    let mut query = vec![("token", token)];
+    for name in ["user", "room", "format"] {
+        if let Some(value) = options.get(name) {
+            query.push((name, value));
+        }
+    }
+
+    let request = client.get(format!("{}/export", server)).query(&query).map_err(io::Error::other)?;
+    let response = request.send().await.map_err(|error| io::Error::other(error.to_string()))?;
+    let body = read_response(response).await?;
+    match options.get("output") {
+        Some(path) => std::fs::write(path, body),
+        None => io::stdout().write_all(&body),
+    }
+}
+
+async fn import(client: &awc::Client, server: &str, token: &str, options: &HashMap<String, String>) -> io::Result<()> {
+    let path = options.get("file").ok_or_else(|| usage_error("import needs --file"))?;
+    let export: Value = serde_json::from_slice(&std::fs::read(path)?)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    let response = client.post(format!("{}/import", server))
+        .send_json(&json!({ "token": token, "export": export }))
+        .await
+        .map_err(|error| io::Error::other(error.to_string()))?;
+    let body: Value = serde_json::from_slice(&read_response(response).await?)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    println!("imported {} messages, skipped {}", body["imported"], body["skipped"]);
+    Ok(())
+}
+
+pub async fn run(config: &Config, args: Vec<String>) -> io::Result<()> {
+    let (command, args) = args.split_first().ok_or_else(|| usage_error("missing command"))?;
+    if command == "help" || command == "--help" {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let options = parse_options(args)?;
+    let server = options.get("server").cloned()
+        .unwrap_or_else(|| format!("http://{}", config.bind_address));
+    let server = server.trim_end_matches('/');
+    let token = options.get("token").cloned()
+        .or_else(|| std::env::var("CHAT_TOKEN").ok())
+        .ok_or_else(|| usage_error("missing --token"))?;
+
+    let client = awc::Client::builder().timeout(Duration::from_secs(60)).finish();
+    match command.as_str() {
+        "export" => export(&client, server, &token, &options).await,
+        "import" => import(&client, server, &token, &options).await,
+        _ => Err(usage_error(&format!("unknown command '{}'", command))),
+    }
+}
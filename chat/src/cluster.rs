@@ -0,0 +1,600 @@
+// Only delivery is shared between nodes. Accounts, sessions, rooms, groups, privacy settings and
+// history stay on the node that created them, so every node must be reachable by the same users
+// and a conversation's membership is only known to the nodes where its members joined.
+use std::collections::HashSet;
+use std::io;
+use std::time::Duration;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use crate::models::*;
+use crate::AppState;
+use crate::history;
+use crate::mentions;
+use crate::privacy::{self, Delivery};
+use crate::websocket::*;
+
+const BUS_CAPACITY: usize = 1024;
+const PEER_QUEUE: usize = 1024;
+const INITIAL_BACKOFF_MS: u64 = 500;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClusterEvent {
+    Broadcast {
+        message: String,
+    },
+    Direct {
+        from: String,
+        to: String,
+        content: String,
+        mentions: Vec<String>,
+        message: String,
+    },
+    Encrypted {
+        from: String,
+        to: String,
+        message: String,
+    },
+    Targeted {
+        recipients: Vec<String>,
+        room: Option<String>,
+        message: String,
+    },
+    Presence {
+        username: String,
+        online: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub origin: String,
+    pub event: ClusterEvent,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    node: String,
+    secret: String,
+}
+
+fn secrets_match(offered: &str, expected: &str) -> bool {
+    let offered = Sha256::digest(offered.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    offered.iter().zip(expected.iter()).fold(0u8, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+pub trait FanOut: Send + Sync {
+    fn start(&self, app_state: web::Data<AppState>) -> io::Result<()>;
+    fn publish(&self, envelope: Envelope);
+}
+
+pub fn publish(app_state: &web::Data<AppState>, event: ClusterEvent) {
+    app_state.cluster.publish(Envelope {
+        origin: app_state.config.cluster.node_id.clone(),
+        event,
+    });
+}
+
+pub fn broadcast(app_state: &web::Data<AppState>, message: &str) {
+    for (_user, addr) in app_state.connections.lock().unwrap().iter() {
+        addr.do_send(BroadcastMessage(message.to_string()));
+    }
+    publish(app_state, ClusterEvent::Broadcast { message: message.to_string() });
+}
+
+fn deliver_targeted(app_state: &web::Data<AppState>, recipients: &[String], room: Option<&str>, message: &str) {
+    let members: HashSet<String> = room
+        .and_then(|room| app_state.rooms.lock().unwrap().get(room).cloned())
+        .unwrap_or_default();
+    for (user, addr) in app_state.connections.lock().unwrap().iter() {
+        if recipients.contains(user) || members.contains(user) {
+            addr.do_send(BroadcastMessage(message.to_string()));
+        }
+    }
+}
+
+pub fn send_to(app_state: &web::Data<AppState>, recipients: &[String], message: &str) {
+    deliver_targeted(app_state, recipients, None, message);
+    publish(app_state, ClusterEvent::Targeted { recipients: recipients.to_vec(), room: None, message: message.to_string() });
+}
+
+pub fn send_to_room(app_state: &web::Data<AppState>, room: &str, members: &[String], message: &str) {
+    deliver_targeted(app_state, members, Some(room), message);
+    publish(app_state, ClusterEvent::Targeted { recipients: members.to_vec(), room: Some(room.to_string()), message: message.to_string() });
+}
+
+pub fn announce(app_state: &web::Data<AppState>, username: &str, online: bool) {
+    publish(app_state, ClusterEvent::Presence { username: username.to_string(), online });
+}
+
+pub fn is_remote(app_state: &web::Data<AppState>, username: &str) -> bool {
+    !app_state.connections.lock().unwrap().contains_key(username)
+        && app_state.remote_presence.lock().unwrap().contains_key(username)
+}
+
+pub fn online(app_state: &web::Data<AppState>) -> Vec<String> {
+    let mut online: Vec<String> = app_state.connections.lock().unwrap().keys().cloned().collect();
+    let remote: Vec<String> = app_state.remote_presence.lock().unwrap().keys().cloned().collect();
+    for username in remote {
+        if !online.contains(&username) {
+            online.push(username);
+        }
+    }
+    online
+}
+
+fn deliver_presence(app_state: &web::Data<AppState>, username: &str, online: bool) {
+    for (user, addr) in app_state.connections.lock().unwrap().iter() {
+        if user == username {
+            continue;
+        }
+        if online {
+            addr.do_send(UserConnected { username: username.to_string() });
+        } else {
+            addr.do_send(UserDisconnected { username: username.to_string() });
+        }
+    }
+}
+
+pub fn receive(app_state: &web::Data<AppState>, envelope: Envelope) {
+    match envelope.event {
+        ClusterEvent::Broadcast { message } => {
+            for (_user, addr) in app_state.connections.lock().unwrap().iter() {
+                addr.do_send(BroadcastMessage(message.clone()));
+            }
+        },
+        ClusterEvent::Direct { from, to, content, mentions, message } => {
+            let Some(addr) = app_state.connections.lock().unwrap().get(&to).cloned() else {
+                return;
+            };
+            if !matches!(privacy::direct_delivery(app_state, &to, &from), Delivery::Deliver) {
+                tracing::debug!(%from, %to, "remote direct message refused by privacy settings");
+                return;
+            }
+            addr.do_send(PrivateMessage { content: message });
+            history::push(app_state, &to, HistoryEntry::new(&from, &from, format!("Від {}: {}", from, content)));
+            mentions::track(app_state, &from, std::slice::from_ref(&to), &from, &content, &mentions);
+        },
+        ClusterEvent::Encrypted { from, to, message } => {
+            let Some(addr) = app_state.connections.lock().unwrap().get(&to).cloned() else {
+                return;
+            };
+            if !matches!(privacy::direct_delivery(app_state, &to, &from), Delivery::Deliver) {
+                tracing::debug!(%from, %to, "remote encrypted message refused by privacy settings");
+                return;
+            }
+            addr.do_send(PrivateMessage { content: message });
+            mentions::track(app_state, &from, std::slice::from_ref(&to), &from, "", &[]);
+        },
+        ClusterEvent::Targeted { recipients, room, message } => deliver_targeted(app_state, &recipients, room.as_deref(), &message),
+        ClusterEvent::Presence { username, online } => {
+            {
+                let mut presence = app_state.remote_presence.lock().unwrap();
+                if online {
+                    presence.insert(username.clone(), envelope.origin);
+                } else if presence.get(&username) == Some(&envelope.origin) {
+                    presence.remove(&username);
+                } else {
+                    return;
+                }
+            }
+            deliver_presence(app_state, &username, online);
+        },
+    }
+}
+
+fn forget_node(app_state: &web::Data<AppState>, node: &str) {
+    let departed: Vec<String> = {
+        let mut presence = app_state.remote_presence.lock().unwrap();
+        let departed = presence.iter().filter(|(_, origin)| *origin == node).map(|(username, _)| username.clone()).collect();
+        presence.retain(|_, origin| origin != node);
+        departed
+    };
+    for username in departed {
+        deliver_presence(app_state, &username, false);
+    }
+}
+
+fn presence_snapshot(app_state: &web::Data<AppState>) -> Vec<Envelope> {
+    let online: Vec<String> = app_state.connections.lock().unwrap().keys().cloned().collect();
+    online.into_iter()
+        .filter(|username| !privacy::is_hidden(app_state, username))
+        .map(|username| Envelope {
+            origin: app_state.config.cluster.node_id.clone(),
+            event: ClusterEvent::Presence { username, online: true },
+        })
+        .collect()
+}
+
+pub struct InProcessFanOut {
+    node_id: String,
+    bus: broadcast::Sender<Envelope>,
+}
+
+impl InProcessFanOut {
+    pub fn new(node_id: &str, bus: broadcast::Sender<Envelope>) -> Self {
+        InProcessFanOut { node_id: node_id.to_string(), bus }
+    }
+
+    pub fn bus() -> broadcast::Sender<Envelope> {
+        broadcast::channel(BUS_CAPACITY).0
+    }
+}
+
+impl FanOut for InProcessFanOut {
+    fn start(&self, app_state: web::Data<AppState>) -> io::Result<()> {
+        let node_id = self.node_id.clone();
+        let mut receiver = self.bus.subscribe();
+        actix::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(envelope) if envelope.origin != node_id => receive(&app_state, envelope),
+                    Ok(_) => {},
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => tracing::warn!(skipped, "cluster bus lagged"),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn publish(&self, envelope: Envelope) {
+        let _ = self.bus.send(envelope);
+    }
+}
+
+pub struct TcpFanOut {
+    node_id: String,
+    listen: String,
+    secret: String,
+    peers: Vec<(String, mpsc::Sender<String>)>,
+    receivers: std::sync::Mutex<Vec<mpsc::Receiver<String>>>,
+}
+
+impl TcpFanOut {
+    pub fn new(node_id: &str, listen: &str, peers: &[String], secret: &str) -> Self {
+        let (peers, receivers) = peers.iter()
+            .map(|peer| {
+                let (sender, receiver) = mpsc::channel(PEER_QUEUE);
+                ((peer.clone(), sender), receiver)
+            })
+            .unzip();
+        TcpFanOut {
+            node_id: node_id.to_string(),
+            listen: listen.to_string(),
+            secret: secret.to_string(),
+            peers,
+            receivers: std::sync::Mutex::new(receivers),
+        }
+    }
+}
+
+async fn write_line(writer: &mut OwnedWriteHalf, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\n").await
+}
+
+async fn stream_events(stream: TcpStream, lines: Vec<String>, queue: &mut mpsc::Receiver<String>) -> io::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    for line in lines.iter() {
+        write_line(&mut writer, line).await?;
+    }
+
+    let mut buffer = [0u8; 64];
+    loop {
+        tokio::select! {
+            line = queue.recv() => match line {
+                Some(line) => write_line(&mut writer, &line).await?,
+                None => return Ok(()),
+            },
+            read = reader.read(&mut buffer) => if read? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the connection"));
+            },
+        }
+    }
+}
+
+async fn run_peer(app_state: web::Data<AppState>, hello: String, address: String, mut queue: mpsc::Receiver<String>) {
+    let mut backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+    loop {
+        match TcpStream::connect(&address).await {
+            Ok(stream) => {
+                backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+                let mut dropped = 0;
+                while queue.try_recv().is_ok() {
+                    dropped += 1;
+                }
+                if dropped > 0 {
+                    tracing::warn!(peer = %address, dropped, "discarded cluster events queued while the peer was unreachable");
+                }
+                tracing::info!(peer = %address, "connected to cluster peer");
+
+                let mut lines = vec![hello.clone()];
+                lines.extend(presence_snapshot(&app_state).iter().filter_map(|envelope| serde_json::to_string(envelope).ok()));
+                match stream_events(stream, lines, &mut queue).await {
+                    Ok(()) => return,
+                    Err(error) => tracing::warn!(peer = %address, %error, "lost connection to cluster peer"),
+                }
+            },
+            Err(error) => tracing::debug!(peer = %address, %error, "cluster peer unreachable"),
+        }
+        actix::clock::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn serve_peer(app_state: web::Data<AppState>, stream: TcpStream, secret: String) {
+    let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+    let mut lines = BufReader::new(stream).lines();
+    let hello = match lines.next_line().await {
+        Ok(Some(line)) => serde_json::from_str::<Hello>(&line).ok(),
+        _ => None,
+    };
+    let Some(hello) = hello.filter(|hello| secrets_match(&hello.secret, &secret)) else {
+        tracing::warn!(%peer, "rejected cluster peer");
+        return;
+    };
+    tracing::info!(%peer, node = %hello.node, "cluster peer joined");
+
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => match serde_json::from_str::<Envelope>(&line) {
+                Ok(envelope) => receive(&app_state, envelope),
+                Err(error) => tracing::warn!(node = %hello.node, %error, "invalid cluster event"),
+            },
+            Ok(None) => break,
+            Err(error) => {
+                tracing::warn!(node = %hello.node, %error, "cluster peer read failed");
+                break;
+            },
+        }
+    }
+    tracing::info!(node = %hello.node, "cluster peer left");
+    forget_node(&app_state, &hello.node);
+}
+
+impl FanOut for TcpFanOut {
+    fn start(&self, app_state: web::Data<AppState>) -> io::Result<()> {
+        let listener = std::net::TcpListener::bind(&self.listen)?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        tracing::info!(address = %self.listen, node = %self.node_id, "cluster listener started");
+
+        let secret = self.secret.clone();
+        let listener_state = app_state.clone();
+        actix::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        actix::spawn(serve_peer(listener_state.clone(), stream, secret.clone()));
+                    },
+                    Err(error) => tracing::warn!(%error, "cluster accept failed"),
+                }
+            }
+        });
+
+        let hello = serde_json::to_string(&Hello { node: self.node_id.clone(), secret: self.secret.clone() })?;
+        let receivers = std::mem::take(&mut *self.receivers.lock().unwrap());
+        for ((address, _), queue) in self.peers.iter().zip(receivers) {
+            actix::spawn(run_peer(app_state.clone(), hello.clone(), address.clone(), queue));
+        }
+        Ok(())
+    }
+
+    fn publish(&self, envelope: Envelope) {
+        let line = match serde_json::to_string(&envelope) {
+            Ok(line) => line,
+            Err(error) => return tracing::error!(%error, "failed to encode cluster event"),
+        };
+        for (address, sender) in self.peers.iter() {
+            if sender.try_send(line.clone()).is_err() {
+                tracing::debug!(peer = %address, "cluster peer queue full, event dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use serde_json::{json, Value};
+    use super::*;
+    use crate::config::Config;
+    use crate::sse;
+
+    type Events = mpsc::UnboundedReceiver<Value>;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn node(node_id: &str, listen: u16, peer: u16) -> web::Data<AppState> {
+        let mut config = Config::from_env();
+        config.cluster.node_id = node_id.to_string();
+        let fan_out = TcpFanOut::new(node_id, &format!("127.0.0.1:{}", listen), &[format!("127.0.0.1:{}", peer)], "secret");
+        web::Data::new(AppState::new(config, Box::new(fan_out), crate::commands::CommandRegistry::with_builtins()))
+    }
+
+    async fn connect(app_state: &web::Data<AppState>, username: &str) -> (Events, String) {
+        let mut stream = Box::pin(sse::open(app_state, username, "token"));
+        let (sender, mut events) = mpsc::unbounded_channel();
+        actix::spawn(async move {
+            while let Some(Ok(event)) = stream.next().await {
+                let event = String::from_utf8_lossy(&event).to_string();
+                if let Some(data) = event.lines().find_map(|line| line.strip_prefix("data: ")) {
+                    let _ = sender.send(serde_json::from_str(data).unwrap());
+                }
+            }
+        });
+        let hello = next_event(&mut events, |_| true).await;
+        let username = username.to_string();
+        let app_state = app_state.clone();
+        wait_until(move || app_state.connections.lock().unwrap().contains_key(&username)).await;
+        (events, hello["streamId"].as_str().unwrap().to_string())
+    }
+
+    async fn next_event(events: &mut Events, wanted: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await
+                .expect("no matching event within 5 seconds")
+                .expect("event stream ended");
+            if wanted(&event) {
+                return event;
+            }
+        }
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            actix::clock::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("condition not met within 5 seconds");
+    }
+
+    #[actix_web::test]
+    async fn routes_events_between_nodes() {
+        let (port_a, port_b) = (free_port(), free_port());
+        let node_a = node("a", port_a, port_b);
+        let node_b = node("b", port_b, port_a);
+
+        let (mut alice, alice_stream) = connect(&node_a, "alice").await;
+        let (mut bob, _) = connect(&node_b, "bob").await;
+        node_a.cluster.start(node_a.clone()).unwrap();
+        node_b.cluster.start(node_b.clone()).unwrap();
+
+        let joined = next_event(&mut bob, |event| event["type"] == "user_connected").await;
+        assert_eq!(joined["username"], "alice");
+        wait_until(|| node_a.remote_presence.lock().unwrap().contains_key("bob")).await;
+
+        let message = json!({ "type": "message", "recipient": "public", "content": "привіт усім" }).to_string();
+        sse::post(&node_a, &alice_stream, "alice", message).unwrap();
+        let public = next_event(&mut bob, |event| event["type"] == "public").await;
+        assert_eq!(public["content"], "привіт усім");
+
+        let message = json!({ "type": "message", "recipient": "bob", "content": "лише тобі" }).to_string();
+        sse::post(&node_a, &alice_stream, "alice", message).unwrap();
+        let direct = next_event(&mut bob, |event| event["type"] == "private").await;
+        assert_eq!(direct["from"], "alice");
+        assert_eq!(direct["content"], "лише тобі");
+        assert_eq!(node_b.messages.lock().unwrap()["bob"][0].line, "Від alice: лише тобі");
+
+        next_event(&mut alice, |event| event["type"] == "private").await;
+        let connection = node_a.connections.lock().unwrap()["alice"].clone();
+        connection.do_send(Disconnect { reason: "test".to_string() });
+        let left = next_event(&mut bob, |event| event["type"] == "user_disconnected").await;
+        assert_eq!(left["username"], "alice");
+        assert!(!node_b.remote_presence.lock().unwrap().contains_key("alice"));
+    }
+
+    #[actix_web::test]
+    async fn routes_room_and_group_events_to_members_on_other_nodes() {
+        let (port_a, port_b) = (free_port(), free_port());
+        let node_a = node("a", port_a, port_b);
+        let node_b = node("b", port_b, port_a);
+
+        let (_alice, alice_stream) = connect(&node_a, "alice").await;
+        let (mut bob, _) = connect(&node_b, "bob").await;
+        let (mut carol, _) = connect(&node_b, "carol").await;
+        node_a.cluster.start(node_a.clone()).unwrap();
+        node_b.cluster.start(node_b.clone()).unwrap();
+        wait_until(|| node_a.remote_presence.lock().unwrap().contains_key("carol")).await;
+
+        node_a.rooms.lock().unwrap().insert("#dev".to_string(), ["alice".to_string()].into());
+        node_b.rooms.lock().unwrap().insert("#dev".to_string(), ["bob".to_string()].into());
+        let message = json!({ "type": "message", "recipient": "#dev", "content": "збірка зелена" }).to_string();
+        sse::post(&node_a, &alice_stream, "alice", message).unwrap();
+        let room = next_event(&mut bob, |event| event["type"] == "room").await;
+        assert_eq!(room["room"], "#dev");
+        assert_eq!(room["content"], "збірка зелена");
+
+        let group_id = crate::groups::new_id();
+        node_a.groups.lock().unwrap().insert(group_id.clone(), GroupConversation {
+            id: group_id.clone(),
+            owner: "alice".to_string(),
+            participants: ["alice".to_string(), "carol".to_string(), "dave".to_string()].into(),
+            messages: Vec::new(),
+        });
+        let message = json!({ "type": "message", "recipient": group_id, "content": "план на тиждень" }).to_string();
+        sse::post(&node_a, &alice_stream, "alice", message).unwrap();
+        let group = next_event(&mut carol, |event| event["type"] == "group").await;
+        assert_eq!(group["group"], group_id.as_str());
+        assert_eq!(group["content"], "план на тиждень");
+
+        let message = json!({ "type": "message", "recipient": "#dev", "content": "ще одне" }).to_string();
+        sse::post(&node_a, &alice_stream, "alice", message).unwrap();
+        next_event(&mut bob, |event| event["type"] == "room" && event["content"] == "ще одне").await;
+        assert!(tokio::time::timeout(Duration::from_millis(300), next_event(&mut carol, |event| event["type"] == "room")).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn routes_encrypted_messages_and_reports_undeliverable_ones() {
+        let (port_a, port_b) = (free_port(), free_port());
+        let node_a = node("a", port_a, port_b);
+        let node_b = node("b", port_b, port_a);
+
+        let (mut alice, alice_stream) = connect(&node_a, "alice").await;
+        let (mut bob, _) = connect(&node_b, "bob").await;
+        node_a.cluster.start(node_a.clone()).unwrap();
+        node_b.cluster.start(node_b.clone()).unwrap();
+        wait_until(|| node_a.remote_presence.lock().unwrap().contains_key("bob")).await;
+
+        let send = |to: &str| {
+            let message = json!({ "type": "encrypted", "recipient": to, "ciphertext": "c2VjcmV0" }).to_string();
+            sse::post(&node_a, &alice_stream, "alice", message).unwrap();
+        };
+        send("bob");
+        let encrypted = next_event(&mut bob, |event| event["type"] == "encrypted").await;
+        assert_eq!(encrypted["from"], "alice");
+        assert_eq!(encrypted["ciphertext"], "c2VjcmV0");
+
+        node_a.users.lock().unwrap().insert("carol".to_string(), User {
+            username: "carol".to_string(),
+            password: String::new(),
+            is_bot: false,
+            profile: Profile::default(),
+            owner: None,
+        });
+        let errors = [
+            ("dave", "Користувач не знайдений"),
+            ("carol", "Користувач не опублікував ключі шифрування"),
+        ];
+        for (to, expected) in errors {
+            send(to);
+            let error = next_event(&mut alice, |event| event["type"] == "error").await;
+            assert_eq!(error["message"], expected);
+        }
+        node_a.key_bundles.lock().unwrap().insert("carol".to_string(), KeyBundle::default());
+        send("carol");
+        let error = next_event(&mut alice, |event| event["type"] == "error").await;
+        assert_eq!(error["message"], "Користувач не в мережі, зашифроване повідомлення не доставлено");
+    }
+
+    #[actix_web::test]
+    async fn rejects_peers_without_the_secret() {
+        let port = free_port();
+        let node_a = node("a", port, free_port());
+        node_a.cluster.start(node_a.clone()).unwrap();
+
+        let mut intruder = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let hello = json!({ "node": "x", "secret": "guess" });
+        let forged = json!({ "origin": "x", "event": { "type": "presence", "username": "mallory", "online": true } });
+        intruder.write_all(format!("{}\n{}\n", hello, forged).as_bytes()).await.unwrap();
+
+        let mut buffer = [0u8; 1];
+        let closed = tokio::time::timeout(Duration::from_secs(5), intruder.read(&mut buffer)).await.unwrap();
+        assert!(matches!(closed, Ok(0) | Err(_)));
+        assert!(node_a.remote_presence.lock().unwrap().is_empty());
+        assert!(secrets_match("secret", "secret"));
+        assert!(!secrets_match("secret", "secret2"));
+    }
+}
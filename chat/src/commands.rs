@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use actix_web_actors::ws;
+use serde_json::{json, Value};
+use crate::websocket::ChatSession;
+use crate::groups;
+use crate::privacy;
+use crate::history;
+use crate::cluster;
+
+pub type CommandResult = Result<Value, String>;
+
+pub trait Command: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn usage(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    fn execute(&self, session: &mut ChatSession, recipient: &str, args: &str, ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult;
+}
+
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl CommandRegistry {
+    pub fn with_builtins() -> Self {
+        let mut registry = CommandRegistry::default();
+        registry.register(Box::new(MeCommand));
+        registry.register(Box::new(WhoCommand));
+        registry.register(Box::new(MsgCommand));
+        registry.register(Box::new(JoinCommand));
+        registry.register(Box::new(LeaveCommand));
+        registry.register(Box::new(AwayCommand));
+        registry.register(Box::new(HelpCommand));
+        registry
+    }
+
+    pub fn register(&mut self, command: Box<dyn Command>) {
+        self.commands.insert(command.name(), command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Command> {
+        self.commands.get(name).map(|command| command.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.values().map(|command| command.as_ref())
+    }
+}
+
+pub const ESCAPE: &str = "//";
+
+pub fn is_command(content: &str) -> bool {
+    content.starts_with('/') && !content.starts_with(ESCAPE)
+}
+
+pub fn unescape(content: &str) -> &str {
+    if content.starts_with(ESCAPE) { &content[1..] } else { content }
+}
+
+pub fn room_name(name: &str) -> Option<String> {
+    let name = name.trim_start_matches('#');
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| format!("#{}", name))
+}
+
+struct MeCommand;
+
+impl Command for MeCommand {
+    fn name(&self) -> &'static str { "me" }
+    fn usage(&self) -> &'static str { "/me <дія>" }
+    fn description(&self) -> &'static str { "Надіслати повідомлення-дію від третьої особи" }
+
+    fn execute(&self, session: &mut ChatSession, recipient: &str, args: &str, ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        if args.is_empty() {
+            return Err(format!("Використання: {}", self.usage()));
+        }
+        let content = format!("* {} {}", session.username, args);
+        if recipient == "public" {
+            session.send_public(&content, None)?;
+        } else if recipient.starts_with('#') {
+            session.send_room(recipient, &content, None)?;
+        } else if groups::is_group(recipient) {
+            session.send_group(recipient, &content, None)?;
+        } else {
+            session.send_private(recipient, &content, None, ctx)?;
+        }
+        Ok(json!({ "recipient": recipient, "content": content }))
+    }
+}
+
+struct WhoCommand;
+
+impl Command for WhoCommand {
+    fn name(&self) -> &'static str { "who" }
+    fn usage(&self) -> &'static str { "/who" }
+    fn description(&self) -> &'static str { "Показати користувачів онлайн" }
+
+    fn execute(&self, session: &mut ChatSession, _recipient: &str, _args: &str, _ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        let mut users: Vec<String> = cluster::online(&session.app_state).into_iter()
+            .filter(|user| *user == session.username || !privacy::is_hidden(&session.app_state, user))
+            .collect();
+        users.sort();
+        let away = session.app_state.away.lock().unwrap();
+        let users: Vec<Value> = users.iter()
+            .map(|user| json!({ "username": user, "away": away.get(user) }))
+            .collect();
+        Ok(json!({ "users": users }))
+    }
+}
+
+struct MsgCommand;
+
+impl Command for MsgCommand {
+    fn name(&self) -> &'static str { "msg" }
+    fn usage(&self) -> &'static str { "/msg <користувач> <текст>" }
+    fn description(&self) -> &'static str { "Надіслати приватне повідомлення" }
+
+    fn execute(&self, session: &mut ChatSession, _recipient: &str, args: &str, ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        let Some((to, text)) = args.split_once(char::is_whitespace) else {
+            return Err(format!("Використання: {}", self.usage()));
+        };
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(format!("Використання: {}", self.usage()));
+        }
+        session.send_private(to, text, None, ctx)?;
+        Ok(json!({ "to": to }))
+    }
+}
+
+struct JoinCommand;
+
+impl Command for JoinCommand {
+    fn name(&self) -> &'static str { "join" }
+    fn usage(&self) -> &'static str { "/join <кімната>" }
+    fn description(&self) -> &'static str { "Приєднатися до кімнати або створити її" }
+
+    fn execute(&self, session: &mut ChatSession, _recipient: &str, args: &str, _ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        let Some(room) = room_name(args) else {
+            return Err(format!("Використання: {}", self.usage()));
+        };
+
+        let members: Vec<String> = {
+            let mut rooms = session.app_state.rooms.lock().unwrap();
+            let members = rooms.entry(room.clone()).or_default();
+            if !members.insert(session.username.clone()) {
+                return Err("Ви вже є учасником цієї кімнати".to_string());
+            }
+            members.iter().cloned().collect()
+        };
+        history::open(&session.app_state, &session.username, &room);
+
+        let notification = json!({
+            "type": "room_joined",
+            "room": room,
+            "username": session.username
+        }).to_string();
+        let others: Vec<String> = members.iter().filter(|member| **member != session.username).cloned().collect();
+        cluster::send_to(&session.app_state, &others, &notification);
+
+        Ok(json!({ "room": room, "members": members }))
+    }
+}
+
+struct LeaveCommand;
+
+impl Command for LeaveCommand {
+    fn name(&self) -> &'static str { "leave" }
+    fn usage(&self) -> &'static str { "/leave [кімната]" }
+    fn description(&self) -> &'static str { "Вийти з кімнати" }
+
+    fn execute(&self, session: &mut ChatSession, recipient: &str, args: &str, _ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        let target = if args.is_empty() && recipient.starts_with('#') { recipient } else { args };
+        let Some(room) = room_name(target) else {
+            return Err(format!("Використання: {}", self.usage()));
+        };
+
+        let members: Vec<String> = {
+            let mut rooms = session.app_state.rooms.lock().unwrap();
+            let Some(members) = rooms.get_mut(&room) else {
+                return Err("Ви не є учасником цієї кімнати".to_string());
+            };
+            if !members.remove(&session.username) {
+                return Err("Ви не є учасником цієї кімнати".to_string());
+            }
+            let members: Vec<String> = members.iter().cloned().collect();
+            if members.is_empty() {
+                rooms.remove(&room);
+            }
+            members
+        };
+        if let Some(cursors) = session.app_state.read_cursors.lock().unwrap().get_mut(&session.username) {
+            cursors.remove(&room);
+        }
+        history::touch(&session.app_state);
+        if let Some(conversations) = session.app_state.unread.lock().unwrap().get_mut(&session.username) {
+            conversations.remove(&room);
+        }
+
+        let notification = json!({
+            "type": "room_left",
+            "room": room,
+            "username": session.username
+        }).to_string();
+        cluster::send_to(&session.app_state, &members, &notification);
+
+        Ok(json!({ "room": room }))
+    }
+}
+
+struct AwayCommand;
+
+impl Command for AwayCommand {
+    fn name(&self) -> &'static str { "away" }
+    fn usage(&self) -> &'static str { "/away [повідомлення]" }
+    fn description(&self) -> &'static str { "Позначити себе відсутнім або повернутися" }
+
+    fn execute(&self, session: &mut ChatSession, _recipient: &str, args: &str, _ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        let away_message = {
+            let mut away = session.app_state.away.lock().unwrap();
+            if args.is_empty() && away.remove(&session.username).is_some() {
+                None
+            } else {
+                let message = if args.is_empty() { "Відійшов".to_string() } else { args.to_string() };
+                away.insert(session.username.clone(), message.clone());
+                Some(message)
+            }
+        };
+
+        let notification = match &away_message {
+            Some(message) => json!({
+                "type": "user_away",
+                "username": session.username,
+                "message": message
+            }),
+            None => json!({
+                "type": "user_back",
+                "username": session.username
+            }),
+        }.to_string();
+        cluster::broadcast(&session.app_state, &notification);
+
+        Ok(json!({ "away": away_message.is_some(), "message": away_message }))
+    }
+}
+
+struct HelpCommand;
+
+impl Command for HelpCommand {
+    fn name(&self) -> &'static str { "help" }
+    fn usage(&self) -> &'static str { "/help" }
+    fn description(&self) -> &'static str { "Показати список команд" }
+
+    fn execute(&self, session: &mut ChatSession, _recipient: &str, _args: &str, _ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+        let commands: Vec<Value> = session.commands.iter()
+            .map(|command| json!({
+                "name": command.name(),
+                "usage": command.usage(),
+                "description": command.description()
+            }))
+            .collect();
+        Ok(json!({ "commands": commands }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use futures_util::StreamExt;
+    use super::*;
+    use crate::config::Config;
+    use crate::cluster::InProcessFanOut;
+    use crate::sse;
+
+    struct PingCommand;
+
+    impl Command for PingCommand {
+        fn name(&self) -> &'static str { "ping" }
+        fn usage(&self) -> &'static str { "/ping" }
+        fn description(&self) -> &'static str { "Перевірити з'єднання" }
+
+        fn execute(&self, session: &mut ChatSession, _recipient: &str, args: &str, _ctx: &mut ws::WebsocketContext<ChatSession>) -> CommandResult {
+            Ok(json!({ "pong": session.username, "args": args }))
+        }
+    }
+
+    #[actix_web::test]
+    async fn runs_commands_registered_at_construction() {
+        let config = Config::from_env();
+        let cluster = Box::new(InProcessFanOut::new(&config.cluster.node_id, InProcessFanOut::bus()));
+        let mut commands = CommandRegistry::with_builtins();
+        commands.register(Box::new(PingCommand));
+        let app_state = actix_web::web::Data::new(crate::AppState::new(config, cluster, commands));
+        assert!(app_state.commands.iter().any(|command| command.name() == "help"));
+
+        let mut events = Box::pin(sse::open(&app_state, "alice", "alice-token"));
+        let hello = events.next().await.unwrap().unwrap();
+        let hello = String::from_utf8_lossy(&hello).to_string();
+        let data = hello.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+        let stream_id = serde_json::from_str::<Value>(data).unwrap()["streamId"].as_str().unwrap().to_string();
+
+        let message = json!({ "type": "message", "recipient": "public", "content": "/ping раз два" }).to_string();
+        sse::post(&app_state, &stream_id, "alice", message).unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.next()).await
+                .expect("no event within 5 seconds")
+                .expect("event stream ended")
+                .unwrap();
+            let event = String::from_utf8_lossy(&event).to_string();
+            let Some(data) = event.lines().find_map(|line| line.strip_prefix("data: ")) else {
+                continue;
+            };
+            let event: Value = serde_json::from_str(data).unwrap();
+            if event["type"] == "command_result" {
+                assert_eq!(event["command"], "ping");
+                assert_eq!(event["result"], json!({ "pong": "alice", "args": "раз два" }));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn double_slash_escapes_commands() {
+        assert!(is_command("/join rust"));
+        assert!(!is_command("//join rust"));
+        assert!(!is_command("hello /join"));
+        assert_eq!(unescape("//join rust"), "/join rust");
+        assert_eq!(unescape("///"), "//");
+        assert_eq!(unescape("/join rust"), "/join rust");
+        assert_eq!(unescape("hello"), "hello");
+    }
+}
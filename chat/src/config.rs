@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::crypto::Keyring;
+use crate::groups;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeletionPolicy {
+    Anonymize,
+    Purge,
+}
+
+impl FromStr for DeletionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "anonymize" => Ok(DeletionPolicy::Anonymize),
+            "purge" => Ok(DeletionPolicy::Purge),
+            _ => Err(format!("unknown deletion policy '{}'", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    CoalescePresence,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "coalesce_presence" => Ok(OverflowPolicy::CoalescePresence),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}'", value)),
+        }
+    }
+}
+
+impl OverflowPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::CoalescePresence => "coalesce_presence",
+            OverflowPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetentionPolicy {
+    Forever,
+    MaxAge(Duration),
+    MaxCount(usize),
+}
+
+impl RetentionPolicy {
+    pub fn keeps(&self, timestamp: u64, now: u64, newer: usize) -> bool {
+        match self {
+            RetentionPolicy::Forever => true,
+            RetentionPolicy::MaxAge(max_age) => now.saturating_sub(timestamp) <= max_age.as_secs(),
+            RetentionPolicy::MaxCount(max_count) => newer < *max_count,
+        }
+    }
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value == "forever" {
+            return Ok(RetentionPolicy::Forever);
+        }
+        let unit = match value.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3600,
+            Some('d') => 86400,
+            _ => return value.parse().map(RetentionPolicy::MaxCount).map_err(|_| format!("invalid retention policy '{}'", value)),
+        };
+        let seconds = value[..value.len() - 1].parse::<u64>().ok()
+            .and_then(|amount| amount.checked_mul(unit))
+            .ok_or_else(|| format!("invalid retention policy '{}'", value))?;
+        Ok(RetentionPolicy::MaxAge(Duration::from_secs(seconds)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub node_id: String,
+    pub listen: Option<String>,
+    pub peers: Vec<String>,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub direct_messages: RetentionPolicy,
+    pub rooms: RetentionPolicy,
+    pub room_overrides: HashMap<String, RetentionPolicy>,
+    pub cleanup_interval: Duration,
+}
+
+impl Retention {
+    pub fn policy_for(&self, conversation: &str) -> RetentionPolicy {
+        let is_room = conversation == "public" || conversation.starts_with('#') || groups::is_group(conversation);
+        if !is_room {
+            return self.direct_messages;
+        }
+        self.room_overrides.get(conversation).copied().unwrap_or(self.rooms)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind_address: String,
+    pub account_deletion: DeletionPolicy,
+    pub deleted_files_retention: Duration,
+    pub user_storage_quota: usize,
+    pub denied_mime_types: Vec<String>,
+    pub encryption: Keyring,
+    pub admins: Vec<String>,
+    pub retention: Retention,
+    pub history_save_interval: Duration,
+    pub outbound_queue_capacity: usize,
+    pub outbound_overflow: OverflowPolicy,
+    pub metrics_token: Option<String>,
+    pub log_format: LogFormat,
+    pub log_filter: String,
+    pub shutdown_timeout: Duration,
+    pub reconnect_after: Duration,
+    pub cluster: Cluster,
+    pub ws_compression: bool,
+    pub ws_max_frame_bytes: usize,
+    pub ws_max_message_bytes: usize,
+    pub irc_listen: Option<String>,
+    pub webhook_allow_private: bool,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let config = Config {
+            bind_address: env_or("CHAT_BIND_ADDRESS", "127.0.0.1:8080".to_string()),
+            account_deletion: env_or("CHAT_ACCOUNT_DELETION", DeletionPolicy::Anonymize),
+            deleted_files_retention: Duration::from_secs(env_or("CHAT_DELETED_FILES_RETENTION_HOURS", 0u64) * 3600),
+            user_storage_quota: env_or("CHAT_USER_QUOTA_MB", 100usize) * 1024 * 1024,
+            denied_mime_types: env_list("CHAT_DENIED_MIME_TYPES", &[
+                "application/x-executable",
+                "application/x-mach-binary",
+                "application/vnd.microsoft.portable-executable",
+                "text/x-shellscript",
+            ]),
+            encryption: Keyring::parse(
+                std::env::var("CHAT_ENCRYPTION_KEY").ok().as_deref(),
+                &env_list("CHAT_ENCRYPTION_PREVIOUS_KEYS", &[]),
+            ).unwrap_or_else(|error| panic!("invalid encryption keys: {}", error)),
+            admins: env_list("CHAT_ADMINS", &[]),
+            retention: Retention {
+                direct_messages: env_or("CHAT_RETENTION_DM", RetentionPolicy::Forever),
+                rooms: env_or("CHAT_RETENTION_ROOMS", RetentionPolicy::Forever),
+                room_overrides: env_list("CHAT_RETENTION_ROOM_OVERRIDES", &[]).iter().map(|entry| {
+                    entry.split_once('=')
+                        .and_then(|(room, policy)| Some((room.trim().to_string(), policy.parse().ok()?)))
+                        .unwrap_or_else(|| panic!("invalid value for CHAT_RETENTION_ROOM_OVERRIDES: '{}'", entry))
+                }).collect(),
+                cleanup_interval: Duration::from_secs(env_or("CHAT_CLEANUP_INTERVAL_SECS", 3600u64)),
+            },
+            history_save_interval: Duration::from_secs(env_or("CHAT_HISTORY_SAVE_INTERVAL_SECS", 5u64)),
+            outbound_queue_capacity: env_or("CHAT_OUTBOUND_QUEUE", 256usize).max(1),
+            outbound_overflow: env_or("CHAT_OUTBOUND_OVERFLOW", OverflowPolicy::DropOldest),
+            metrics_token: std::env::var("CHAT_METRICS_TOKEN").ok().filter(|token| !token.is_empty()),
+            log_format: env_or("CHAT_LOG_FORMAT", LogFormat::Pretty),
+            log_filter: env_or("CHAT_LOG", "info".to_string()),
+            shutdown_timeout: Duration::from_secs(env_or("CHAT_SHUTDOWN_TIMEOUT_SECS", 10u64)),
+            reconnect_after: Duration::from_secs(env_or("CHAT_RECONNECT_AFTER_SECS", 5u64)),
+            ws_compression: env_or("CHAT_WS_COMPRESSION", true),
+            ws_max_frame_bytes: env_or("CHAT_WS_MAX_FRAME_BYTES", 64 * 1024 * 1024usize).max(125),
+            ws_max_message_bytes: env_or("CHAT_WS_MAX_MESSAGE_BYTES", 64 * 1024 * 1024usize).max(125),
+            irc_listen: std::env::var("CHAT_IRC_LISTEN").ok().filter(|address| !address.is_empty()),
+            webhook_allow_private: env_or("CHAT_WEBHOOK_ALLOW_PRIVATE", false),
+            cluster: Cluster {
+                node_id: env_or("CHAT_NODE_ID", uuid::Uuid::new_v4().simple().to_string()),
+                listen: std::env::var("CHAT_CLUSTER_LISTEN").ok().filter(|address| !address.is_empty()),
+                peers: env_list("CHAT_CLUSTER_PEERS", &[]),
+                secret: std::env::var("CHAT_CLUSTER_SECRET").unwrap_or_default(),
+            },
+        };
+        if config.cluster.listen.is_some() && config.cluster.secret.is_empty() {
+            panic!("CHAT_CLUSTER_SECRET is required when CHAT_CLUSTER_LISTEN is set");
+        }
+        config
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("invalid value for {}: '{}'", name, value)),
+        Err(_) => default,
+    }
+}
+
+fn env_list(name: &str, default: &[&str]) -> Vec<String> {
+    match std::env::var(name) {
+        Ok(value) => value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use actix_web::web::Bytes;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use futures_util::{stream, Stream};
+use tokio::io::AsyncReadExt;
+
+const MAGIC: &[u8; 4] = b"RCE1";
+const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+#[derive(Clone, Default)]
+pub struct Keyring {
+    active: Option<String>,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn parse_key(entry: &str) -> Result<(String, [u8; 32]), String> {
+    let (key_id, key) = entry.split_once(':').ok_or_else(|| format!("expected '<id>:<hex key>', got '{}'", entry))?;
+    if key_id.is_empty() || key_id.len() > u8::MAX as usize {
+        return Err(format!("invalid key id '{}'", key_id));
+    }
+    let key = hex::decode(key.trim()).map_err(|_| format!("key '{}' is not valid hex", key_id))?;
+    let key = key.try_into().map_err(|_| format!("key '{}' must be 32 bytes", key_id))?;
+    Ok((key_id.to_string(), key))
+}
+
+impl Keyring {
+    pub fn parse(active: Option<&str>, previous: &[String]) -> Result<Self, String> {
+        let mut keyring = Keyring::default();
+        for entry in previous {
+            let (key_id, key) = parse_key(entry)?;
+            keyring.keys.insert(key_id, key);
+        }
+        if let Some(entry) = active {
+            let (key_id, key) = parse_key(entry)?;
+            keyring.keys.insert(key_id.clone(), key);
+            keyring.active = Some(key_id);
+        }
+        Ok(keyring)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub fn is_active(&self, key_id: &str) -> bool {
+        self.active.as_deref() == Some(key_id)
+    }
+
+    pub fn active_id(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    fn cipher(&self, key_id: &str) -> Option<Aes256Gcm> {
+        self.keys.get(key_id).map(|key| Aes256Gcm::new(GenericArray::from_slice(key)))
+    }
+}
+
+struct Header {
+    key_id: String,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+impl Header {
+    fn len(&self) -> usize {
+        MAGIC.len() + 1 + self.key_id.len() + NONCE_PREFIX_LEN
+    }
+}
+
+fn parse_header(data: &[u8]) -> Option<Header> {
+    let rest = data.strip_prefix(MAGIC)?;
+    let (&id_len, rest) = rest.split_first()?;
+    let key_id = std::str::from_utf8(rest.get(..id_len as usize)?).ok()?.to_string();
+    let nonce_prefix = rest.get(id_len as usize..id_len as usize + NONCE_PREFIX_LEN)?.try_into().ok()?;
+    Some(Header { key_id, nonce_prefix })
+}
+
+fn chunk_count(body_len: usize) -> usize {
+    body_len.div_ceil(SEALED_CHUNK_SIZE).max(1)
+}
+
+pub fn encrypt(keyring: &Keyring, data: &[u8]) -> Result<Vec<u8>, String> {
+    let key_id = keyring.active.as_deref().ok_or_else(|| "encryption is not configured".to_string())?;
+    let cipher = keyring.cipher(key_id).ok_or_else(|| format!("unknown key '{}'", key_id))?;
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let mut sealed = Vec::with_capacity(data.len() + (data.len() / CHUNK_SIZE + 1) * TAG_LEN + 64);
+    sealed.extend_from_slice(MAGIC);
+    sealed.push(key_id.len() as u8);
+    sealed.extend_from_slice(key_id.as_bytes());
+    sealed.extend_from_slice(&nonce_prefix);
+
+    let mut encryptor = EncryptorBE32::from_aead(cipher, GenericArray::from_slice(&nonce_prefix));
+    let mut chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+    let last = chunks.pop().unwrap_or(&[]);
+    for chunk in chunks {
+        sealed.extend(encryptor.encrypt_next(chunk).map_err(|_| "encryption failed".to_string())?);
+    }
+    sealed.extend(encryptor.encrypt_last(last).map_err(|_| "encryption failed".to_string())?);
+    Ok(sealed)
+}
+
+pub fn decrypt(keyring: &Keyring, data: &[u8]) -> Result<Vec<u8>, String> {
+    let header = parse_header(data).ok_or_else(|| "data is not encrypted".to_string())?;
+    let cipher = keyring.cipher(&header.key_id).ok_or_else(|| format!("unknown key '{}'", header.key_id))?;
+    let body = &data[header.len()..];
+
+    let mut decryptor = DecryptorBE32::from_aead(cipher, GenericArray::from_slice(&header.nonce_prefix));
+    let mut chunks: Vec<&[u8]> = body.chunks(SEALED_CHUNK_SIZE).collect();
+    let last = chunks.pop().unwrap_or(&[]);
+    let mut plain = Vec::with_capacity(body.len());
+    for chunk in chunks {
+        plain.extend(decryptor.decrypt_next(chunk).map_err(|_| "decryption failed".to_string())?);
+    }
+    plain.extend(decryptor.decrypt_last(last).map_err(|_| "decryption failed".to_string())?);
+    Ok(plain)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub async fn open_stream(
+    keyring: &Keyring,
+    path: &str,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let total = file.metadata().await?.len() as usize;
+
+    let mut prefix = [0u8; MAGIC.len() + 1];
+    if total < prefix.len() {
+        return Err(invalid_data("truncated file"));
+    }
+    file.read_exact(&mut prefix).await?;
+    if &prefix[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("missing encryption header"));
+    }
+    let mut rest = vec![0u8; prefix[MAGIC.len()] as usize + NONCE_PREFIX_LEN];
+    file.read_exact(&mut rest).await?;
+    let header = parse_header(&[prefix.as_slice(), &rest].concat()).ok_or_else(|| invalid_data("corrupted header"))?;
+    let cipher = keyring.cipher(&header.key_id).ok_or_else(|| invalid_data("unknown encryption key"))?;
+
+    let body_len = total.checked_sub(header.len()).ok_or_else(|| invalid_data("truncated file"))?;
+    let chunks = chunk_count(body_len);
+    let decryptor = DecryptorBE32::from_aead(cipher, GenericArray::from_slice(&header.nonce_prefix));
+
+    Ok(stream::try_unfold((file, Some(decryptor), 0usize), move |(mut file, decryptor, index)| async move {
+        let Some(mut decryptor) = decryptor else {
+            return Ok(None);
+        };
+        let last = index + 1 == chunks;
+        let len = if last { body_len - index * SEALED_CHUNK_SIZE } else { SEALED_CHUNK_SIZE };
+        let mut buffer = vec![0u8; len];
+        file.read_exact(&mut buffer).await?;
+
+        let (plain, decryptor) = if last {
+            (decryptor.decrypt_last(buffer.as_slice()), None)
+        } else {
+            (decryptor.decrypt_next(buffer.as_slice()), Some(decryptor))
+        };
+        let plain = plain.map_err(|_| invalid_data("decryption failed"))?;
+        Ok(Some((Bytes::from(plain), (file, decryptor, index + 1))))
+    }))
+}
@@ -0,0 +1,670 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::web::{Buf, Bytes, BytesMut};
+use actix_web_actors::ws::WebsocketContext;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures_util::stream::{self, Stream, StreamExt};
+use tokio::sync::mpsc;
+use crate::config::Config;
+use crate::websocket::ChatSession;
+
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const MIN_COMPRESSED_BYTES: usize = 128;
+const INFLATE_CHUNK: usize = 16 * 1024;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_frame: usize,
+    pub max_message: usize,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Self {
+        Limits {
+            max_frame: config.ws_max_frame_bytes,
+            max_message: config.ws_max_message_bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deflate {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl Deflate {
+    fn accept(offer: &str) -> Option<Deflate> {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut deflate = Deflate::default();
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            match (name, value) {
+                ("server_no_context_takeover", None) => deflate.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => deflate.client_no_context_takeover = true,
+                ("server_max_window_bits", Some("15")) => {},
+                ("client_max_window_bits", _) => {},
+                _ => return None,
+            }
+        }
+        Some(deflate)
+    }
+
+    pub fn negotiate(headers: &HeaderMap) -> Option<Deflate> {
+        headers.get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(Deflate::accept)
+    }
+
+    pub fn header(&self) -> String {
+        let mut header = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        header
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Violation {
+    code: CloseCode,
+    reason: &'static str,
+}
+
+impl Violation {
+    fn too_big() -> Self {
+        Violation { code: CloseCode::Size, reason: "Повідомлення завелике" }
+    }
+
+    fn protocol() -> Self {
+        Violation { code: CloseCode::Protocol, reason: "Порушення протоколу WebSocket" }
+    }
+
+    fn invalid() -> Self {
+        Violation { code: CloseCode::Invalid, reason: "Некоректне повідомлення" }
+    }
+}
+
+type Pending = Rc<RefCell<Option<Violation>>>;
+
+struct RawFrame {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    payload: BytesMut,
+}
+
+fn parse_frame(buffer: &mut BytesMut, max_frame: usize) -> Result<Option<RawFrame>, Violation> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let (first, second) = (buffer[0], buffer[1]);
+    if first & 0x30 != 0 || second & 0x80 == 0 {
+        return Err(Violation::protocol());
+    }
+
+    let (length, header) = match second & 0x7f {
+        126 if buffer.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(buffer[2..10].try_into().unwrap()), 10),
+        length => (length as u64, 2),
+    };
+    if length > max_frame as u64 {
+        return Err(Violation::too_big());
+    }
+    let length = length as usize;
+    if buffer.len() < header + 4 + length {
+        return Ok(None);
+    }
+
+    let mask = [buffer[header], buffer[header + 1], buffer[header + 2], buffer[header + 3]];
+    buffer.advance(header + 4);
+    let mut payload = buffer.split_to(length);
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok(Some(RawFrame {
+        fin: first & 0x80 != 0,
+        rsv1: first & 0x40 != 0,
+        opcode: first & 0x0f,
+        payload,
+    }))
+}
+
+fn write_frame(buffer: &mut BytesMut, opcode: u8, compressed: bool, payload: &[u8]) {
+    buffer.extend_from_slice(&[0x80 | if compressed { 0x40 } else { 0 } | opcode]);
+    match payload.len() {
+        length if length < 126 => buffer.extend_from_slice(&[length as u8]),
+        length if length <= u16::MAX as usize => {
+            buffer.extend_from_slice(&[126]);
+            buffer.extend_from_slice(&(length as u16).to_be_bytes());
+        },
+        length => {
+            buffer.extend_from_slice(&[127]);
+            buffer.extend_from_slice(&(length as u64).to_be_bytes());
+        },
+    }
+    buffer.extend_from_slice(payload);
+}
+
+fn inflate(inflater: &mut Decompress, data: &[u8], limit: usize) -> Result<Vec<u8>, Violation> {
+    let input = [data, &DEFLATE_TRAILER].concat();
+    let mut output = Vec::with_capacity(INFLATE_CHUNK.min(limit + 1));
+    let mut consumed = 0;
+    loop {
+        let before = inflater.total_in();
+        let status = inflater.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+            .map_err(|_| Violation::invalid())?;
+        consumed += (inflater.total_in() - before) as usize;
+        if output.len() > limit {
+            return Err(Violation::too_big());
+        }
+        let finished = consumed >= input.len() && output.len() < output.capacity();
+        if finished || status == Status::StreamEnd {
+            return Ok(output);
+        }
+        if status == Status::BufError && output.len() < output.capacity() {
+            return Err(Violation::invalid());
+        }
+        output.reserve(INFLATE_CHUNK);
+    }
+}
+
+fn deflate(compressor: &mut Compress, data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() / 2 + 64);
+    let mut consumed = 0;
+    loop {
+        let before = compressor.total_in();
+        if compressor.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync).is_err() {
+            break;
+        }
+        consumed += (compressor.total_in() - before) as usize;
+        if consumed >= data.len() && output.len() < output.capacity() {
+            break;
+        }
+        output.reserve(output.capacity().max(64));
+    }
+    if output.ends_with(&DEFLATE_TRAILER) {
+        output.truncate(output.len() - DEFLATE_TRAILER.len());
+    }
+    output
+}
+
+struct Partial {
+    opcode: u8,
+    compressed: bool,
+    data: BytesMut,
+}
+
+struct Inbound<S> {
+    payload: Pin<Box<S>>,
+    buffer: BytesMut,
+    message: Option<Partial>,
+    inflater: Option<Decompress>,
+    client_no_context_takeover: bool,
+    limits: Limits,
+    encoder: Codec,
+    pending: Pending,
+}
+
+impl<S> Inbound<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>>,
+{
+    fn control(&self, frame: RawFrame) -> Result<Message, Violation> {
+        if !frame.fin || frame.rsv1 || frame.payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(Violation::protocol());
+        }
+        let payload = frame.payload.freeze();
+        match frame.opcode {
+            OP_PING => Ok(Message::Ping(payload)),
+            OP_PONG => Ok(Message::Pong(payload)),
+            _ if payload.is_empty() => Ok(Message::Close(None)),
+            _ if payload.len() == 1 => Err(Violation::protocol()),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let description = std::str::from_utf8(&payload[2..]).map_err(|_| Violation::invalid())?;
+                Ok(Message::Close(Some(CloseReason {
+                    code: CloseCode::from(code),
+                    description: (!description.is_empty()).then(|| description.to_string()),
+                })))
+            },
+        }
+    }
+
+    fn data(&mut self, frame: RawFrame) -> Result<Option<Message>, Violation> {
+        match (frame.opcode, self.message.is_some()) {
+            (OP_TEXT | OP_BINARY, false) => {
+                if frame.rsv1 && self.inflater.is_none() {
+                    return Err(Violation::protocol());
+                }
+                self.message = Some(Partial { opcode: frame.opcode, compressed: frame.rsv1, data: BytesMut::new() });
+            },
+            (OP_CONTINUATION, true) if !frame.rsv1 => {},
+            _ => return Err(Violation::protocol()),
+        }
+
+        let message = self.message.as_mut().unwrap();
+        message.data.extend_from_slice(&frame.payload);
+        if message.data.len() > self.limits.max_message {
+            return Err(Violation::too_big());
+        }
+        if !frame.fin {
+            return Ok(None);
+        }
+
+        let message = self.message.take().unwrap();
+        let data = match (message.compressed, self.inflater.as_mut()) {
+            (true, Some(inflater)) => {
+                let data = inflate(inflater, &message.data, self.limits.max_message)?;
+                if self.client_no_context_takeover {
+                    inflater.reset(false);
+                }
+                Bytes::from(data)
+            },
+            _ => message.data.freeze(),
+        };
+        if message.opcode == OP_BINARY {
+            return Ok(Some(Message::Binary(data)));
+        }
+        let text = String::from_utf8(data.to_vec()).map_err(|_| Violation::invalid())?;
+        Ok(Some(Message::Text(text.into())))
+    }
+
+    fn next_message(&mut self) -> Result<Option<Message>, Violation> {
+        while let Some(frame) = parse_frame(&mut self.buffer, self.limits.max_frame)? {
+            let message = match frame.opcode {
+                OP_CLOSE | OP_PING | OP_PONG => Some(self.control(frame)?),
+                _ => self.data(frame)?,
+            };
+            if message.is_some() {
+                return Ok(message);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn next(&mut self) -> Option<Result<Bytes, PayloadError>> {
+        loop {
+            match self.next_message() {
+                Ok(Some(message)) => {
+                    let mut encoded = BytesMut::new();
+                    self.encoder.encode(message, &mut encoded).ok()?;
+                    return Some(Ok(encoded.freeze()));
+                },
+                Ok(None) => {},
+                Err(violation) => {
+                    tracing::info!(code = u16::from(violation.code), reason = violation.reason, "closing websocket");
+                    *self.pending.borrow_mut() = Some(violation);
+                    return None;
+                },
+            }
+
+            match self.payload.next().await? {
+                Ok(chunk) => self.buffer.extend_from_slice(&chunk),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+struct Outbound {
+    frames: Pin<Box<dyn Stream<Item = Result<Bytes, actix_web::Error>>>>,
+    decoder: Codec,
+    encoder: Codec,
+    buffer: BytesMut,
+    compressor: Option<Compress>,
+    server_no_context_takeover: bool,
+    pending: Pending,
+    finished: bool,
+}
+
+impl Outbound {
+    fn encode(&mut self, frame: Frame) -> Option<Bytes> {
+        let mut encoded = BytesMut::new();
+        let (opcode, data) = match frame {
+            Frame::Text(data) => (OP_TEXT, data),
+            Frame::Binary(data) => (OP_BINARY, data),
+            Frame::Ping(data) => {
+                self.encoder.encode(Message::Ping(data), &mut encoded).ok()?;
+                return Some(encoded.freeze());
+            },
+            Frame::Pong(data) => {
+                self.encoder.encode(Message::Pong(data), &mut encoded).ok()?;
+                return Some(encoded.freeze());
+            },
+            Frame::Close(reason) => {
+                self.encoder.encode(Message::Close(reason), &mut encoded).ok()?;
+                return Some(encoded.freeze());
+            },
+            Frame::Continuation(_) => return None,
+        };
+
+        match self.compressor.as_mut() {
+            Some(compressor) if data.len() >= MIN_COMPRESSED_BYTES => {
+                let compressed = deflate(compressor, &data);
+                if self.server_no_context_takeover {
+                    compressor.reset();
+                }
+                write_frame(&mut encoded, opcode, true, &compressed);
+            },
+            _ => write_frame(&mut encoded, opcode, false, &data),
+        }
+        Some(encoded.freeze())
+    }
+
+    fn close_frame(&mut self) -> Option<Bytes> {
+        let violation = self.pending.borrow_mut().take()?;
+        let mut encoded = BytesMut::new();
+        let reason = CloseReason { code: violation.code, description: Some(violation.reason.to_string()) };
+        self.encoder.encode(Message::Close(Some(reason)), &mut encoded).ok()?;
+        Some(encoded.freeze())
+    }
+
+    async fn next(&mut self) -> Option<Result<Bytes, actix_web::Error>> {
+        if self.finished {
+            return None;
+        }
+        loop {
+            if self.compressor.is_some() {
+                match self.decoder.decode(&mut self.buffer) {
+                    Ok(Some(frame)) => match self.encode(frame) {
+                        Some(encoded) => return Some(Ok(encoded)),
+                        None => continue,
+                    },
+                    Ok(None) => {},
+                    Err(error) => {
+                        tracing::warn!(%error, "failed to decode outgoing frame");
+                        return None;
+                    },
+                }
+            }
+
+            match self.frames.next().await {
+                Some(Ok(chunk)) if self.compressor.is_some() => self.buffer.extend_from_slice(&chunk),
+                Some(result) => return Some(result),
+                None => {
+                    self.finished = true;
+                    return self.close_frame().map(Ok);
+                },
+            }
+        }
+    }
+}
+
+pub fn session_decoder() -> Codec {
+    Codec::new().client_mode().max_size(usize::MAX)
+}
+
+pub fn text_frames(inbox: mpsc::Receiver<String>) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+    stream::unfold((inbox, Codec::new().client_mode()), |(mut inbox, mut codec)| async move {
+        let text = inbox.recv().await?;
+        let mut buffer = BytesMut::new();
+        codec.encode(Message::Text(text.into()), &mut buffer).ok()?;
+        Some((Ok(buffer.freeze()), (inbox, codec)))
+    })
+}
+
+pub fn serve<S>(session: ChatSession, payload: S, limits: Limits, deflate: Option<Deflate>) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + 'static,
+{
+    let pending = Pending::default();
+    let inbound = Inbound {
+        payload: Box::pin(payload),
+        buffer: BytesMut::new(),
+        message: None,
+        inflater: deflate.map(|_| Decompress::new(false)),
+        client_no_context_takeover: deflate.map(|deflate| deflate.client_no_context_takeover).unwrap_or(false),
+        limits,
+        encoder: Codec::new().client_mode().max_size(limits.max_message),
+        pending: pending.clone(),
+    };
+    let inbound = stream::unfold(inbound, |mut inbound| async move {
+        let item = inbound.next().await?;
+        Some((item, inbound))
+    });
+
+    let frames = WebsocketContext::with_codec(session, inbound, Codec::new().max_size(limits.max_message));
+    let outbound = Outbound {
+        frames: Box::pin(frames),
+        decoder: session_decoder(),
+        encoder: Codec::new(),
+        buffer: BytesMut::new(),
+        compressor: deflate.map(|_| Compress::new(Compression::default(), false)),
+        server_no_context_takeover: deflate.map(|deflate| deflate.server_no_context_takeover).unwrap_or(false),
+        pending,
+        finished: false,
+    };
+    stream::unfold(outbound, |mut outbound| async move {
+        let item = outbound.next().await?;
+        Some((item, outbound))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    fn client_frame(first: u8, payload: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        frame.extend_from_slice(&[first]);
+        match payload.len() {
+            length if length < 126 => frame.extend_from_slice(&[0x80 | length as u8]),
+            length if length <= u16::MAX as usize => {
+                frame.extend_from_slice(&[0x80 | 126]);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+            length => {
+                frame.extend_from_slice(&[0x80 | 127]);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ MASK[index % 4]));
+        frame
+    }
+
+    fn receiver(limits: Limits, deflate: bool) -> Inbound<stream::Empty<Result<Bytes, PayloadError>>> {
+        Inbound {
+            payload: Box::pin(stream::empty()),
+            buffer: BytesMut::new(),
+            message: None,
+            inflater: deflate.then(|| Decompress::new(false)),
+            client_no_context_takeover: false,
+            limits,
+            encoder: Codec::new().client_mode(),
+            pending: Pending::default(),
+        }
+    }
+
+    fn limits(max_frame: usize, max_message: usize) -> Limits {
+        Limits { max_frame, max_message }
+    }
+
+    fn code(result: Result<Option<Message>, Violation>) -> Option<CloseCode> {
+        result.err().map(|violation| violation.code)
+    }
+
+    #[test]
+    fn parses_masked_frames_of_every_length_encoding() {
+        for length in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..length).map(|index| index as u8).collect();
+            let mut buffer = client_frame(0x82, &payload);
+            let frame = parse_frame(&mut buffer, usize::MAX).unwrap().unwrap();
+            assert!(frame.fin && !frame.rsv1);
+            assert_eq!(frame.opcode, OP_BINARY);
+            assert_eq!(&frame.payload[..], &payload[..], "length {}", length);
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn waits_for_incomplete_frames() {
+        let frame = client_frame(0x81, &[b'x'; 300]);
+        for cut in [0, 1, 3, 7, frame.len() - 1] {
+            let mut buffer = BytesMut::from(&frame[..cut]);
+            assert!(parse_frame(&mut buffer, usize::MAX).unwrap().is_none(), "cut at {}", cut);
+            assert_eq!(buffer.len(), cut);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_and_oversized_frames() {
+        let unmasked = BytesMut::from(&[0x81, 0x01, b'x'][..]);
+        let reserved = client_frame(0x81 | 0x20, b"x");
+        let mut oversized_header = client_frame(0x82, &[0; 70000]);
+        oversized_header.truncate(10);
+        let cases = [
+            (unmasked, CloseCode::Protocol),
+            (reserved, CloseCode::Protocol),
+            (client_frame(0x82, &[0; 200]), CloseCode::Size),
+            (oversized_header, CloseCode::Size),
+        ];
+        for (mut buffer, expected) in cases {
+            let violation = parse_frame(&mut buffer, 128).err().expect("frame was accepted");
+            assert_eq!(violation.code, expected);
+        }
+    }
+
+    #[test]
+    fn enforces_control_frame_rules() {
+        let mut close = vec![0x03, 0xe8];
+        close.extend_from_slice("бувай".as_bytes());
+        let cases: Vec<(BytesMut, Option<CloseCode>)> = vec![
+            (client_frame(0x89, b"ping"), None),
+            (client_frame(0x88, &close), None),
+            (client_frame(0x88, &[]), None),
+            (client_frame(0x09, b"fragmented ping"), Some(CloseCode::Protocol)),
+            (client_frame(0x89, &[0; 126]), Some(CloseCode::Protocol)),
+            (client_frame(0xc9, b"compressed"), Some(CloseCode::Protocol)),
+            (client_frame(0x88, &[0x03]), Some(CloseCode::Protocol)),
+            (client_frame(0x88, &[0x03, 0xe8, 0xff]), Some(CloseCode::Invalid)),
+        ];
+        for (frame, expected) in cases {
+            let mut inbound = receiver(limits(1024, 1024), true);
+            inbound.buffer = frame;
+            assert_eq!(code(inbound.next_message()), expected);
+        }
+
+        let mut inbound = receiver(limits(1024, 1024), false);
+        inbound.buffer = client_frame(0x88, &close);
+        match inbound.next_message().unwrap() {
+            Some(Message::Close(Some(reason))) => {
+                assert_eq!(reason.code, CloseCode::Normal);
+                assert_eq!(reason.description.as_deref(), Some("бувай"));
+            },
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reassembles_fragments_and_rejects_bad_sequences() {
+        let mut inbound = receiver(limits(1024, 1024), false);
+        inbound.buffer.extend_from_slice(&client_frame(0x01, b"hel"));
+        inbound.buffer.extend_from_slice(&client_frame(0x89, b"ping"));
+        inbound.buffer.extend_from_slice(&client_frame(0x80, b"lo"));
+        assert!(matches!(inbound.next_message().unwrap(), Some(Message::Ping(_))));
+        match inbound.next_message().unwrap() {
+            Some(Message::Text(text)) => assert_eq!(&text[..], "hello"),
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        let sequences: Vec<(Vec<BytesMut>, CloseCode)> = vec![
+            (vec![client_frame(0x80, b"orphan")], CloseCode::Protocol),
+            (vec![client_frame(0x01, b"a"), client_frame(0x81, b"b")], CloseCode::Protocol),
+            (vec![client_frame(0x01, b"a"), client_frame(0xc0, b"b")], CloseCode::Protocol),
+            (vec![client_frame(0xc1, b"not deflated")], CloseCode::Protocol),
+            (vec![client_frame(0x81, &[0xff, 0xfe])], CloseCode::Invalid),
+            (vec![client_frame(0x83, b"reserved opcode")], CloseCode::Protocol),
+            (vec![client_frame(0x01, &[b'a'; 600]), client_frame(0x80, &[b'a'; 600])], CloseCode::Size),
+        ];
+        for (frames, expected) in sequences {
+            let mut inbound = receiver(limits(1024, 1024), false);
+            for frame in frames {
+                inbound.buffer.extend_from_slice(&frame);
+            }
+            assert_eq!(code(inbound.next_message()), Some(expected));
+        }
+    }
+
+    #[test]
+    fn inflates_within_the_message_limit() {
+        let text = "привіт ".repeat(100);
+        let compressed = deflate(&mut Compress::new(Compression::default(), false), text.as_bytes());
+        let mut inbound = receiver(limits(1024, 4096), true);
+        inbound.buffer = client_frame(0xc1, &compressed);
+        match inbound.next_message().unwrap() {
+            Some(Message::Text(inflated)) => assert_eq!(&inflated[..], text),
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        let bomb = deflate(&mut Compress::new(Compression::best(), false), &vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 2048);
+        let mut inbound = receiver(limits(4096, 64 * 1024), true);
+        inbound.buffer = client_frame(0xc2, &bomb);
+        assert_eq!(code(inbound.next_message()), Some(CloseCode::Size));
+
+        assert_eq!(inflate(&mut Decompress::new(false), &[0xff; 32], 1024).err().map(|violation| violation.code), Some(CloseCode::Invalid));
+    }
+
+    #[test]
+    fn negotiates_permessage_deflate() {
+        let cases = [
+            ("permessage-deflate", Some("permessage-deflate")),
+            ("permessage-deflate; client_max_window_bits", Some("permessage-deflate")),
+            ("permessage-deflate; server_no_context_takeover; client_no_context_takeover",
+                Some("permessage-deflate; server_no_context_takeover; client_no_context_takeover")),
+            ("permessage-deflate; server_max_window_bits=10, permessage-deflate", Some("permessage-deflate")),
+            ("permessage-deflate; server_max_window_bits=10", None),
+            ("permessage-deflate; unknown", None),
+            ("x-webkit-deflate-frame", None),
+        ];
+        for (offer, expected) in cases {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::SEC_WEBSOCKET_EXTENSIONS, offer.parse().unwrap());
+            assert_eq!(Deflate::negotiate(&headers).map(|deflate| deflate.header()).as_deref(), expected, "{}", offer);
+        }
+        assert!(Deflate::negotiate(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn writes_server_frames_unmasked() {
+        for (length, header) in [(5, vec![0x81, 5]), (126, vec![0x81, 126, 0, 126]), (65536, vec![0x81, 127, 0, 0, 0, 0, 0, 1, 0, 0])] {
+            let mut buffer = BytesMut::new();
+            write_frame(&mut buffer, OP_TEXT, false, &vec![b'a'; length]);
+            assert_eq!(&buffer[..header.len()], &header[..]);
+            assert_eq!(buffer.len(), header.len() + length);
+        }
+        let mut buffer = BytesMut::new();
+        write_frame(&mut buffer, OP_BINARY, true, b"x");
+        assert_eq!(buffer[0], 0xc2);
+    }
+}
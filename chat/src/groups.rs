@@ -0,0 +1,119 @@
+use actix_web::web;
+use serde_json::json;
+use crate::models::*;
+use crate::cluster;
+use crate::AppState;
+use crate::history;
+use crate::privacy::{self, Delivery};
+
+pub const PREFIX: &str = "group:";
+pub const MIN_PARTICIPANTS: usize = 3;
+
+pub fn is_group(recipient: &str) -> bool {
+    recipient.starts_with(PREFIX)
+}
+
+pub fn new_id() -> String {
+    format!("{}{}", PREFIX, uuid::Uuid::new_v4())
+}
+
+pub fn participants(app_state: &web::Data<AppState>, group_id: &str, username: &str) -> Result<Vec<String>, String> {
+    let groups = app_state.groups.lock().unwrap();
+    match groups.get(group_id) {
+        Some(group) if group.participants.contains(username) => Ok(group.participants.iter().cloned().collect()),
+        _ => Err("Групову розмову не знайдено".to_string()),
+    }
+}
+
+pub fn push_history(app_state: &web::Data<AppState>, group_id: &str, from: &str, line: String) {
+    if let Some(group) = app_state.groups.lock().unwrap().get_mut(group_id) {
+        let mut entry = HistoryEntry::new(group_id, from, line);
+        entry.seq = history::next_seq(app_state);
+        group.messages.push(entry);
+    }
+}
+
+pub fn history(app_state: &web::Data<AppState>, group_id: &str, username: &str) -> Option<Vec<String>> {
+    let blocked = app_state.privacy.lock().unwrap().get(username).map(|settings| settings.blocked.clone()).unwrap_or_default();
+    let groups = app_state.groups.lock().unwrap();
+    let group = groups.get(group_id).filter(|group| group.participants.contains(username))?;
+    Some(group.messages.iter().filter(|entry| !blocked.contains(&entry.from)).map(|entry| entry.line.clone()).collect())
+}
+
+pub fn broadcast(app_state: &web::Data<AppState>, recipients: &[String], message: &str) {
+    cluster::send_to(app_state, recipients, message);
+}
+
+pub fn audience(app_state: &web::Data<AppState>, recipients: &[String], from: &str) -> Vec<String> {
+    recipients.iter()
+        .filter(|recipient| !matches!(privacy::direct_delivery(app_state, recipient, from), Delivery::Drop))
+        .cloned()
+        .collect()
+}
+
+pub fn deliver(app_state: &web::Data<AppState>, recipients: &[String], from: &str, message: &str) {
+    broadcast(app_state, &audience(app_state, recipients, from), message);
+}
+
+pub fn notify_updated(app_state: &web::Data<AppState>, group: &GroupConversation, removed: Option<&str>) {
+    let event = json!({
+        "type": "group_updated",
+        "id": group.id,
+        "owner": group.owner,
+        "participants": group.participants,
+        "removed": removed
+    }).to_string();
+    history::touch(app_state);
+    let mut recipients: Vec<String> = group.participants.iter().cloned().collect();
+    if let Some(removed) = removed {
+        recipients.push(removed.to_string());
+    }
+    broadcast(app_state, &recipients, &event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[actix_web::test]
+    async fn skips_recipients_who_blocked_the_sender() {
+        let app_state = crate::test_state(Config::from_env());
+        let mut settings = PrivacySettings::default();
+        settings.blocked.insert("mallory".to_string());
+        app_state.privacy.lock().unwrap().insert("alice".to_string(), settings);
+
+        let recipients = vec!["alice".to_string(), "bob".to_string(), "mallory".to_string()];
+        assert_eq!(audience(&app_state, &recipients, "mallory"), vec!["bob".to_string(), "mallory".to_string()]);
+        assert_eq!(audience(&app_state, &recipients, "bob"), recipients);
+    }
+
+    #[actix_web::test]
+    async fn hides_blocked_senders_from_history_and_unread_counts() {
+        let app_state = crate::test_state(Config::from_env());
+        let mut settings = PrivacySettings::default();
+        settings.blocked.insert("mallory".to_string());
+        app_state.privacy.lock().unwrap().insert("alice".to_string(), settings);
+        let group_id = new_id();
+        let participants: Vec<String> = ["alice", "bob", "mallory"].iter().map(|name| name.to_string()).collect();
+        app_state.groups.lock().unwrap().insert(group_id.clone(), GroupConversation {
+            id: group_id.clone(),
+            owner: "bob".to_string(),
+            participants: participants.iter().cloned().collect(),
+            messages: Vec::new(),
+        });
+
+        push_history(&app_state, &group_id, "mallory", "mallory: @alice спам".to_string());
+        push_history(&app_state, &group_id, "bob", "bob: привіт".to_string());
+        assert_eq!(history(&app_state, &group_id, "alice").unwrap(), vec!["bob: привіт".to_string()]);
+        assert_eq!(history(&app_state, &group_id, "bob").unwrap().len(), 2);
+        assert!(history(&app_state, &group_id, "carol").is_none());
+
+        crate::mentions::track(&app_state, "mallory", &participants, &group_id, "@alice @bob спам", &["alice".to_string(), "bob".to_string()]);
+        let unread = app_state.unread.lock().unwrap();
+        assert!(!unread.contains_key("alice"));
+        assert_eq!(unread["bob"][&group_id].unread, 1);
+        assert_eq!(unread["bob"][&group_id].mentions, 1);
+        assert!(!app_state.mentions.lock().unwrap().contains_key("alice"));
+    }
+}
@@ -1,19 +1,107 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use crate::models::*;
 use crate::AppState;
+use crate::threads;
+use crate::groups;
+use crate::privacy;
+use crate::uploads;
+use crate::profiles;
+use crate::accounts;
+use crate::crypto;
+use crate::keys;
+use crate::webhooks;
+use crate::media;
+use crate::history;
+use crate::mentions;
+use crate::cluster;
+use crate::shutdown;
+use crate::sse;
+use crate::transcripts::{self, Scope};
 use actix_web::Error;
+use actix_web::http::header;
 use actix_files::NamedFile;
+use std::collections::BTreeSet;
+use std::sync::atomic::Ordering;
+
+pub fn authenticate(data: &web::Data<AppState>, token: &str) -> Option<String> {
+    let session = data.sessions.lock().unwrap().get(token).cloned();
+    let username = session.or_else(|| data.api_keys.lock().unwrap().get(token).cloned())?;
+    tracing::Span::current().record("username", username.as_str());
+    Some(username)
+}
+
+fn invalid_token(data: &web::Data<AppState>) -> HttpResponse {
+    data.metrics.record_auth_failure("token");
+    tracing::warn!("invalid token");
+    let error = ErrorMessage {
+        msg_type: "error".to_string(),
+        message: "Invalid token".to_string(),
+    };
+    HttpResponse::Unauthorized().json(error)
+}
+
+pub async fn open_event_stream(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    if shutdown::is_shutting_down(&data) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Сервер перезапускається".to_string(),
+        };
+        return HttpResponse::ServiceUnavailable().json(error);
+    }
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(sse::open(&data, &username, &query.token))
+}
+
+pub async fn post_event(data: web::Data<AppState>, path: web::Path<String>, request: web::Json<EventPostRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &request.token) else {
+        return invalid_token(&data);
+    };
+
+    match sse::post(&data, &path, &username, request.message.to_string()) {
+        Ok(()) => HttpResponse::Accepted().json(SignupResponse {
+            msg_type: "success".to_string(),
+            message: "Повідомлення прийнято".to_string(),
+        }),
+        Err(message) => {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message,
+            };
+            HttpResponse::NotFound().json(error)
+        },
+    }
+}
 
 pub async fn signup(data: web::Data<AppState>, new_user: web::Json<User>) -> HttpResponse {
     let mut users = data.users.lock().unwrap();
-    if users.contains_key(&new_user.username) {
+    if users.contains_key(&new_user.username) || accounts::is_reserved(&new_user.username) {
+        tracing::info!(username = %new_user.username, "signup rejected: username taken");
         let error = ErrorMessage {
             msg_type: "error".to_string(),
             message: "Такий користувач вже існує".to_string(),
         };
         return HttpResponse::BadRequest().json(error);
     }
-    users.insert(new_user.username.clone(), new_user.into_inner());
+    if !accounts::is_valid_username(&new_user.username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Некоректне ім'я користувача".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+    let username = new_user.username.clone();
+    users.insert(username.clone(), new_user.into_inner());
+    drop(users);
+    history::open(&data, &username, history::PUBLIC);
+    tracing::info!(%username, "user signed up");
+
     let response = SignupResponse {
         msg_type: "success".to_string(),
         message: "Реєстрація успішна".to_string(),
@@ -24,11 +112,13 @@ pub async fn signup(data: web::Data<AppState>, new_user: web::Json<User>) -> Htt
 pub async fn login(data: web::Data<AppState>, info: web::Json<LoginInfo>) -> HttpResponse {
     let users = data.users.lock().unwrap();
     if let Some(user) = users.get(&info.username) {
-        if user.password == info.password {
+        if !user.is_bot && user.password == info.password {
             let token = uuid::Uuid::new_v4().to_string();
             drop(users);
             let mut sessions = data.sessions.lock().unwrap();
             sessions.insert(token.clone(), info.username.clone());
+            tracing::Span::current().record("username", info.username.as_str());
+            tracing::info!("login succeeded");
             let response = LoginResponse {
                 msg_type: "login".to_string(),
                 token,
@@ -36,6 +126,9 @@ pub async fn login(data: web::Data<AppState>, info: web::Json<LoginInfo>) -> Htt
             return HttpResponse::Ok().json(response);
         }
     }
+    drop(users);
+    data.metrics.record_auth_failure("login");
+    tracing::warn!(username = %info.username, "login failed");
     let error = ErrorMessage {
         msg_type: "error".to_string(),
         message: "Не знайдено користувача з такими обліковими даними".to_string(),
@@ -44,57 +137,870 @@ pub async fn login(data: web::Data<AppState>, info: web::Json<LoginInfo>) -> Htt
 }
 
 pub async fn get_history(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
-    let sessions = data.sessions.lock().unwrap();
-    if let Some(username) = sessions.get(&query.token) {
-        let messages = data.messages.lock().unwrap();
-        if let Some(history) = messages.get(username) {
-            let response = HistoryResponse {
-                msg_type: "history".to_string(),
-                messages: history.clone(),
+    if let Some(username) = authenticate(&data, &query.token) {
+        let response = HistoryResponse {
+            msg_type: "history".to_string(),
+            messages: history::visible(&data, &username),
+        };
+        return HttpResponse::Ok().json(response);
+    }
+    invalid_token(&data)
+}
+
+pub async fn get_online_users(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    if let Some(username) = authenticate(&data, &query.token) {
+        let online = cluster::online(&data);
+        let users: Vec<String> = online.into_iter()
+            .filter(|user| *user == username || !privacy::is_hidden(&data, user))
+            .collect();
+        let response = OnlineUsersResponse {
+            msg_type: "online_users".to_string(),
+            users,
+        };
+        return HttpResponse::Ok().json(response);
+    }
+    invalid_token(&data)
+}
+
+pub async fn download_file(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> Result<HttpResponse, Error> {
+    if authenticate(&data, &query.token).is_none() {
+        return Err(actix_web::error::ErrorUnauthorized("Invalid token"));
+    }
+
+    let Some((file_path, content_type, encrypted)) = uploads::resolve(&data, &path.into_inner()) else {
+        return Err(actix_web::error::ErrorNotFound("File not found"));
+    };
+    let (content_type, disposition) = match media::is_inline(&content_type) {
+        true => (content_type.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM), header::DispositionType::Inline),
+        false => (mime::APPLICATION_OCTET_STREAM, header::DispositionType::Attachment),
+    };
+    let disposition = header::ContentDisposition { disposition, parameters: Vec::new() };
+    if encrypted {
+        let stream = crypto::open_stream(&data.config.encryption, &file_path).await?;
+        return Ok(HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(disposition)
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .streaming(stream));
+    }
+    let mut response = NamedFile::open(file_path)?
+        .set_content_type(content_type)
+        .set_content_disposition(disposition)
+        .into_response(&req);
+    response.headers_mut().insert(header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+pub async fn upload_keys(data: web::Data<AppState>, info: web::Json<KeyUploadRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    match keys::upload(&data, &username, info.into_inner()) {
+        Ok(one_time_prekeys) => {
+            let response = KeyUploadResponse {
+                msg_type: "keys_uploaded".to_string(),
+                one_time_prekeys,
+            };
+            HttpResponse::Ok().json(response)
+        },
+        Err(message) => {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message,
             };
+            HttpResponse::BadRequest().json(error)
+        },
+    }
+}
+
+pub async fn fetch_keys(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let owner = path.into_inner();
+    if !privacy::has_blocked(&data, &owner, &username) {
+        if let Some(response) = keys::fetch(&data, &username, &owner) {
             return HttpResponse::Ok().json(response);
         }
-        let empty_history = HistoryResponse {
-            msg_type: "history".to_string(),
-            messages: Vec::new(),
+    }
+    let error = ErrorMessage {
+        msg_type: "error".to_string(),
+        message: "Ключі користувача не знайдено".to_string(),
+    };
+    HttpResponse::NotFound().json(error)
+}
+
+pub async fn export_history(data: web::Data<AppState>, query: web::Query<ExportQuery>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let query = query.into_inner();
+    let scope = match (query.user, query.room) {
+        (Some(user), None) => Scope::User(user),
+        (None, Some(room)) => Scope::Room(room),
+        (None, None) => Scope::User(username.clone()),
+        (Some(_), Some(_)) => {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message: "Вкажіть або користувача, або кімнату".to_string(),
+            };
+            return HttpResponse::BadRequest().json(error);
+        },
+    };
+    if !transcripts::can_export(&data, &username, &scope) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Недостатньо прав для експорту".to_string(),
         };
-        return HttpResponse::Ok().json(empty_history);
+        return HttpResponse::Forbidden().json(error);
     }
+
+    let export = transcripts::export(&data, &username, &scope);
+    match query.format.as_deref().unwrap_or("json") {
+        "json" => HttpResponse::Ok().json(export),
+        "html" => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(transcripts::render_html(&export)),
+        _ => {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message: "Невідомий формат експорту".to_string(),
+            };
+            HttpResponse::BadRequest().json(error)
+        },
+    }
+}
+
+pub async fn import_history(data: web::Data<AppState>, info: web::Json<ImportRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+    if !data.config.is_admin(&username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Імпорт доступний лише адміністраторам".to_string(),
+        };
+        return HttpResponse::Forbidden().json(error);
+    }
+
+    match transcripts::import(&data, info.into_inner().export) {
+        Ok((imported, skipped)) => {
+            let response = ImportResponse {
+                msg_type: "history_imported".to_string(),
+                imported,
+                skipped,
+            };
+            HttpResponse::Ok().json(response)
+        },
+        Err(message) => {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message,
+            };
+            HttpResponse::BadRequest().json(error)
+        },
+    }
+}
+
+pub async fn get_outbound_stats(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+    if !data.config.is_admin(&username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Доступно лише адміністраторам".to_string(),
+        };
+        return HttpResponse::Forbidden().json(error);
+    }
+
+    let metrics = &data.outbound_metrics;
+    let response = OutboundStatsResponse {
+        msg_type: "outbound_stats".to_string(),
+        queue_capacity: data.config.outbound_queue_capacity,
+        overflow_policy: data.config.outbound_overflow.as_str().to_string(),
+        dropped_oldest: metrics.dropped_oldest.load(Ordering::Relaxed),
+        coalesced_presence: metrics.coalesced_presence.load(Ordering::Relaxed),
+        disconnected: metrics.disconnected.load(Ordering::Relaxed),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn get_metrics(req: HttpRequest, data: web::Data<AppState>) -> HttpResponse {
+    if let Some(expected) = &data.config.metrics_token {
+        let provided = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if provided != Some(expected.as_str()) {
+            return invalid_token(&data);
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(data.metrics.render(&data))
+}
+
+pub async fn create_bot(data: web::Data<AppState>, info: web::Json<BotRequest>) -> HttpResponse {
+    let Some(owner) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    let mut users = data.users.lock().unwrap();
+    if users.contains_key(&info.username) || accounts::is_reserved(&info.username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Такий користувач вже існує".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+    if !accounts::is_valid_username(&info.username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Некоректне ім'я користувача".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+    users.insert(info.username.clone(), User {
+        username: info.username.clone(),
+        password: String::new(),
+        is_bot: true,
+        profile: Profile::default(),
+        owner: Some(owner),
+    });
+    drop(users);
+    history::open(&data, &info.username, history::PUBLIC);
+
+    let api_key = uuid::Uuid::new_v4().simple().to_string();
+    data.api_keys.lock().unwrap().insert(api_key.clone(), info.username.clone());
+
+    let response = BotResponse {
+        msg_type: "bot".to_string(),
+        username: info.username.clone(),
+        api_key,
+    };
+    HttpResponse::Ok().json(response)
+}
+
+fn owns_bot(data: &web::Data<AppState>, owner: &str, bot: &str) -> bool {
+    data.users.lock().unwrap().get(bot)
+        .map(|user| user.is_bot && user.owner.as_deref() == Some(owner))
+        .unwrap_or(false)
+}
+
+fn bot_not_found() -> HttpResponse {
     let error = ErrorMessage {
         msg_type: "error".to_string(),
-        message: "Invalid token".to_string(),
+        message: "Бота не знайдено".to_string(),
     };
-    HttpResponse::Unauthorized().json(error)
+    HttpResponse::NotFound().json(error)
 }
 
-pub async fn get_online_users(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
-    let sessions = data.sessions.lock().unwrap();
-    if let Some(_username) = sessions.get(&query.token) {
-        let connections = data.connections.lock().unwrap();
-        let users: Vec<String> = connections.keys().cloned().collect();
-        let response = OnlineUsersResponse {
-            msg_type: "online_users".to_string(),
-            users,
+pub async fn reissue_bot_key(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    info: web::Json<HistoryRequest>,
+) -> HttpResponse {
+    let Some(owner) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+    let bot = path.into_inner();
+    if !owns_bot(&data, &owner, &bot) {
+        return bot_not_found();
+    }
+
+    let revoked = accounts::revoke_api_keys(&data, &bot);
+    let api_key = uuid::Uuid::new_v4().simple().to_string();
+    data.api_keys.lock().unwrap().insert(api_key.clone(), bot.clone());
+    tracing::info!(bot = %bot, revoked, "bot api key reissued");
+
+    let response = BotResponse {
+        msg_type: "bot".to_string(),
+        username: bot,
+        api_key,
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn revoke_bot_key(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    let Some(owner) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+    let bot = path.into_inner();
+    if !owns_bot(&data, &owner, &bot) {
+        return bot_not_found();
+    }
+
+    let revoked = accounts::revoke_api_keys(&data, &bot);
+    tracing::info!(bot = %bot, revoked, "bot api keys revoked");
+    let response = SignupResponse {
+        msg_type: "success".to_string(),
+        message: "Ключі API бота відкликано".to_string(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn create_webhook(data: web::Data<AppState>, info: web::Json<WebhookRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    if info.room.is_none() && info.mention.is_none() {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Вкажіть кімнату або згадку для підписки".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+    if let Err(message) = webhooks::authorize(&data, &username, info.room.as_deref(), info.mention.as_deref()) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message,
+        };
+        return HttpResponse::Forbidden().json(error);
+    }
+    if let Err(message) = webhooks::resolve(&info.url, data.config.webhook_allow_private).await {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message,
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+
+    let info = info.into_inner();
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        owner: username,
+        url: info.url,
+        room: info.room,
+        mention: info.mention,
+        secret: uuid::Uuid::new_v4().simple().to_string(),
+    };
+    let response = WebhookResponse {
+        msg_type: "webhook".to_string(),
+        id: webhook.id.clone(),
+        secret: webhook.secret.clone(),
+    };
+    data.webhooks.lock().unwrap().insert(webhook.id.clone(), webhook);
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn delete_webhook(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let mut webhooks = data.webhooks.lock().unwrap();
+    let webhook_id = path.into_inner();
+    if webhooks.get(&webhook_id).map(|webhook| webhook.owner == username).unwrap_or(false) {
+        webhooks.remove(&webhook_id);
+        let response = SignupResponse {
+            msg_type: "success".to_string(),
+            message: "Вебхук видалено".to_string(),
         };
         return HttpResponse::Ok().json(response);
     }
     let error = ErrorMessage {
         msg_type: "error".to_string(),
-        message: "Invalid token".to_string(),
+        message: "Вебхук не знайдено".to_string(),
     };
-    HttpResponse::Unauthorized().json(error)
+    HttpResponse::NotFound().json(error)
 }
 
-pub async fn download_file(
+pub async fn get_unread(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let mut conversations = data.unread.lock().unwrap().get(&username).cloned().unwrap_or_default();
+    for (conversation, unread) in history::unread(&data, &username) {
+        conversations.entry(conversation).or_default().unread = unread;
+    }
+    let response = UnreadResponse {
+        msg_type: "unread".to_string(),
+        conversations,
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn mark_read(data: web::Data<AppState>, info: web::Json<MarkReadRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    if let Some(conversations) = data.unread.lock().unwrap().get_mut(&username) {
+        conversations.remove(&info.conversation);
+    }
+    history::mark_read(&data, &username, &info.conversation);
+    mentions::mark_read(&data, &username, &info.conversation);
+    let response = SignupResponse {
+        msg_type: "success".to_string(),
+        message: "Розмову позначено прочитаною".to_string(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn get_mentions(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let mentions = data.mentions.lock().unwrap();
+    let response = MentionsResponse {
+        msg_type: "mentions".to_string(),
+        mentions: mentions.get(&username).cloned().unwrap_or_default(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn get_thread(
     data: web::Data<AppState>,
     path: web::Path<String>,
     query: web::Query<HistoryRequest>,
-) -> Result<NamedFile, Error> {
-    let sessions = data.sessions.lock().unwrap();
-    if sessions.get(&query.token).is_none() {
-        return Err(actix_web::error::ErrorUnauthorized("Invalid token"));
+) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    if let Some((root, replies)) = threads::thread(&data, &path.into_inner(), &username) {
+        let response = ThreadResponse {
+            msg_type: "thread".to_string(),
+            root,
+            replies,
+        };
+        return HttpResponse::Ok().json(response);
+    }
+    let error = ErrorMessage {
+        msg_type: "error".to_string(),
+        message: "Повідомлення не знайдено".to_string(),
+    };
+    HttpResponse::NotFound().json(error)
+}
+
+fn group_not_found() -> HttpResponse {
+    let error = ErrorMessage {
+        msg_type: "error".to_string(),
+        message: "Групову розмову не знайдено".to_string(),
+    };
+    HttpResponse::NotFound().json(error)
+}
+
+pub async fn create_group(data: web::Data<AppState>, info: web::Json<CreateGroupRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    let mut participants: BTreeSet<String> = info.participants.iter().cloned().collect();
+    participants.insert(username.clone());
+    {
+        let users = data.users.lock().unwrap();
+        if let Some(missing) = participants.iter().find(|participant| !users.contains_key(*participant)) {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message: format!("Користувач {} не знайдений", missing),
+            };
+            return HttpResponse::BadRequest().json(error);
+        }
+    }
+    if participants.len() < groups::MIN_PARTICIPANTS {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Групова розмова потребує щонайменше трьох учасників".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+
+    let group = GroupConversation {
+        id: groups::new_id(),
+        owner: username,
+        participants,
+        messages: Vec::new(),
+    };
+    data.groups.lock().unwrap().insert(group.id.clone(), group.clone());
+    groups::notify_updated(&data, &group, None);
+    let response = GroupResponse {
+        msg_type: "group".to_string(),
+        group: GroupSummary::from(&group),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn list_groups(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let groups = data.groups.lock().unwrap();
+    let response = GroupListResponse {
+        msg_type: "groups".to_string(),
+        groups: groups.values()
+            .filter(|group| group.participants.contains(&username))
+            .map(GroupSummary::from)
+            .collect(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn get_group_history(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    match groups::history(&data, &path.into_inner(), &username) {
+        Some(messages) => {
+            let response = HistoryResponse {
+                msg_type: "history".to_string(),
+                messages,
+            };
+            HttpResponse::Ok().json(response)
+        },
+        None => group_not_found(),
+    }
+}
+
+pub async fn add_group_participant(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    info: web::Json<UsernameRequest>,
+) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+    if !data.users.lock().unwrap().contains_key(&info.username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Користувач не знайдений".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
     }
 
-    let file_path = format!("uploads/{}", path.into_inner());
-    Ok(NamedFile::open(file_path)?)
+    let group = {
+        let mut groups = data.groups.lock().unwrap();
+        match groups.get_mut(&path.into_inner()) {
+            Some(group) if group.participants.contains(&username) => {
+                group.participants.insert(info.username.clone());
+                group.clone()
+            },
+            _ => return group_not_found(),
+        }
+    };
+    groups::notify_updated(&data, &group, None);
+    let response = GroupResponse {
+        msg_type: "group".to_string(),
+        group: GroupSummary::from(&group),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn remove_group_participant(
+    data: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let (group_id, removed) = path.into_inner();
+    let group = {
+        let mut groups = data.groups.lock().unwrap();
+        let Some(group) = groups.get_mut(&group_id).filter(|group| group.participants.contains(&username)) else {
+            return group_not_found();
+        };
+        if removed != username && group.owner != username {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message: "Лише власник може видаляти інших учасників".to_string(),
+            };
+            return HttpResponse::Forbidden().json(error);
+        }
+        if !group.participants.remove(&removed) {
+            return group_not_found();
+        }
+        if group.owner == removed {
+            if let Some(next_owner) = group.participants.iter().next() {
+                group.owner = next_owner.clone();
+            }
+        }
+        let group = group.clone();
+        if group.participants.is_empty() {
+            groups.remove(&group_id);
+        }
+        group
+    };
+    groups::notify_updated(&data, &group, Some(&removed));
+    let response = GroupResponse {
+        msg_type: "group".to_string(),
+        group: GroupSummary::from(&group),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+fn privacy_response(data: &web::Data<AppState>, username: &str) -> HttpResponse {
+    let privacy = data.privacy.lock().unwrap();
+    let response = PrivacyResponse {
+        msg_type: "privacy".to_string(),
+        settings: privacy.get(username).cloned().unwrap_or_default(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn get_privacy(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+    privacy_response(&data, &username)
+}
+
+pub async fn update_privacy(data: web::Data<AppState>, info: web::Json<PrivacyUpdate>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    {
+        let mut privacy = data.privacy.lock().unwrap();
+        let settings = privacy.entry(username.clone()).or_default();
+        if let Some(dm_policy) = info.dm_policy {
+            settings.dm_policy = dm_policy;
+        }
+        if let Some(hide_presence) = info.hide_presence {
+            settings.hide_presence = hide_presence;
+        }
+    }
+    privacy_response(&data, &username)
+}
+
+fn update_privacy_list(
+    data: &web::Data<AppState>,
+    token: &str,
+    other: &str,
+    update: impl FnOnce(&mut PrivacySettings, &str),
+) -> HttpResponse {
+    let Some(username) = authenticate(data, token) else {
+        return invalid_token(data);
+    };
+    if other == username || !data.users.lock().unwrap().contains_key(other) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Користувач не знайдений".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+
+    update(data.privacy.lock().unwrap().entry(username.clone()).or_default(), other);
+    privacy_response(data, &username)
+}
+
+pub async fn block_user(data: web::Data<AppState>, info: web::Json<UsernameRequest>) -> HttpResponse {
+    update_privacy_list(&data, &info.token, &info.username, |settings, other| {
+        settings.blocked.insert(other.to_string());
+    })
+}
+
+pub async fn unblock_user(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    update_privacy_list(&data, &query.token, &path.into_inner(), |settings, other| {
+        settings.blocked.remove(other);
+    })
+}
+
+pub async fn add_contact(data: web::Data<AppState>, info: web::Json<UsernameRequest>) -> HttpResponse {
+    update_privacy_list(&data, &info.token, &info.username, |settings, other| {
+        settings.contacts.insert(other.to_string());
+    })
+}
+
+pub async fn remove_contact(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    update_privacy_list(&data, &query.token, &path.into_inner(), |settings, other| {
+        settings.contacts.remove(other);
+    })
+}
+
+pub async fn get_user_profile(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<HistoryRequest>,
+) -> HttpResponse {
+    if authenticate(&data, &query.token).is_none() {
+        return invalid_token(&data);
+    }
+
+    profile_response(&data, &path.into_inner())
+}
+
+fn profile_response(data: &web::Data<AppState>, username: &str) -> HttpResponse {
+    let users = data.users.lock().unwrap();
+    if let Some(user) = users.get(username) {
+        let response = ProfileResponse {
+            msg_type: "profile".to_string(),
+            username: user.username.clone(),
+            is_bot: user.is_bot,
+            profile: user.profile.clone(),
+        };
+        return HttpResponse::Ok().json(response);
+    }
+    let error = ErrorMessage {
+        msg_type: "error".to_string(),
+        message: "Користувач не знайдений".to_string(),
+    };
+    HttpResponse::NotFound().json(error)
+}
+
+pub async fn update_profile(data: web::Data<AppState>, info: web::Json<ProfileUpdate>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+
+    let info = info.into_inner();
+    let result = profiles::update(&data, &username, |profile| {
+        if let Some(display_name) = info.display_name {
+            profile.display_name = profiles::validate(display_name, profiles::MAX_DISPLAY_NAME_LEN, "Ім'я")?;
+        }
+        if let Some(bio) = info.bio {
+            profile.bio = profiles::validate(bio, profiles::MAX_BIO_LEN, "Опис")?;
+        }
+        if let Some(timezone) = info.timezone {
+            profile.timezone = profiles::validate_timezone(timezone)?;
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => profile_response(&data, &username),
+        Err(message) => {
+            let error = ErrorMessage {
+                msg_type: "error".to_string(),
+                message,
+            };
+            HttpResponse::BadRequest().json(error)
+        },
+    }
+}
+
+fn wrong_password() -> HttpResponse {
+    let error = ErrorMessage {
+        msg_type: "error".to_string(),
+        message: "Невірний пароль".to_string(),
+    };
+    HttpResponse::Forbidden().json(error)
+}
+
+pub async fn change_password(data: web::Data<AppState>, info: web::Json<ChangePasswordRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+    if info.new_password.is_empty() {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Пароль не може бути порожнім".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+
+    {
+        let mut users = data.users.lock().unwrap();
+        match users.get_mut(&username) {
+            Some(user) if !user.is_bot && user.password == info.current_password => {
+                user.password = info.new_password.clone();
+            },
+            _ => return wrong_password(),
+        }
+    }
+    history::touch(&data);
+    data.sessions.lock().unwrap().retain(|token, owner| *owner != username || *token == info.token);
+    accounts::disconnect_other_sessions(&data, &username, &info.token, "Пароль змінено, підключіться знову");
+
+    let response = SignupResponse {
+        msg_type: "success".to_string(),
+        message: "Пароль змінено, інші сесії завершено".to_string(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn change_username(data: web::Data<AppState>, info: web::Json<RenameRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+    if !accounts::is_valid_username(&info.new_username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message: "Некоректне ім'я користувача".to_string(),
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+    if let Err(message) = accounts::rename(&data, &username, &info.new_username) {
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message,
+        };
+        return HttpResponse::BadRequest().json(error);
+    }
+    let response = SignupResponse {
+        msg_type: "success".to_string(),
+        message: "Ім'я користувача змінено".to_string(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn delete_account(data: web::Data<AppState>, info: web::Json<DeleteAccountRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &info.token) else {
+        return invalid_token(&data);
+    };
+    let password_matches = data.users.lock().unwrap().get(&username)
+        .map(|user| user.password == info.password)
+        .unwrap_or(false);
+    if !password_matches {
+        return wrong_password();
+    }
+
+    accounts::delete(&data, &username);
+    let response = SignupResponse {
+        msg_type: "success".to_string(),
+        message: "Обліковий запис видалено".to_string(),
+    };
+    HttpResponse::Ok().json(response)
+}
+
+pub async fn get_storage(data: web::Data<AppState>, query: web::Query<HistoryRequest>) -> HttpResponse {
+    let Some(username) = authenticate(&data, &query.token) else {
+        return invalid_token(&data);
+    };
+
+    let response = StorageResponse {
+        msg_type: "storage".to_string(),
+        used: uploads::usage(&data, &username),
+        quota: data.config.user_storage_quota,
+    };
+    HttpResponse::Ok().json(response)
 }
@@ -0,0 +1,493 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use actix::prelude::*;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use crate::models::*;
+use crate::AppState;
+use crate::crypto::{self, Keyring};
+use crate::threads;
+use crate::uploads;
+
+pub const PUBLIC: &str = "public";
+pub const HISTORY_FILE: &str = "history.json";
+
+pub fn is_shared(conversation: &str) -> bool {
+    conversation == PUBLIC || conversation.starts_with('#')
+}
+
+pub fn touch(app_state: &web::Data<AppState>) {
+    app_state.history_dirty.store(true, Ordering::SeqCst);
+}
+
+pub fn next_seq(app_state: &web::Data<AppState>) -> u64 {
+    touch(app_state);
+    app_state.history_seq.fetch_add(1, Ordering::SeqCst)
+}
+
+fn current_seq(app_state: &web::Data<AppState>) -> u64 {
+    app_state.history_seq.load(Ordering::SeqCst)
+}
+
+pub fn push(app_state: &web::Data<AppState>, owner: &str, mut entry: HistoryEntry) {
+    let mut messages = app_state.messages.lock().unwrap();
+    entry.seq = next_seq(app_state);
+    messages.entry(owner.to_string()).or_default().push(entry);
+}
+
+pub fn push_shared(app_state: &web::Data<AppState>, mut entry: HistoryEntry) {
+    let mut logs = app_state.room_logs.lock().unwrap();
+    entry.seq = next_seq(app_state);
+    logs.entry(entry.conversation.clone()).or_default().push(entry);
+}
+
+pub fn open(app_state: &web::Data<AppState>, username: &str, conversation: &str) {
+    touch(app_state);
+    let seq = current_seq(app_state);
+    app_state.read_cursors.lock().unwrap()
+        .entry(username.to_string()).or_default()
+        .entry(conversation.to_string()).or_insert(ReadCursor { visible_from: seq, unread_from: seq });
+}
+
+pub fn mark_read(app_state: &web::Data<AppState>, username: &str, conversation: &str) {
+    touch(app_state);
+    let seq = current_seq(app_state);
+    if let Some(cursor) = app_state.read_cursors.lock().unwrap().get_mut(username).and_then(|cursors| cursors.get_mut(conversation)) {
+        cursor.unread_from = seq;
+    }
+}
+
+fn readable_cursors(app_state: &web::Data<AppState>, username: &str) -> Vec<(String, ReadCursor)> {
+    let cursors = app_state.read_cursors.lock().unwrap().get(username).cloned().unwrap_or_default();
+    let rooms = app_state.rooms.lock().unwrap();
+    cursors.into_iter()
+        .filter(|(conversation, _)| {
+            conversation == PUBLIC || rooms.get(conversation).map(|members| members.contains(username)).unwrap_or(false)
+        })
+        .collect()
+}
+
+fn blocked_by(app_state: &web::Data<AppState>, username: &str) -> BTreeSet<String> {
+    let privacy = app_state.privacy.lock().unwrap();
+    privacy.get(username).map(|settings| settings.blocked.clone()).unwrap_or_default()
+}
+
+pub fn visible(app_state: &web::Data<AppState>, username: &str) -> Vec<String> {
+    let mut entries: Vec<HistoryEntry> = app_state.messages.lock().unwrap().get(username).cloned().unwrap_or_default();
+    let cursors = readable_cursors(app_state, username);
+    let blocked = blocked_by(app_state, username);
+
+    {
+        let logs = app_state.room_logs.lock().unwrap();
+        for (conversation, cursor) in cursors.iter() {
+            let Some(log) = logs.get(conversation) else {
+                continue;
+            };
+            let start = log.partition_point(|entry| entry.seq < cursor.visible_from);
+            entries.extend(log[start..].iter().filter(|entry| !blocked.contains(&entry.from)).cloned());
+        }
+    }
+    entries.sort_by_key(|entry| entry.seq);
+    entries.into_iter().map(|entry| entry.line).collect()
+}
+
+pub fn unread(app_state: &web::Data<AppState>, username: &str) -> HashMap<String, usize> {
+    let cursors = readable_cursors(app_state, username);
+    let blocked = blocked_by(app_state, username);
+
+    let logs = app_state.room_logs.lock().unwrap();
+    let mut unread = HashMap::new();
+    for (conversation, cursor) in cursors {
+        let Some(log) = logs.get(&conversation) else {
+            continue;
+        };
+        let from = cursor.visible_from.max(cursor.unread_from);
+        let start = log.partition_point(|entry| entry.seq < from);
+        let count = log[start..].iter()
+            .filter(|entry| entry.from != username && !blocked.contains(&entry.from))
+            .count();
+        if count > 0 {
+            unread.insert(conversation, count);
+        }
+    }
+    unread
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    seq: u64,
+    conversation: String,
+    from: String,
+    timestamp: u64,
+    line: Option<String>,
+    sealed: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredUser {
+    username: String,
+    password: Option<String>,
+    sealed_password: Option<String>,
+    is_bot: bool,
+    owner: Option<String>,
+    profile: Profile,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredGroup {
+    id: String,
+    owner: String,
+    participants: BTreeSet<String>,
+    messages: Vec<StoredEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIndexEntry {
+    message: StoredMessage,
+    sealed_content: Option<String>,
+    sealed_filename: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StoredHistory {
+    messages: HashMap<String, Vec<StoredEntry>>,
+    room_logs: HashMap<String, Vec<StoredEntry>>,
+    #[serde(default)]
+    read_cursors: HashMap<String, HashMap<String, ReadCursor>>,
+    #[serde(default)]
+    users: Vec<StoredUser>,
+    #[serde(default)]
+    groups: Vec<StoredGroup>,
+    #[serde(default)]
+    message_index: Vec<StoredIndexEntry>,
+}
+
+fn seal_text(keyring: &Keyring, text: &str) -> Result<(Option<String>, Option<String>), String> {
+    match keyring.is_enabled() {
+        true => Ok((None, Some(hex::encode(crypto::encrypt(keyring, text.as_bytes())?)))),
+        false => Ok((Some(text.to_string()), None)),
+    }
+}
+
+fn unseal_text(keyring: &Keyring, plain: Option<String>, sealed: Option<String>) -> Result<String, String> {
+    match (plain, sealed) {
+        (_, Some(sealed)) => {
+            let sealed = hex::decode(sealed).map_err(|_| "sealed text is not valid hex".to_string())?;
+            String::from_utf8(crypto::decrypt(keyring, &sealed)?).map_err(|_| "sealed text is not valid UTF-8".to_string())
+        },
+        (Some(plain), None) => Ok(plain),
+        (None, None) => Err("entry has no text".to_string()),
+    }
+}
+
+fn seal(keyring: &Keyring, entry: &HistoryEntry) -> Result<StoredEntry, String> {
+    let (line, sealed) = seal_text(keyring, &entry.line)?;
+    Ok(StoredEntry {
+        seq: entry.seq,
+        conversation: entry.conversation.clone(),
+        from: entry.from.clone(),
+        timestamp: entry.timestamp,
+        line,
+        sealed,
+    })
+}
+
+fn unseal(keyring: &Keyring, stored: StoredEntry) -> Result<HistoryEntry, String> {
+    let line = unseal_text(keyring, stored.line, stored.sealed)?;
+    Ok(HistoryEntry {
+        seq: stored.seq,
+        conversation: stored.conversation,
+        from: stored.from,
+        line,
+        timestamp: stored.timestamp,
+    })
+}
+
+fn seal_entries(keyring: &Keyring, entries: &[HistoryEntry]) -> Result<Vec<StoredEntry>, String> {
+    entries.iter().map(|entry| seal(keyring, entry)).collect()
+}
+
+fn unseal_entries(keyring: &Keyring, entries: Vec<StoredEntry>) -> Result<Vec<HistoryEntry>, String> {
+    entries.into_iter().map(|entry| unseal(keyring, entry)).collect()
+}
+
+fn seal_user(keyring: &Keyring, user: &User) -> Result<StoredUser, String> {
+    let (password, sealed_password) = seal_text(keyring, &user.password)?;
+    Ok(StoredUser {
+        username: user.username.clone(),
+        password,
+        sealed_password,
+        is_bot: user.is_bot,
+        owner: user.owner.clone(),
+        profile: user.profile.clone(),
+    })
+}
+
+fn unseal_user(keyring: &Keyring, stored: StoredUser) -> Result<User, String> {
+    Ok(User {
+        password: unseal_text(keyring, stored.password, stored.sealed_password)?,
+        username: stored.username,
+        is_bot: stored.is_bot,
+        profile: stored.profile,
+        owner: stored.owner,
+    })
+}
+
+fn seal_message(keyring: &Keyring, message: &StoredMessage) -> Result<StoredIndexEntry, String> {
+    let mut message = message.clone();
+    if !keyring.is_enabled() {
+        return Ok(StoredIndexEntry { message, sealed_content: None, sealed_filename: None });
+    }
+    let (_, sealed_content) = seal_text(keyring, &std::mem::take(&mut message.content))?;
+    let sealed_filename = match message.attachment.as_mut() {
+        Some(attachment) => seal_text(keyring, &std::mem::take(&mut attachment.filename))?.1,
+        None => None,
+    };
+    Ok(StoredIndexEntry { message, sealed_content, sealed_filename })
+}
+
+fn unseal_message(keyring: &Keyring, stored: StoredIndexEntry) -> Result<StoredMessage, String> {
+    let mut message = stored.message;
+    if stored.sealed_content.is_some() {
+        message.content = unseal_text(keyring, None, stored.sealed_content)?;
+    }
+    if let (Some(attachment), Some(sealed)) = (message.attachment.as_mut(), stored.sealed_filename) {
+        attachment.filename = unseal_text(keyring, None, Some(sealed))?;
+    }
+    Ok(message)
+}
+
+fn seal_all(keyring: &Keyring, logs: &HashMap<String, Vec<HistoryEntry>>) -> Result<HashMap<String, Vec<StoredEntry>>, String> {
+    logs.iter()
+        .map(|(key, entries)| Ok((key.clone(), entries.iter().map(|entry| seal(keyring, entry)).collect::<Result<_, _>>()?)))
+        .collect()
+}
+
+fn unseal_all(keyring: &Keyring, logs: HashMap<String, Vec<StoredEntry>>) -> Result<HashMap<String, Vec<HistoryEntry>>, String> {
+    logs.into_iter()
+        .map(|(key, entries)| Ok((key, entries.into_iter().map(|entry| unseal(keyring, entry)).collect::<Result<_, _>>()?)))
+        .collect()
+}
+
+fn load_from(app_state: &web::Data<AppState>, path: &str) -> std::io::Result<usize> {
+    let stored: StoredHistory = match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).map_err(std::io::Error::other)?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error),
+    };
+    let keyring = &app_state.config.encryption;
+    let invalid = std::io::Error::other;
+    let messages = unseal_all(keyring, stored.messages).map_err(invalid)?;
+    let room_logs = unseal_all(keyring, stored.room_logs).map_err(invalid)?;
+    let users = stored.users.into_iter()
+        .map(|user| unseal_user(keyring, user).map(|user| (user.username.clone(), user)))
+        .collect::<Result<HashMap<_, _>, _>>().map_err(invalid)?;
+    let groups = stored.groups.into_iter()
+        .map(|group| Ok((group.id.clone(), GroupConversation {
+            messages: unseal_entries(keyring, group.messages)?,
+            id: group.id,
+            owner: group.owner,
+            participants: group.participants,
+        })))
+        .collect::<Result<HashMap<_, _>, String>>().map_err(invalid)?;
+    let mut message_index = stored.message_index.into_iter()
+        .map(|message| unseal_message(keyring, message))
+        .collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    message_index.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+
+    let entries = messages.values().chain(room_logs.values()).chain(groups.values().map(|group| &group.messages)).flatten();
+    let cursors = stored.read_cursors.values().flat_map(|cursors| cursors.values());
+    let next = entries.clone().map(|entry| entry.seq + 1)
+        .chain(cursors.map(|cursor| cursor.visible_from.max(cursor.unread_from)))
+        .max().unwrap_or(0);
+    let loaded = entries.count();
+    app_state.history_seq.fetch_max(next, Ordering::SeqCst);
+    *app_state.messages.lock().unwrap() = messages;
+    *app_state.room_logs.lock().unwrap() = room_logs;
+    *app_state.read_cursors.lock().unwrap() = stored.read_cursors;
+    *app_state.users.lock().unwrap() = users;
+    *app_state.groups.lock().unwrap() = groups;
+    app_state.message_index.lock().unwrap().clear();
+    app_state.threads.lock().unwrap().clear();
+    for message in message_index {
+        threads::insert(app_state, message);
+    }
+    Ok(loaded)
+}
+
+fn save_to(app_state: &web::Data<AppState>, path: &str) -> std::io::Result<()> {
+    let _io = app_state.history_io.lock().unwrap();
+    app_state.history_dirty.store(false, Ordering::SeqCst);
+    let keyring = &app_state.config.encryption;
+    let sealed = || -> Result<StoredHistory, String> {
+        let messages = app_state.messages.lock().unwrap().clone();
+        let room_logs = app_state.room_logs.lock().unwrap().clone();
+        let users: Vec<User> = app_state.users.lock().unwrap().values().cloned().collect();
+        let groups: Vec<GroupConversation> = app_state.groups.lock().unwrap().values().cloned().collect();
+        let message_index: Vec<StoredMessage> = app_state.message_index.lock().unwrap().values().cloned().collect();
+        Ok(StoredHistory {
+            messages: seal_all(keyring, &messages)?,
+            room_logs: seal_all(keyring, &room_logs)?,
+            read_cursors: app_state.read_cursors.lock().unwrap().clone(),
+            users: users.iter().map(|user| seal_user(keyring, user)).collect::<Result<_, _>>()?,
+            groups: groups.into_iter().map(|group| Ok(StoredGroup {
+                messages: seal_entries(keyring, &group.messages)?,
+                id: group.id,
+                owner: group.owner,
+                participants: group.participants,
+            })).collect::<Result<_, String>>()?,
+            message_index: message_index.iter().map(|message| seal_message(keyring, message)).collect::<Result<_, _>>()?,
+        })
+    };
+    sealed().map_err(std::io::Error::other)
+        .and_then(|stored| serde_json::to_vec(&stored).map_err(std::io::Error::other))
+        .and_then(|data| uploads::write_atomic(path, &data))
+        .inspect_err(|_| touch(app_state))
+}
+
+pub fn load(app_state: &web::Data<AppState>) -> std::io::Result<usize> {
+    load_from(app_state, HISTORY_FILE)
+}
+
+pub fn save(app_state: &web::Data<AppState>) -> std::io::Result<()> {
+    save_to(app_state, HISTORY_FILE)
+}
+
+pub fn flush(app_state: &web::Data<AppState>) -> std::io::Result<bool> {
+    if !app_state.history_dirty.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+    save(app_state).map(|_| true)
+}
+
+pub struct Writer {
+    pub app_state: web::Data<AppState>,
+}
+
+impl Actor for Writer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = self.app_state.config.history_save_interval.max(Duration::from_secs(1));
+        ctx.run_interval(interval, |writer, _| {
+            let app_state = writer.app_state.clone();
+            actix::spawn(async move {
+                match web::block(move || flush(&app_state)).await {
+                    Ok(Ok(saved)) => if saved {
+                        tracing::debug!("history saved");
+                    },
+                    Ok(Err(error)) => tracing::error!(%error, "failed to save history"),
+                    Err(error) => tracing::error!(%error, "history save task failed"),
+                }
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_KEY: &str = "old:0000000000000000000000000000000000000000000000000000000000000001";
+    const NEW_KEY: &str = "new:0000000000000000000000000000000000000000000000000000000000000002";
+
+    #[test]
+    fn sealed_lines_survive_key_rotation() {
+        let old = Keyring::parse(Some(OLD_KEY), &[]).unwrap();
+        let rotated = Keyring::parse(Some(NEW_KEY), &[OLD_KEY.to_string()]).unwrap();
+        let entry = HistoryEntry::new("bob", "alice", "До bob: привіт".to_string());
+
+        let stored = seal(&old, &entry).unwrap();
+        assert!(stored.line.is_none());
+        assert!(!stored.sealed.as_ref().unwrap().contains(&hex::encode("привіт")));
+
+        let reopened = unseal(&rotated, stored).unwrap();
+        assert_eq!(reopened.line, entry.line);
+        assert_eq!(reopened.from, "alice");
+        assert!(unseal(&Keyring::default(), seal(&rotated, &reopened).unwrap()).is_err());
+    }
+
+    fn sealed_state() -> web::Data<AppState> {
+        let mut config = crate::config::Config::from_env();
+        config.encryption = Keyring::parse(Some(OLD_KEY), &[]).unwrap();
+        crate::test_state(config)
+    }
+
+    #[actix_web::test]
+    async fn restores_history_cursors_and_accounts_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("history-{}.json", uuid::Uuid::new_v4())).to_string_lossy().to_string();
+        let before = sealed_state();
+        before.users.lock().unwrap().insert("alice".to_string(), User {
+            username: "alice".to_string(),
+            password: "пароль-аліси".to_string(),
+            is_bot: false,
+            profile: Profile::default(),
+            owner: None,
+        });
+        push_shared(&before, HistoryEntry::new(PUBLIC, "bob", "bob: до реєстрації".to_string()));
+        open(&before, "alice", PUBLIC);
+        push_shared(&before, HistoryEntry::new(PUBLIC, "bob", "bob: загальне".to_string()));
+        push(&before, "alice", HistoryEntry::new("bob", "bob", "Від bob: таємниця".to_string()));
+        let stored = threads::record(&before, "bob", PUBLIC, "загальне", None).unwrap();
+        before.groups.lock().unwrap().insert("group:1".to_string(), GroupConversation {
+            id: "group:1".to_string(),
+            owner: "alice".to_string(),
+            participants: ["alice", "bob", "carol"].iter().map(|name| name.to_string()).collect(),
+            messages: Vec::new(),
+        });
+        crate::groups::push_history(&before, "group:1", "carol", "[group:1] carol: план".to_string());
+        assert!(before.history_dirty.load(Ordering::SeqCst));
+        save_to(&before, &path).unwrap();
+        assert!(!before.history_dirty.load(Ordering::SeqCst));
+
+        let file = std::fs::read_to_string(&path).unwrap();
+        for secret in ["пароль-аліси", "таємниця", "загальне", "план"] {
+            assert!(!file.contains(secret), "{} stored in plain text", secret);
+        }
+
+        let after = sealed_state();
+        assert_eq!(load_from(&after, &path).unwrap(), 4);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(after.users.lock().unwrap()["alice"].password, "пароль-аліси");
+        open(&after, "alice", PUBLIC);
+        assert_eq!(visible(&after, "alice"), vec!["bob: загальне".to_string(), "Від bob: таємниця".to_string()]);
+        assert_eq!(unread(&after, "alice")[PUBLIC], 1);
+        assert_eq!(after.message_index.lock().unwrap()[&stored.id].content, "загальне");
+        assert_eq!(after.groups.lock().unwrap()["group:1"].messages[0].line, "[group:1] carol: план");
+        assert!(next_seq(&after) >= current_seq(&before));
+    }
+
+    #[actix_web::test]
+    async fn shared_logs_respect_the_join_point_and_read_cursor() {
+        let app_state = crate::test_state(crate::config::Config::from_env());
+        push_shared(&app_state, HistoryEntry::new(PUBLIC, "bob", "bob: раніше".to_string()));
+        open(&app_state, "alice", PUBLIC);
+        let cursor = app_state.read_cursors.lock().unwrap()["alice"][PUBLIC];
+        assert_eq!((cursor.visible_from, cursor.unread_from), (1, 1));
+
+        push_shared(&app_state, HistoryEntry::new(PUBLIC, "bob", "bob: перше".to_string()));
+        push_shared(&app_state, HistoryEntry::new(PUBLIC, "alice", "alice: моє".to_string()));
+        push_shared(&app_state, HistoryEntry::new("#dev", "bob", "[#dev] bob: не для alice".to_string()));
+        assert_eq!(app_state.room_logs.lock().unwrap()[PUBLIC].len(), 3);
+        assert_eq!(visible(&app_state, "alice"), vec!["bob: перше".to_string(), "alice: моє".to_string()]);
+        assert_eq!(unread(&app_state, "alice"), HashMap::from([(PUBLIC.to_string(), 1)]));
+
+        open(&app_state, "alice", PUBLIC);
+        assert_eq!(app_state.read_cursors.lock().unwrap()["alice"][PUBLIC].visible_from, 1);
+
+        mark_read(&app_state, "alice", PUBLIC);
+        assert!(unread(&app_state, "alice").is_empty());
+        push_shared(&app_state, HistoryEntry::new(PUBLIC, "bob", "bob: нове".to_string()));
+        assert_eq!(unread(&app_state, "alice"), HashMap::from([(PUBLIC.to_string(), 1)]));
+        assert_eq!(visible(&app_state, "alice").len(), 3);
+
+        app_state.rooms.lock().unwrap().insert("#dev".to_string(), ["alice".to_string()].into());
+        open(&app_state, "alice", "#dev");
+        push_shared(&app_state, HistoryEntry::new("#dev", "bob", "[#dev] bob: привіт alice".to_string()));
+        assert_eq!(visible(&app_state, "alice").last().unwrap(), "[#dev] bob: привіт alice");
+        app_state.rooms.lock().unwrap().clear();
+        assert!(!visible(&app_state, "alice").iter().any(|line| line.starts_with("[#dev]")));
+        assert!(visible(&app_state, "carol").is_empty());
+    }
+}
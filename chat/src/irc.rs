@@ -0,0 +1,518 @@
+use std::collections::HashSet;
+use std::io;
+use std::time::Duration;
+use actix_codec::Decoder;
+use actix_http::ws::{Codec, Frame};
+use actix_web::web::{self, BytesMut};
+use actix_web_actors::ws::WebsocketContext;
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use crate::AppState;
+use crate::cluster;
+use crate::commands;
+use crate::framing;
+use crate::groups;
+use crate::privacy;
+use crate::shutdown;
+use crate::websocket::ChatSession;
+
+const SERVER_NAME: &str = "chat";
+const PUBLIC_CHANNEL: &str = "#public";
+const GROUP_CHANNEL_PREFIX: char = '&';
+const MAX_LINE: usize = 8192;
+const INBOX_CAPACITY: usize = 64;
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Command {
+    name: String,
+    params: Vec<String>,
+}
+
+fn parse(line: &str) -> Option<Command> {
+    let mut line = line;
+    if line.starts_with('@') {
+        line = line.split_once(' ')?.1;
+    }
+    if line.starts_with(':') {
+        line = line.split_once(' ')?.1;
+    }
+    let (head, trailing) = match line.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (line, None),
+    };
+    let mut words = head.split(' ').filter(|word| !word.is_empty());
+    let name = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(str::to_string).collect();
+    params.extend(trailing.map(str::to_string));
+    Some(Command { name, params })
+}
+
+fn clean(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
+
+fn word(text: &str) -> String {
+    let word: String = text.chars().map(|c| if c.is_control() || c.is_whitespace() || c == ',' { '_' } else { c }).collect();
+    match word.strip_prefix(':') {
+        Some(rest) => format!("_{}", rest),
+        None if word.is_empty() => "_".to_string(),
+        None => word,
+    }
+}
+
+fn prefix(username: &str) -> String {
+    let username = word(username).replace(['!', '@'], "_");
+    format!(":{}!{}@{}", username, username, SERVER_NAME)
+}
+
+fn group_channel(group_id: &str) -> String {
+    format!("{}{}", GROUP_CHANNEL_PREFIX, group_id.strip_prefix(groups::PREFIX).unwrap_or(group_id))
+}
+
+fn channel_for(conversation: &str, nick: &str) -> String {
+    if conversation == "public" {
+        PUBLIC_CHANNEL.to_string()
+    } else if conversation.starts_with('#') {
+        word(conversation)
+    } else if groups::is_group(conversation) {
+        word(&group_channel(conversation))
+    } else {
+        nick.to_string()
+    }
+}
+
+async fn read_lines(reader: OwnedReadHalf, lines: mpsc::Sender<String>) {
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        match (&mut reader).take(MAX_LINE as u64 + 1).read_until(b'\n', &mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) if buffer.len() > MAX_LINE => {
+                tracing::warn!("irc line too long, dropping client");
+                return;
+            },
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buffer).trim_end_matches(['\r', '\n']).to_string();
+                if !line.is_empty() && lines.send(line).await.is_err() {
+                    return;
+                }
+            },
+        }
+    }
+}
+
+async fn write_line(writer: &mut OwnedWriteHalf, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await
+}
+
+struct Registration {
+    nick: String,
+    password: Option<String>,
+}
+
+async fn register(lines: &mut mpsc::Receiver<String>, writer: &mut OwnedWriteHalf) -> io::Result<Option<Registration>> {
+    let mut password = None;
+    let mut nick = None;
+    let mut user = false;
+    loop {
+        let Ok(Some(line)) = tokio::time::timeout(REGISTRATION_TIMEOUT, lines.recv()).await else {
+            return Ok(None);
+        };
+        let Some(command) = parse(&line) else {
+            continue;
+        };
+        match (command.name.as_str(), command.params.first()) {
+            ("CAP", Some(sub)) if sub.eq_ignore_ascii_case("LS") => write_line(writer, &format!(":{} CAP * LS :", SERVER_NAME)).await?,
+            ("CAP", _) => {},
+            ("PASS", Some(value)) => password = Some(value.clone()),
+            ("NICK", Some(value)) => nick = Some(value.clone()),
+            ("USER", Some(_)) => user = true,
+            ("PING", token) => write_line(writer, &format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, token.map(String::as_str).unwrap_or(SERVER_NAME))).await?,
+            ("QUIT", _) => return Ok(None),
+            (_, _) => write_line(writer, &format!(":{} 451 * :Спочатку виконайте вхід (PASS, NICK, USER)", SERVER_NAME)).await?,
+        }
+        if let (Some(nick), true) = (&nick, user) {
+            return Ok(Some(Registration { nick: nick.clone(), password }));
+        }
+    }
+}
+
+fn authenticate(app_state: &web::Data<AppState>, registration: &Registration) -> bool {
+    let users = app_state.users.lock().unwrap();
+    matches!(users.get(&registration.nick), Some(user) if !user.is_bot && Some(&user.password) == registration.password.as_ref())
+}
+
+struct Gateway {
+    app_state: web::Data<AppState>,
+    nick: String,
+    writer: OwnedWriteHalf,
+    inbox: Option<mpsc::Sender<String>>,
+    joined: HashSet<String>,
+}
+
+impl Gateway {
+    async fn send(&mut self, line: &str) -> io::Result<()> {
+        write_line(&mut self.writer, line).await
+    }
+
+    async fn reply(&mut self, code: &str, params: &str) -> io::Result<()> {
+        let line = format!(":{} {} {} {}", SERVER_NAME, code, self.nick, params);
+        self.send(&line).await
+    }
+
+    async fn relay(&mut self, from: &str, verb: &str, target: &str, content: &str) -> io::Result<()> {
+        for line in content.lines() {
+            self.send(&format!("{} {} {} :{}", prefix(from), verb, word(target), clean(line))).await?;
+        }
+        Ok(())
+    }
+
+    async fn notice(&mut self, content: &str) -> io::Result<()> {
+        for line in content.lines() {
+            let line = format!(":{} NOTICE {} :{}", SERVER_NAME, self.nick, clean(line));
+            self.send(&line).await?;
+        }
+        Ok(())
+    }
+
+    fn forward(&mut self, recipient: &str, content: &str) {
+        let message = json!({ "type": "message", "recipient": recipient, "content": content }).to_string();
+        if let Some(inbox) = &self.inbox {
+            if inbox.try_send(message).is_err() {
+                tracing::warn!(nick = %self.nick, "irc inbox full, message dropped");
+            }
+        }
+    }
+
+    async fn welcome(&mut self) -> io::Result<()> {
+        let nick = self.nick.clone();
+        self.reply("001", &format!(":Ласкаво просимо до чату, {}", nick)).await?;
+        self.reply("002", &format!(":Ваш сервер {}", SERVER_NAME)).await?;
+        self.reply("004", &format!("{} chat o o", SERVER_NAME)).await?;
+        self.reply("005", "CHANTYPES=#& NICKLEN=64 :підтримується цим сервером").await?;
+        self.reply("422", &format!(":Загальний чат доступний у каналі {}", PUBLIC_CHANNEL)).await
+    }
+
+    fn members(&self, channel: &str) -> Vec<String> {
+        if channel == PUBLIC_CHANNEL {
+            return cluster::online(&self.app_state).into_iter()
+                .filter(|user| *user == self.nick || !privacy::is_hidden(&self.app_state, user))
+                .collect();
+        }
+        if channel.starts_with(GROUP_CHANNEL_PREFIX) {
+            return groups::participants(&self.app_state, &Self::recipient(channel), &self.nick).unwrap_or_default();
+        }
+        self.app_state.rooms.lock().unwrap().get(channel)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    async fn names(&mut self, channel: &str) -> io::Result<()> {
+        let members: Vec<String> = self.members(channel).iter().map(|member| word(member)).collect();
+        let channel = word(channel);
+        self.reply("353", &format!("= {} :{}", channel, members.join(" "))).await?;
+        self.reply("366", &format!("{} :Кінець списку учасників", channel)).await
+    }
+
+    async fn joined_channel(&mut self, channel: &str) -> io::Result<()> {
+        self.joined.insert(channel.to_string());
+        self.send(&format!("{} JOIN {}", prefix(&self.nick), word(channel))).await?;
+        self.names(channel).await
+    }
+
+    async fn parted_channel(&mut self, username: &str, channel: &str) -> io::Result<()> {
+        self.send(&format!("{} PART {}", prefix(username), word(channel))).await
+    }
+
+    async fn join(&mut self, channel: &str) -> io::Result<()> {
+        if channel.eq_ignore_ascii_case(PUBLIC_CHANNEL) {
+            return self.joined_channel(PUBLIC_CHANNEL).await;
+        }
+        if channel.starts_with(GROUP_CHANNEL_PREFIX) {
+            if groups::participants(&self.app_state, &Self::recipient(channel), &self.nick).is_err() {
+                return self.reply("403", &format!("{} :Такого каналу не існує", word(channel))).await;
+            }
+            return self.joined_channel(channel).await;
+        }
+        let Some(room) = commands::room_name(channel).filter(|_| channel.starts_with('#')) else {
+            return self.reply("403", &format!("{} :Такого каналу не існує", word(channel))).await;
+        };
+        let is_member = self.app_state.rooms.lock().unwrap().get(&room).is_some_and(|members| members.contains(&self.nick));
+        if is_member {
+            return self.joined_channel(&room).await;
+        }
+        self.forward("public", &format!("/join {}", room));
+        Ok(())
+    }
+
+    fn recipient(target: &str) -> String {
+        if target.eq_ignore_ascii_case(PUBLIC_CHANNEL) {
+            return "public".to_string();
+        }
+        match target.strip_prefix(GROUP_CHANNEL_PREFIX) {
+            Some(id) => format!("{}{}", groups::PREFIX, id),
+            None => target.to_string(),
+        }
+    }
+
+    async fn on_command(&mut self, command: Command) -> io::Result<bool> {
+        let params = command.params;
+        match command.name.as_str() {
+            "PING" => {
+                let token = params.first().cloned().unwrap_or_else(|| SERVER_NAME.to_string());
+                self.send(&format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, token)).await?;
+            },
+            "PONG" | "CAP" => {},
+            "QUIT" => {
+                self.send("ERROR :До побачення").await?;
+                return Ok(false);
+            },
+            "JOIN" => match params.first() {
+                Some(channels) => {
+                    for channel in channels.split(',').filter(|channel| !channel.is_empty()) {
+                        self.join(channel).await?;
+                    }
+                },
+                None => self.reply("461", "JOIN :Недостатньо параметрів").await?,
+            },
+            "PART" => {
+                for channel in params.first().map(String::as_str).unwrap_or_default().split(',').filter(|channel| !channel.is_empty()) {
+                    if channel.starts_with('#') && !channel.eq_ignore_ascii_case(PUBLIC_CHANNEL) {
+                        self.forward("public", &format!("/leave {}", channel));
+                    } else {
+                        let channel = if channel.eq_ignore_ascii_case(PUBLIC_CHANNEL) { PUBLIC_CHANNEL } else { channel };
+                        if self.joined.remove(channel) {
+                            self.parted_channel(&self.nick.clone(), channel).await?;
+                        }
+                    }
+                }
+            },
+            "NAMES" => {
+                let channels: Vec<String> = match params.first() {
+                    Some(channels) => channels.split(',').map(str::to_string).collect(),
+                    None => self.joined.iter().cloned().collect(),
+                };
+                for channel in channels {
+                    self.names(&channel).await?;
+                }
+            },
+            "PRIVMSG" | "NOTICE" => match (params.first(), params.get(1)) {
+                (Some(target), Some(text)) => {
+                    let content = match text.strip_prefix("\u{1}ACTION ") {
+                        Some(action) => format!("/me {}", action.trim_end_matches('\u{1}')),
+                        None if text.starts_with('/') => format!("/{}", text),
+                        None => text.clone(),
+                    };
+                    for target in target.split(',') {
+                        self.forward(&Self::recipient(target), &content);
+                    }
+                },
+                _ => self.reply("412", ":Немає тексту для надсилання").await?,
+            },
+            "PASS" | "USER" => self.reply("462", ":Ви вже увійшли").await?,
+            name => self.reply("421", &format!("{} :Невідома команда", word(name))).await?,
+        }
+        Ok(true)
+    }
+
+    async fn on_event(&mut self, text: &str) -> io::Result<()> {
+        let Ok(event) = serde_json::from_str::<Value>(text) else {
+            return Ok(());
+        };
+        let field = |name: &str| event[name].as_str().unwrap_or_default().to_string();
+        let nick = self.nick.clone();
+        match event["type"].as_str().unwrap_or_default() {
+            "public" if field("from") != nick && self.joined.contains(PUBLIC_CHANNEL) => {
+                self.relay(&field("from"), "PRIVMSG", PUBLIC_CHANNEL, &field("content")).await?;
+            },
+            "room" if field("from") != nick && self.joined.contains(&field("room")) => {
+                self.relay(&field("from"), "PRIVMSG", &field("room"), &field("content")).await?;
+            },
+            "group" if field("from") != nick => {
+                let channel = group_channel(&field("group"));
+                if !self.joined.contains(&channel) {
+                    self.joined_channel(&channel).await?;
+                }
+                self.relay(&field("from"), "PRIVMSG", &channel, &field("content")).await?;
+            },
+            "private" if field("from") != nick => {
+                self.relay(&field("from"), "PRIVMSG", &nick, &field("content")).await?;
+            },
+            "file" if field("from") != nick => {
+                let target = channel_for(&field("to"), &nick);
+                let notice = format!("[файл] {} ({} байт): /download/{}", clean(&field("filename")), event["size"], word(&field("fileId")));
+                self.relay(&field("from"), "NOTICE", &target, &notice).await?;
+            },
+            "user_connected" if self.joined.contains(PUBLIC_CHANNEL) => {
+                self.send(&format!("{} JOIN {}", prefix(&field("username")), PUBLIC_CHANNEL)).await?;
+            },
+            "user_disconnected" if self.joined.contains(PUBLIC_CHANNEL) => {
+                self.send(&format!("{} QUIT :Вийшов із чату", prefix(&field("username")))).await?;
+            },
+            "user_renamed" => {
+                let (old, new) = (field("old"), field("new"));
+                self.send(&format!("{} NICK :{}", prefix(&old), word(&new))).await?;
+                if old == nick {
+                    self.nick = new;
+                }
+            },
+            "room_joined" if self.joined.contains(&field("room")) => {
+                self.send(&format!("{} JOIN {}", prefix(&field("username")), word(&field("room")))).await?;
+            },
+            "room_left" if self.joined.contains(&field("room")) => {
+                self.parted_channel(&field("username"), &field("room")).await?;
+            },
+            "command_result" if field("command") == "join" => {
+                let room = event["result"]["room"].as_str().unwrap_or_default().to_string();
+                self.joined_channel(&room).await?;
+            },
+            "command_result" if field("command") == "leave" => {
+                let room = event["result"]["room"].as_str().unwrap_or_default().to_string();
+                if self.joined.remove(&room) {
+                    self.parted_channel(&nick, &room).await?;
+                }
+            },
+            "command_result" if matches!(field("command").as_str(), "me" | "msg") => {},
+            "command_result" => self.notice(&format!("/{}: {}", field("command"), event["result"])).await?,
+            "command_error" | "error" | "server_shutdown" => self.notice(&field("message")).await?,
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+async fn serve_client(app_state: web::Data<AppState>, stream: TcpStream) -> io::Result<()> {
+    let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+    let (reader, mut writer) = stream.into_split();
+    if shutdown::is_shutting_down(&app_state) {
+        return write_line(&mut writer, "ERROR :Сервер перезапускається").await;
+    }
+
+    let (sender, mut lines) = mpsc::channel(INBOX_CAPACITY);
+    actix::spawn(read_lines(reader, sender));
+    let Some(registration) = register(&mut lines, &mut writer).await? else {
+        return Ok(());
+    };
+    if !authenticate(&app_state, &registration) {
+        app_state.metrics.record_auth_failure("irc");
+        tracing::warn!(%peer, nick = %registration.nick, "irc login failed");
+        write_line(&mut writer, &format!(":{} 464 {} :Неправильний логін або пароль", SERVER_NAME, registration.nick)).await?;
+        return write_line(&mut writer, "ERROR :Помилка автентифікації").await;
+    }
+    tracing::info!(%peer, nick = %registration.nick, "irc client registered");
+
+    let (inbox, receiver) = mpsc::channel(INBOX_CAPACITY);
+    let session = ChatSession::new(&app_state, &registration.nick, None);
+    let codec = Codec::new().max_size(app_state.config.ws_max_message_bytes);
+    let mut frames = Box::pin(WebsocketContext::with_codec(session, framing::text_frames(receiver), codec));
+    let mut decoder = framing::session_decoder();
+    let mut buffer = BytesMut::new();
+
+    let mut gateway = Gateway {
+        app_state,
+        nick: registration.nick,
+        writer,
+        inbox: Some(inbox),
+        joined: HashSet::new(),
+    };
+    gateway.welcome().await?;
+
+    loop {
+        while let Some(frame) = decoder.decode(&mut buffer).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))? {
+            match frame {
+                Frame::Text(text) => gateway.on_event(&String::from_utf8_lossy(&text)).await?,
+                Frame::Close(reason) => {
+                    let reason = reason.and_then(|reason| reason.description).unwrap_or_else(|| "З'єднання закрито".to_string());
+                    return gateway.send(&format!("ERROR :{}", clean(&reason))).await;
+                },
+                _ => {},
+            }
+        }
+
+        tokio::select! {
+            line = lines.recv(), if gateway.inbox.is_some() => {
+                let keep = match line.as_deref().and_then(parse) {
+                    Some(command) => gateway.on_command(command).await?,
+                    None => line.is_some(),
+                };
+                if !keep {
+                    gateway.inbox = None;
+                }
+            },
+            chunk = frames.next() => match chunk {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                _ => return Ok(()),
+            },
+        }
+    }
+}
+
+pub fn start(app_state: web::Data<AppState>, address: &str) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    tracing::info!(%address, "irc gateway started");
+
+    actix::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let app_state = app_state.clone();
+                    actix::spawn(async move {
+                        if let Err(error) = serve_client(app_state, stream).await {
+                            tracing::debug!(%error, "irc client connection ended");
+                        }
+                    });
+                },
+                Err(error) => tracing::warn!(%error, "irc accept failed"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tags_prefixes_and_trailing_parameters() {
+        let command = parse("@time=now :nick!user@host privmsg #room :hello :world").unwrap();
+        assert_eq!(command.name, "PRIVMSG");
+        assert_eq!(command.params, vec!["#room", "hello :world"]);
+
+        let command = parse("JOIN  #a,#b").unwrap();
+        assert_eq!(command.name, "JOIN");
+        assert_eq!(command.params, vec!["#a,#b"]);
+
+        assert!(parse(":prefix-only").is_none());
+        assert!(parse("").is_none());
+    }
+
+    #[test]
+    fn strips_line_breaks_and_controls_from_interpolated_fields() {
+        assert_eq!(clean("hi\rPRIVMSG #x :pwned\u{1}"), "hi PRIVMSG #x :pwned ");
+        assert_eq!(prefix("eve\r\n:chat 001 x"), ":eve__:chat_001_x!eve__:chat_001_x@chat");
+        assert_eq!(prefix("a!b@c"), ":a_b_c!a_b_c@chat");
+        assert_eq!(word(":fake"), "_fake");
+        assert_eq!(word(""), "_");
+        assert_eq!(word("#a,#b"), "#a_#b");
+    }
+
+    #[test]
+    fn maps_conversations_to_channels() {
+        assert_eq!(channel_for("public", "alice"), PUBLIC_CHANNEL);
+        assert_eq!(channel_for("#rust", "alice"), "#rust");
+        assert_eq!(channel_for("group:1234", "alice"), "&1234");
+        assert_eq!(channel_for("bob", "alice"), "alice");
+        assert_eq!(Gateway::recipient("&1234"), "group:1234");
+        assert_eq!(Gateway::recipient("#PUBLIC"), "public");
+        assert_eq!(Gateway::recipient("bob"), "bob");
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+use actix_web::web;
+use crate::models::*;
+use crate::AppState;
+
+pub const MAX_KEY_LEN: usize = 1024;
+pub const MAX_ONE_TIME_PREKEYS: usize = 100;
+pub const MAX_CIPHERTEXT_LEN: usize = 64 * 1024;
+pub const PREKEY_CLAIM_INTERVAL: Duration = Duration::from_secs(600);
+
+fn is_encoded(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_alphanumeric() || "+/=-_".contains(c))
+}
+
+pub fn validate_key(value: String, label: &str) -> Result<String, String> {
+    let value = value.trim().to_string();
+    if value.is_empty() || value.len() > MAX_KEY_LEN || !is_encoded(&value) {
+        return Err(format!("Некоректний ключ: {}", label));
+    }
+    Ok(value)
+}
+
+pub fn validate_ciphertext(value: Option<String>) -> Result<String, String> {
+    match value {
+        Some(value) if !value.is_empty() && value.len() <= MAX_CIPHERTEXT_LEN && is_encoded(&value) => Ok(value),
+        _ => Err("Некоректний шифротекст".to_string()),
+    }
+}
+
+pub fn upload(app_state: &web::Data<AppState>, username: &str, request: KeyUploadRequest) -> Result<usize, String> {
+    let identity_key = request.identity_key.map(|key| validate_key(key, "identity_key")).transpose()?;
+    let signed_prekey = request.signed_prekey.map(|key| validate_key(key, "signed_prekey")).transpose()?;
+    let prekey_signature = request.prekey_signature.map(|key| validate_key(key, "prekey_signature")).transpose()?;
+    if signed_prekey.is_some() != prekey_signature.is_some() {
+        return Err("Підписаний ключ потрібно надсилати разом з підписом".to_string());
+    }
+    let one_time_prekeys = request.one_time_prekeys.into_iter()
+        .map(|key| validate_key(key, "one_time_prekeys"))
+        .collect::<Result<Vec<String>, String>>()?;
+
+    let mut bundles = app_state.key_bundles.lock().unwrap();
+    if !bundles.contains_key(username) && (identity_key.is_none() || signed_prekey.is_none()) {
+        return Err("Спершу завантажте ключ ідентичності та підписаний ключ".to_string());
+    }
+    let existing = bundles.get(username);
+    let rotated = match (existing, identity_key.as_ref()) {
+        (Some(bundle), Some(key)) => *key != bundle.identity_key,
+        _ => false,
+    };
+    let kept = existing.filter(|_| !rotated).map(|bundle| bundle.one_time_prekeys.len()).unwrap_or(0);
+    if kept + one_time_prekeys.len() > MAX_ONE_TIME_PREKEYS {
+        return Err(format!("Забагато одноразових ключів (максимум {})", MAX_ONE_TIME_PREKEYS));
+    }
+
+    let bundle = bundles.entry(username.to_string()).or_default();
+    if let Some(identity_key) = identity_key {
+        if rotated {
+            bundle.one_time_prekeys.clear();
+        }
+        bundle.identity_key = identity_key;
+    }
+    if let (Some(signed_prekey), Some(prekey_signature)) = (signed_prekey, prekey_signature) {
+        bundle.signed_prekey = signed_prekey;
+        bundle.prekey_signature = prekey_signature;
+    }
+    bundle.one_time_prekeys.extend(one_time_prekeys);
+    Ok(bundle.one_time_prekeys.len())
+}
+
+fn claim_prekey(app_state: &web::Data<AppState>, requester: &str, owner: &str) -> bool {
+    let now = Instant::now();
+    let mut claims = app_state.prekey_claims.lock().unwrap();
+    claims.retain(|_, claimed_at| now.duration_since(*claimed_at) < PREKEY_CLAIM_INTERVAL);
+    let key = (requester.to_string(), owner.to_string());
+    if claims.contains_key(&key) {
+        return false;
+    }
+    claims.insert(key, now);
+    true
+}
+
+pub fn fetch(app_state: &web::Data<AppState>, requester: &str, owner: &str) -> Option<KeyBundleResponse> {
+    if !has_keys(app_state, owner) {
+        return None;
+    }
+    let claimed = requester != owner && claim_prekey(app_state, requester, owner);
+
+    let mut bundles = app_state.key_bundles.lock().unwrap();
+    let bundle = bundles.get_mut(owner)?;
+    Some(KeyBundleResponse {
+        msg_type: "key_bundle".to_string(),
+        username: owner.to_string(),
+        identity_key: bundle.identity_key.clone(),
+        signed_prekey: bundle.signed_prekey.clone(),
+        prekey_signature: bundle.prekey_signature.clone(),
+        one_time_prekey: if claimed { bundle.one_time_prekeys.pop_front() } else { None },
+    })
+}
+
+pub fn has_keys(app_state: &web::Data<AppState>, username: &str) -> bool {
+    app_state.key_bundles.lock().unwrap().contains_key(username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[actix_web::test]
+    async fn repeated_fetches_fall_back_to_the_signed_prekey() {
+        let app_state = crate::test_state(Config::from_env());
+        let request = KeyUploadRequest {
+            token: String::new(),
+            identity_key: Some("identity".to_string()),
+            signed_prekey: Some("signed".to_string()),
+            prekey_signature: Some("signature".to_string()),
+            one_time_prekeys: vec!["first".to_string(), "second".to_string()],
+        };
+        upload(&app_state, "alice", request).unwrap();
+
+        let bundle = fetch(&app_state, "mallory", "alice").unwrap();
+        assert_eq!(bundle.one_time_prekey.as_deref(), Some("first"));
+        let bundle = fetch(&app_state, "mallory", "alice").unwrap();
+        assert_eq!(bundle.signed_prekey, "signed");
+        assert_eq!(bundle.one_time_prekey, None);
+
+        let bundle = fetch(&app_state, "bob", "alice").unwrap();
+        assert_eq!(bundle.one_time_prekey.as_deref(), Some("second"));
+    }
+}
@@ -1,68 +1,300 @@
 mod models;
 mod handlers;
 mod websocket;
+mod webhooks;
+mod commands;
+mod mentions;
+mod threads;
+mod groups;
+mod privacy;
+mod uploads;
+mod profiles;
+mod config;
+mod accounts;
+mod media;
+mod crypto;
+mod keys;
+mod transcripts;
+mod cli;
+mod retention;
+mod history;
+mod outbox;
+mod metrics;
+mod telemetry;
+mod shutdown;
+mod cluster;
+mod sse;
+mod framing;
+mod irc;
 
 use actix_files as fs;
+use actix_web::dev::Service;
 use actix_web::{web, App, HttpServer};
 use models::*;
 use handlers::*;
-use websocket::*;
-use std::collections::HashMap;
-use std::sync::Mutex;
-use actix::Addr;
+use commands::CommandRegistry;
+use cluster::{FanOut, InProcessFanOut, TcpFanOut};
+use config::Config;
+use metrics::Metrics;
+use outbox::{Connection, OutboundMetrics};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use actix::Actor;
 use actix_web_actors::ws;
+use tracing::Instrument;
 use url::Url;
 
 pub struct AppState {
     pub users: Mutex<HashMap<String, User>>,
     pub sessions: Mutex<HashMap<String, String>>,
-    pub connections: Mutex<HashMap<String, Addr<ChatSession>>>,
-    pub messages: Mutex<HashMap<String, Vec<String>>>
+    pub connections: Mutex<HashMap<String, Connection>>,
+    pub remote_presence: Mutex<HashMap<String, String>>,
+    pub event_streams: Mutex<HashMap<String, sse::EventStream>>,
+    pub messages: Mutex<HashMap<String, Vec<HistoryEntry>>>,
+    pub room_logs: Mutex<HashMap<String, Vec<HistoryEntry>>>,
+    pub read_cursors: Mutex<HashMap<String, HashMap<String, ReadCursor>>>,
+    pub history_seq: AtomicU64,
+    pub history_io: Mutex<()>,
+    pub history_dirty: AtomicBool,
+    pub api_keys: Mutex<HashMap<String, String>>,
+    pub webhooks: Mutex<HashMap<String, Webhook>>,
+    pub rooms: Mutex<HashMap<String, HashSet<String>>>,
+    pub away: Mutex<HashMap<String, String>>,
+    pub unread: Mutex<HashMap<String, HashMap<String, UnreadCounter>>>,
+    pub mentions: Mutex<HashMap<String, Vec<Mention>>>,
+    pub message_index: Mutex<HashMap<String, StoredMessage>>,
+    pub threads: Mutex<HashMap<String, Vec<String>>>,
+    pub groups: Mutex<HashMap<String, GroupConversation>>,
+    pub privacy: Mutex<HashMap<String, PrivacySettings>>,
+    pub uploads: Mutex<HashMap<String, UploadRecord>>,
+    pub blobs: Mutex<HashMap<String, BlobRecord>>,
+    pub upload_io: RwLock<()>,
+    pub manifest_io: Mutex<()>,
+    pub manifest_dirty: AtomicBool,
+    pub key_bundles: Mutex<HashMap<String, KeyBundle>>,
+    pub prekey_claims: Mutex<HashMap<(String, String), Instant>>,
+    pub config: Config,
+    pub outbound_metrics: Arc<OutboundMetrics>,
+    pub metrics: Metrics,
+    pub shutting_down: AtomicBool,
+    pub cluster: Box<dyn FanOut>,
+    pub commands: Arc<CommandRegistry>
+}
+
+impl AppState {
+    pub fn new(config: Config, cluster: Box<dyn FanOut>, commands: CommandRegistry) -> Self {
+        AppState {
+            users: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+            remote_presence: Mutex::new(HashMap::new()),
+            event_streams: Mutex::new(HashMap::new()),
+            messages: Mutex::new(HashMap::new()),
+            room_logs: Mutex::new(HashMap::new()),
+            read_cursors: Mutex::new(HashMap::new()),
+            history_seq: AtomicU64::new(0),
+            history_io: Mutex::new(()),
+            history_dirty: AtomicBool::new(false),
+            api_keys: Mutex::new(HashMap::new()),
+            webhooks: Mutex::new(HashMap::new()),
+            rooms: Mutex::new(HashMap::new()),
+            away: Mutex::new(HashMap::new()),
+            unread: Mutex::new(HashMap::new()),
+            mentions: Mutex::new(HashMap::new()),
+            message_index: Mutex::new(HashMap::new()),
+            threads: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new()),
+            privacy: Mutex::new(HashMap::new()),
+            uploads: Mutex::new(HashMap::new()),
+            blobs: Mutex::new(HashMap::new()),
+            upload_io: RwLock::new(()),
+            manifest_io: Mutex::new(()),
+            manifest_dirty: AtomicBool::new(false),
+            key_bundles: Mutex::new(HashMap::new()),
+            prekey_claims: Mutex::new(HashMap::new()),
+            config,
+            outbound_metrics: Arc::new(OutboundMetrics::default()),
+            metrics: Metrics::default(),
+            shutting_down: AtomicBool::new(false),
+            cluster,
+            commands: Arc::new(commands),
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn test_state(config: Config) -> web::Data<AppState> {
+    let cluster = Box::new(InProcessFanOut::new(&config.cluster.node_id, InProcessFanOut::bus()));
+    web::Data::new(AppState::new(config, cluster, CommandRegistry::with_builtins()))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let app_state = web::Data::new(AppState {
-        users: Mutex::new(HashMap::new()),
-        sessions: Mutex::new(HashMap::new()),
-        connections: Mutex::new(HashMap::new()),
-        messages: Mutex::new(HashMap::new()),
-    });
-
-    HttpServer::new(move || {
+    let config = Config::from_env();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(error) = cli::run(&config, args).await {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    telemetry::init(&config);
+    let bind_address = config.bind_address.clone();
+    let cluster: Box<dyn FanOut> = match &config.cluster.listen {
+        Some(listen) => Box::new(TcpFanOut::new(&config.cluster.node_id, listen, &config.cluster.peers, &config.cluster.secret)),
+        None => Box::new(InProcessFanOut::new(&config.cluster.node_id, InProcessFanOut::bus())),
+    };
+    let commands = CommandRegistry::with_builtins();
+    let app_state = web::Data::new(AppState::new(config, cluster, commands));
+
+    let loaded = uploads::load(&app_state).map_err(|error| {
+        tracing::error!(%error, "failed to load upload manifest");
+        error
+    })?;
+    let removed = uploads::collect_garbage(&app_state);
+    let rotated = uploads::rotate_keys(&app_state);
+    tracing::info!(loaded, removed, rotated, "upload directory checked");
+    let entries = history::load(&app_state).and_then(|entries| history::save(&app_state).map(|_| entries)).map_err(|error| {
+        tracing::error!(%error, "failed to load history");
+        error
+    })?;
+    tracing::info!(entries, "history loaded");
+    retention::Janitor { app_state: app_state.clone() }.start();
+    history::Writer { app_state: app_state.clone() }.start();
+    app_state.cluster.start(app_state.clone())?;
+    if let Some(address) = &app_state.config.irc_listen {
+        irc::start(app_state.clone(), address)?;
+    }
+
+    tracing::info!(address = %bind_address, "starting server");
+    let shutdown_state = app_state.clone();
+    let shutdown_timeout = app_state.config.shutdown_timeout;
+
+    let server = HttpServer::new(move || {
+        let request_state = app_state.clone();
         App::new()
             .app_data(app_state.clone())
-            .route("/ws/", web::get().to(websocket_handler))
-            .route("/signup", web::post().to(signup))
-            .route("/login", web::post().to(login))
-            .route("/history", web::get().to(get_history))
-            .route("/online_users", web::get().to(get_online_users))
-            .route("/download/{file_id}", web::get().to(download_file))
-            .service(fs::Files::new("/", "./static").index_file("index.html"))
+            .wrap_fn(move |req, srv| {
+                let app_state = request_state.clone();
+                let started = Instant::now();
+                let route = req.match_pattern();
+                let span = tracing::info_span!(
+                    "http_request",
+                    request_id = %telemetry::new_id(),
+                    method = %req.method(),
+                    route = route.as_deref().unwrap_or(req.path()),
+                    username = tracing::field::Empty,
+                );
+                let response = span.in_scope(|| srv.call(req));
+                async move {
+                    let response = response.await;
+                    let elapsed = started.elapsed();
+                    app_state.metrics.observe_request(route.as_deref(), elapsed);
+                    match &response {
+                        Ok(response) => tracing::info!(status = response.status().as_u16(), elapsed_ms = elapsed.as_millis() as u64, "request finished"),
+                        Err(error) => tracing::warn!(%error, elapsed_ms = elapsed.as_millis() as u64, "request failed"),
+                    }
+                    response
+                }.instrument(span)
+            })
+            .configure(|cfg| routes(cfg, &app_state.config))
     })
-        .bind("127.0.0.1:8080")?
-        .run()
-        .await
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout.as_secs())
+        .bind(bind_address)?
+        .run();
+
+    actix::spawn(shutdown::watch(shutdown_state.clone(), server.handle()));
+    shutdown::serve(&shutdown_state, server).await?;
+    tracing::info!("server stopped");
+    Ok(())
+}
+
+fn routes(cfg: &mut web::ServiceConfig, config: &Config) {
+    cfg
+        .route("/ws/", web::get().to(websocket_handler))
+        .route("/events", web::get().to(open_event_stream))
+        .service(
+            web::resource("/events/{stream_id}")
+                .app_data(web::JsonConfig::default().limit(config.ws_max_message_bytes))
+                .route(web::post().to(post_event))
+        )
+        .route("/signup", web::post().to(signup))
+        .route("/login", web::post().to(login))
+        .route("/history", web::get().to(get_history))
+        .route("/online_users", web::get().to(get_online_users))
+        .route("/download/{file_id}", web::get().to(download_file))
+        .route("/unread", web::get().to(get_unread))
+        .route("/unread/read", web::post().to(mark_read))
+        .route("/mentions", web::get().to(get_mentions))
+        .route("/threads/{message_id}", web::get().to(get_thread))
+        .route("/groups", web::post().to(create_group))
+        .route("/groups", web::get().to(list_groups))
+        .route("/groups/{group_id}/history", web::get().to(get_group_history))
+        .route("/groups/{group_id}/participants", web::post().to(add_group_participant))
+        .route("/groups/{group_id}/participants/{username}", web::delete().to(remove_group_participant))
+        .route("/privacy", web::get().to(get_privacy))
+        .route("/privacy", web::patch().to(update_privacy))
+        .route("/privacy/blocked", web::post().to(block_user))
+        .route("/privacy/blocked/{username}", web::delete().to(unblock_user))
+        .route("/privacy/contacts", web::post().to(add_contact))
+        .route("/privacy/contacts/{username}", web::delete().to(remove_contact))
+        .route("/users/{username}", web::get().to(get_user_profile))
+        .route("/me", web::patch().to(update_profile))
+        .route("/me", web::delete().to(delete_account))
+        .route("/me/password", web::post().to(change_password))
+        .route("/me/storage", web::get().to(get_storage))
+        .route("/me/username", web::post().to(change_username))
+        .route("/keys", web::post().to(upload_keys))
+        .route("/keys/{username}", web::get().to(fetch_keys))
+        .route("/export", web::get().to(export_history))
+        .service(
+            web::resource("/import")
+                .app_data(web::JsonConfig::default().limit(transcripts::MAX_IMPORT_BYTES))
+                .route(web::post().to(import_history))
+        )
+        .route("/admin/outbound", web::get().to(get_outbound_stats))
+        .route("/metrics", web::get().to(get_metrics))
+        .route("/bots", web::post().to(create_bot))
+        .route("/bots/{username}/api_key", web::post().to(reissue_bot_key))
+        .route("/bots/{username}/api_key", web::delete().to(revoke_bot_key))
+        .route("/webhooks", web::post().to(create_webhook))
+        .route("/webhooks/{webhook_id}", web::delete().to(delete_webhook))
+        .service(fs::Files::new("/", "./static").index_file("index.html"));
 }
 
 async fn websocket_handler(req: actix_web::HttpRequest, stream: web::Payload, data: web::Data<AppState>) -> Result<actix_web::HttpResponse, actix_web::Error> {
     use websocket::ChatSession;
-    use actix_web::Error;
+
+    if shutdown::is_shutting_down(&data) {
+        return Ok(actix_web::HttpResponse::ServiceUnavailable().body("Server is shutting down"));
+    }
 
     let query = req.query_string();
-    let url = Url::parse(&format!("http://localhost/?{}", query)).map_err(|_| Error::from(actix_web::error::ErrorBadRequest("Invalid URL")))?;
+    let url = Url::parse(&format!("http://localhost/?{}", query)).map_err(|_| actix_web::error::ErrorBadRequest("Invalid URL"))?;
     let token = url.query_pairs().find(|(k, _)| k == "token").map(|(_, v)| v.to_string());
 
     if let Some(token) = token {
-        let sessions = data.sessions.lock().unwrap();
-        if let Some(username) = sessions.get(&token) {
-            let chat_session = ChatSession {
-                username: username.clone(),
-                app_state: data.clone(),
+        if let Some(username) = authenticate(&data, &token) {
+            let chat_session = ChatSession::new(&data, &username, Some(&token));
+            let deflate = match data.config.ws_compression {
+                true => framing::Deflate::negotiate(req.headers()),
+                false => None,
             };
-            return ws::start(chat_session, &req, stream);
+            let mut response = ws::handshake(&req)?;
+            if let Some(deflate) = deflate {
+                response.insert_header((actix_web::http::header::SEC_WEBSOCKET_EXTENSIONS, deflate.header()));
+            }
+            let limits = framing::Limits::from_config(&data.config);
+            return Ok(response.streaming(framing::serve(chat_session, stream, limits, deflate)));
         }
     }
 
+    data.metrics.record_auth_failure("websocket");
+    tracing::warn!("websocket connection rejected");
     Ok(actix_web::HttpResponse::Unauthorized().body("Unauthorized"))
 }
@@ -0,0 +1,67 @@
+use std::io::Cursor;
+use image::{GenericImageView, ImageFormat, ImageReader, Limits};
+
+pub const THUMBNAIL_SIZE: u32 = 256;
+pub const MAX_IMAGE_DIMENSION: u32 = 8192;
+pub const MAX_DECODE_BYTES: u64 = 64 * 1024 * 1024;
+
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+pub fn sniff(data: &[u8]) -> String {
+    if let Some(kind) = infer::get(data) {
+        return kind.mime_type().to_string();
+    }
+    if std::str::from_utf8(data).is_ok() {
+        return "text/plain".to_string();
+    }
+    "application/octet-stream".to_string()
+}
+
+pub fn is_denied(denied: &[String], content_type: &str) -> bool {
+    denied.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(prefix) => content_type.split('/').next() == Some(prefix),
+        None => pattern == content_type,
+    })
+}
+
+fn image_format(content_type: &str) -> Option<ImageFormat> {
+    match content_type {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+pub fn is_inline(content_type: &str) -> bool {
+    image_format(content_type).is_some()
+}
+
+fn limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    limits
+}
+
+pub fn inspect_image(data: &[u8], content_type: &str) -> Option<ImageInfo> {
+    let format = image_format(content_type)?;
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits());
+    let image = reader.decode().ok()?;
+    let (width, height) = image.dimensions();
+
+    let mut thumbnail = Vec::new();
+    let encoded = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Png);
+    Some(ImageInfo {
+        width,
+        height,
+        thumbnail: encoded.ok().map(|_| thumbnail),
+    })
+}
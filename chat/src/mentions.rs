@@ -0,0 +1,129 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::web;
+use serde_json::json;
+use crate::models::*;
+use crate::websocket::BroadcastMessage;
+use crate::AppState;
+use crate::privacy;
+use crate::history;
+
+pub const HERE: &str = "here";
+pub const MAX_STORED_MENTIONS: usize = 200;
+
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    for (index, _) in content.match_indices('@') {
+        let preceded_by_word = content[..index].chars().next_back().map(|c| c.is_alphanumeric()).unwrap_or(false);
+        if preceded_by_word {
+            continue;
+        }
+        let name: String = content[index + 1..]
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_' || *c == '-')
+            .collect();
+        if !name.is_empty() && !mentions.contains(&name) {
+            mentions.push(name);
+        }
+    }
+    mentions
+}
+
+pub fn is_mentioned(mentions: &[String], username: &str) -> bool {
+    mentions.iter().any(|mention| mention == username)
+}
+
+pub fn audience(app_state: &web::Data<AppState>, mentions: &[String]) -> Vec<String> {
+    let mut audience: Vec<String> = {
+        let users = app_state.users.lock().unwrap();
+        mentions.iter().filter(|mention| users.contains_key(*mention)).cloned().collect()
+    };
+    if is_mentioned(mentions, HERE) {
+        let connections = app_state.connections.lock().unwrap();
+        audience.extend(connections.keys().filter(|user| !is_mentioned(mentions, user)).cloned());
+    }
+    audience
+}
+
+pub fn track(
+    app_state: &web::Data<AppState>,
+    from: &str,
+    audience: &[String],
+    conversation: &str,
+    content: &str,
+    mentions: &[String],
+) {
+    let here = is_mentioned(mentions, HERE);
+    let blocked_by: Vec<&String> = audience.iter().filter(|user| privacy::has_blocked(app_state, user, from)).collect();
+    let connections = app_state.connections.lock().unwrap();
+    let mut unread = app_state.unread.lock().unwrap();
+    let mut stored = app_state.mentions.lock().unwrap();
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let shared = history::is_shared(conversation);
+
+    for user in audience.iter().filter(|user| user.as_str() != from && !blocked_by.contains(user)) {
+        if !shared {
+            unread.entry(user.clone()).or_default().entry(conversation.to_string()).or_default().unread += 1;
+        }
+
+        let online = connections.get(user);
+        let mentioned = is_mentioned(mentions, user) || (here && online.is_some());
+        if !mentioned {
+            continue;
+        }
+        unread.entry(user.clone()).or_default().entry(conversation.to_string()).or_default().mentions += 1;
+
+        let mention = Mention {
+            from: from.to_string(),
+            conversation: conversation.to_string(),
+            content: content.to_string(),
+            timestamp,
+        };
+        if let Some(addr) = online {
+            let notification = json!({
+                "type": "mention",
+                "from": mention.from,
+                "conversation": mention.conversation,
+                "content": mention.content,
+                "timestamp": mention.timestamp
+            }).to_string();
+            addr.do_send(BroadcastMessage(notification));
+        }
+        let user_mentions = stored.entry(user.clone()).or_default();
+        user_mentions.push(mention);
+        if user_mentions.len() > MAX_STORED_MENTIONS {
+            user_mentions.drain(..user_mentions.len() - MAX_STORED_MENTIONS);
+        }
+    }
+}
+
+pub fn mark_read(app_state: &web::Data<AppState>, username: &str, conversation: &str) {
+    if let Some(mentions) = app_state.mentions.lock().unwrap().get_mut(username) {
+        mentions.retain(|mention| mention.conversation != conversation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[actix_web::test]
+    async fn stored_mentions_are_capped_and_pruned_when_read() {
+        let app_state = crate::test_state(Config::from_env());
+        let audience = vec!["bob".to_string()];
+        let mentions = vec!["bob".to_string()];
+        for index in 0..MAX_STORED_MENTIONS + 5 {
+            track(&app_state, "alice", &audience, "#general", &format!("@bob {}", index), &mentions);
+        }
+        track(&app_state, "alice", &audience, "#random", "@bob", &mentions);
+
+        let stored = app_state.mentions.lock().unwrap().get("bob").cloned().unwrap();
+        assert_eq!(stored.len(), MAX_STORED_MENTIONS);
+        assert_eq!(stored[0].content, "@bob 6");
+
+        mark_read(&app_state, "bob", "#general");
+        let stored = app_state.mentions.lock().unwrap().get("bob").cloned().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].conversation, "#random");
+    }
+}
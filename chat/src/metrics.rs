@@ -0,0 +1,232 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use actix_web::web;
+use crate::AppState;
+
+pub const MESSAGE_TYPES: [&str; 9] = [
+    "message",
+    "command",
+    "encrypted",
+    "file",
+    "avatar",
+    "reaction_add",
+    "reaction_remove",
+    "unknown",
+    "invalid",
+];
+
+pub const AUTH_FAILURES: [&str; 4] = ["login", "token", "websocket", "irc"];
+
+pub const ROUTES: [&str; 36] = [
+    "/ws/",
+    "/events",
+    "/events/{stream_id}",
+    "/signup",
+    "/login",
+    "/history",
+    "/online_users",
+    "/download/{file_id}",
+    "/unread",
+    "/unread/read",
+    "/mentions",
+    "/threads/{message_id}",
+    "/groups",
+    "/groups/{group_id}/history",
+    "/groups/{group_id}/participants",
+    "/groups/{group_id}/participants/{username}",
+    "/privacy",
+    "/privacy/blocked",
+    "/privacy/blocked/{username}",
+    "/privacy/contacts",
+    "/privacy/contacts/{username}",
+    "/users/{username}",
+    "/me",
+    "/me/password",
+    "/me/storage",
+    "/me/username",
+    "/keys",
+    "/keys/{username}",
+    "/export",
+    "/import",
+    "/admin/outbound",
+    "/metrics",
+    "/bots",
+    "/bots/{username}/api_key",
+    "/webhooks",
+    "/webhooks/{webhook_id}",
+];
+
+const OTHER_ROUTE: &str = "other";
+
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, route: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{route=\"{}\",le=\"{}\"}} {}", name, route, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{route=\"{}\",le=\"+Inf\"}} {}", name, route, count);
+        let _ = writeln!(out, "{}_sum{{route=\"{}\"}} {}", name, route, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count{{route=\"{}\"}} {}", name, route, count);
+    }
+}
+
+pub struct Metrics {
+    connected_sessions: AtomicI64,
+    messages: [AtomicU64; MESSAGE_TYPES.len()],
+    uploads: AtomicU64,
+    upload_bytes: AtomicU64,
+    auth_failures: [AtomicU64; AUTH_FAILURES.len()],
+    latency: Vec<Histogram>,
+}
+
+fn slot(labels: &[&str], value: &str) -> Option<usize> {
+    labels.iter().position(|label| *label == value)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            connected_sessions: AtomicI64::new(0),
+            messages: Default::default(),
+            uploads: AtomicU64::new(0),
+            upload_bytes: AtomicU64::new(0),
+            auth_failures: Default::default(),
+            latency: (0..=ROUTES.len()).map(|_| Histogram::default()).collect(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn session_opened(&self) {
+        self.connected_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_closed(&self) {
+        self.connected_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_message(&self, msg_type: &str) {
+        let index = slot(&MESSAGE_TYPES, msg_type).or_else(|| slot(&MESSAGE_TYPES, "unknown")).unwrap_or_default();
+        self.messages[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self, size: usize) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        self.upload_bytes.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_auth_failure(&self, reason: &str) {
+        if let Some(index) = slot(&AUTH_FAILURES, reason) {
+            self.auth_failures[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn observe_request(&self, route: Option<&str>, elapsed: Duration) {
+        let index = route.and_then(|route| slot(&ROUTES, route)).unwrap_or(ROUTES.len());
+        self.latency[index].observe(elapsed);
+    }
+
+    pub fn render(&self, app_state: &web::Data<AppState>) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "chat_connected_sessions", "gauge", "Open client sessions over WebSocket, SSE and IRC.");
+        let _ = writeln!(out, "chat_connected_sessions {}", self.connected_sessions.load(Ordering::Relaxed).max(0));
+
+        header(&mut out, "chat_registered_users", "gauge", "Registered accounts, including bots.");
+        let _ = writeln!(out, "chat_registered_users {}", app_state.users.lock().unwrap().len());
+
+        header(&mut out, "chat_messages_total", "counter", "Client messages received, by type.");
+        for (msg_type, counter) in MESSAGE_TYPES.iter().zip(self.messages.iter()) {
+            let _ = writeln!(out, "chat_messages_total{{type=\"{}\"}} {}", msg_type, counter.load(Ordering::Relaxed));
+        }
+
+        header(&mut out, "chat_uploads_total", "counter", "Files and avatars stored.");
+        let _ = writeln!(out, "chat_uploads_total {}", self.uploads.load(Ordering::Relaxed));
+
+        header(&mut out, "chat_upload_bytes_total", "counter", "Bytes received in stored uploads.");
+        let _ = writeln!(out, "chat_upload_bytes_total {}", self.upload_bytes.load(Ordering::Relaxed));
+
+        header(&mut out, "chat_auth_failures_total", "counter", "Rejected logins and tokens, by where they were rejected.");
+        for (reason, counter) in AUTH_FAILURES.iter().zip(self.auth_failures.iter()) {
+            let _ = writeln!(out, "chat_auth_failures_total{{reason=\"{}\"}} {}", reason, counter.load(Ordering::Relaxed));
+        }
+
+        let outbound = &app_state.outbound_metrics;
+        header(&mut out, "chat_outbound_overflow_total", "counter", "Outbound queue overflows, by the action taken.");
+        for (action, counter) in [
+            ("dropped_oldest", &outbound.dropped_oldest),
+            ("coalesced_presence", &outbound.coalesced_presence),
+            ("disconnected", &outbound.disconnected),
+        ] {
+            let _ = writeln!(out, "chat_outbound_overflow_total{{action=\"{}\"}} {}", action, counter.load(Ordering::Relaxed));
+        }
+
+        let name = "chat_http_request_duration_seconds";
+        header(&mut out, name, "histogram", "HTTP handler latency, by route.");
+        for (route, histogram) in ROUTES.iter().chain([OTHER_ROUTE].iter()).zip(self.latency.iter()) {
+            histogram.render(&mut out, name, route);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use actix_web::dev::Service;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use super::*;
+    use crate::config::Config;
+
+    #[actix_web::test]
+    async fn labels_match_the_patterns_the_app_routes_to() {
+        let app_state = crate::test_state(Config::from_env());
+        let matched: Arc<Mutex<Option<String>>> = Arc::default();
+        let recorder = matched.clone();
+        let config = app_state.config.clone();
+        let app = init_service(App::new()
+            .app_data(app_state.clone())
+            .wrap_fn(move |req, srv| {
+                *recorder.lock().unwrap() = req.match_pattern();
+                srv.call(req)
+            })
+            .configure(|cfg| crate::routes(cfg, &config))).await;
+
+        for route in ROUTES {
+            let path = route.replace(['{', '}'], "");
+            call_service(&app, TestRequest::get().uri(&path).to_request()).await;
+            assert_eq!(matched.lock().unwrap().as_deref(), Some(route), "{}", path);
+        }
+
+        call_service(&app, TestRequest::get().uri("/no/such/route").to_request()).await;
+        let unknown = matched.lock().unwrap().clone();
+        assert!(unknown.as_deref().and_then(|route| slot(&ROUTES, route)).is_none());
+    }
+}
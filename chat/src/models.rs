@@ -1,65 +1,487 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     pub username: String,
-    pub password: String
+    pub password: String,
+    #[serde(skip_deserializing)]
+    pub is_bot: bool,
+    #[serde(skip_deserializing)]
+    pub profile: Profile,
+    #[serde(skip)]
+    pub owner: Option<String>
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: Option<String>,
+    pub avatar: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct LoginInfo {
     pub username: String,
     pub password: String
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ClientMessage {
     #[serde(rename = "type")]
     pub msg_type: String,
+    #[serde(default)]
     pub recipient: String,
     pub content: Option<String>,
     pub filename: Option<String>,
     pub content_type: Option<String>,
     pub size: Option<usize>,
-    pub data: Option<Vec<u8>>
+    pub data: Option<Vec<u8>>,
+    pub reply_to: Option<String>,
+    pub message_id: Option<String>,
+    pub emoji: Option<String>,
+    pub ciphertext: Option<String>
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorMessage {
     #[serde(rename = "type")]
     pub msg_type: String,
     pub message: String
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct OnlineUsersResponse {
     #[serde(rename = "type")]
     pub msg_type: String,
     pub users: Vec<String>
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct LoginResponse {
     #[serde(rename = "type")]
     pub msg_type: String,
     pub token: String
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SignupResponse {
     #[serde(rename = "type")]
     pub msg_type: String,
     pub message: String
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct HistoryRequest {
     pub token: String
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct HistoryResponse {
     #[serde(rename = "type")]
     pub msg_type: String,
     pub messages: Vec<String>
 }
+
+#[derive(Deserialize)]
+pub struct BotRequest {
+    pub token: String,
+    pub username: String
+}
+
+#[derive(Serialize)]
+pub struct BotResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub username: String,
+    pub api_key: String
+}
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub owner: String,
+    pub url: String,
+    pub room: Option<String>,
+    pub mention: Option<String>,
+    pub secret: String
+}
+
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    pub token: String,
+    pub url: String,
+    pub room: Option<String>,
+    pub mention: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct WebhookResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub id: String,
+    pub secret: String
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Mention {
+    pub from: String,
+    pub conversation: String,
+    pub content: String,
+    pub timestamp: u64
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct UnreadCounter {
+    pub unread: usize,
+    pub mentions: usize
+}
+
+#[derive(Serialize)]
+pub struct UnreadResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub conversations: HashMap<String, UnreadCounter>
+}
+
+#[derive(Deserialize)]
+pub struct MarkReadRequest {
+    pub token: String,
+    pub conversation: String
+}
+
+#[derive(Serialize)]
+pub struct MentionsResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub mentions: Vec<Mention>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub file_id: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: usize,
+    pub width: Option<u32>,
+    pub height: Option<u32>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: String,
+    pub from: String,
+    pub recipient: String,
+    pub content: String,
+    pub reply_to: Option<String>,
+    pub thread_id: Option<String>,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub reactions: BTreeMap<String, BTreeSet<String>>,
+    #[serde(default)]
+    pub attachment: Option<Attachment>
+}
+
+impl StoredMessage {
+    pub fn reaction_counts(&self) -> BTreeMap<String, usize> {
+        self.reactions.iter().map(|(emoji, users)| (emoji.clone(), users.len())).collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct MessageView {
+    pub id: String,
+    pub from: String,
+    pub recipient: String,
+    pub content: String,
+    pub reply_to: Option<String>,
+    pub thread_id: Option<String>,
+    pub timestamp: u64,
+    pub reactions: BTreeMap<String, usize>,
+    pub attachment: Option<Attachment>
+}
+
+impl From<&StoredMessage> for MessageView {
+    fn from(message: &StoredMessage) -> Self {
+        MessageView {
+            id: message.id.clone(),
+            from: message.from.clone(),
+            recipient: message.recipient.clone(),
+            content: message.content.clone(),
+            reply_to: message.reply_to.clone(),
+            thread_id: message.thread_id.clone(),
+            timestamp: message.timestamp,
+            reactions: message.reaction_counts(),
+            attachment: message.attachment.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ThreadResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub root: MessageView,
+    pub replies: Vec<MessageView>
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub conversation: String,
+    pub from: String,
+    pub line: String,
+    pub timestamp: u64
+}
+
+impl HistoryEntry {
+    pub fn new(conversation: &str, from: &str, line: String) -> Self {
+        HistoryEntry {
+            seq: 0,
+            conversation: conversation.to_string(),
+            from: from.to_string(),
+            line,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ReadCursor {
+    pub visible_from: u64,
+    pub unread_from: u64
+}
+
+#[derive(Debug, Clone)]
+pub struct GroupConversation {
+    pub id: String,
+    pub owner: String,
+    pub participants: BTreeSet<String>,
+    pub messages: Vec<HistoryEntry>
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroupRequest {
+    pub token: String,
+    pub participants: Vec<String>
+}
+
+#[derive(Deserialize)]
+pub struct UsernameRequest {
+    pub token: String,
+    pub username: String
+}
+
+#[derive(Serialize)]
+pub struct GroupSummary {
+    pub id: String,
+    pub owner: String,
+    pub participants: Vec<String>
+}
+
+impl From<&GroupConversation> for GroupSummary {
+    fn from(group: &GroupConversation) -> Self {
+        GroupSummary {
+            id: group.id.clone(),
+            owner: group.owner.clone(),
+            participants: group.participants.iter().cloned().collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GroupResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub group: GroupSummary
+}
+
+#[derive(Serialize)]
+pub struct GroupListResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub groups: Vec<GroupSummary>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DmPolicy {
+    #[default]
+    Everyone,
+    Contacts
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct PrivacySettings {
+    pub dm_policy: DmPolicy,
+    pub hide_presence: bool,
+    pub blocked: BTreeSet<String>,
+    pub contacts: BTreeSet<String>
+}
+
+#[derive(Deserialize)]
+pub struct PrivacyUpdate {
+    pub token: String,
+    pub dm_policy: Option<DmPolicy>,
+    pub hide_presence: Option<bool>
+}
+
+#[derive(Serialize)]
+pub struct PrivacyResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub settings: PrivacySettings
+}
+
+#[derive(Deserialize)]
+pub struct ProfileUpdate {
+    pub token: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct ProfileResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub username: String,
+    pub is_bot: bool,
+    pub profile: Profile
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadRecord {
+    pub owner: String,
+    pub hash: String,
+    pub size: usize,
+    pub content_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub thumbnail: Option<String>,
+    pub uploaded_at: u64,
+    #[serde(default)]
+    pub expires_at: Option<u64>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobRecord {
+    pub size: usize,
+    pub references: usize,
+    pub key_id: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct StorageResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub used: usize,
+    pub quota: usize
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub token: String,
+    pub current_password: String,
+    pub new_password: String
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    pub token: String,
+    pub new_username: String
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    pub token: String,
+    pub password: String
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyBundle {
+    pub identity_key: String,
+    pub signed_prekey: String,
+    pub prekey_signature: String,
+    pub one_time_prekeys: VecDeque<String>
+}
+
+#[derive(Deserialize)]
+pub struct KeyUploadRequest {
+    pub token: String,
+    pub identity_key: Option<String>,
+    pub signed_prekey: Option<String>,
+    pub prekey_signature: Option<String>,
+    #[serde(default)]
+    pub one_time_prekeys: Vec<String>
+}
+
+#[derive(Serialize)]
+pub struct KeyUploadResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub one_time_prekeys: usize
+}
+
+#[derive(Serialize)]
+pub struct KeyBundleResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub username: String,
+    pub identity_key: String,
+    pub signed_prekey: String,
+    pub prekey_signature: String,
+    pub one_time_prekey: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub token: String,
+    pub user: Option<String>,
+    pub room: Option<String>,
+    pub format: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryExport {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub version: u32,
+    pub scope: String,
+    pub name: String,
+    pub exported_at: u64,
+    pub messages: Vec<StoredMessage>
+}
+
+#[derive(Deserialize)]
+pub struct ImportRequest {
+    pub token: String,
+    pub export: HistoryExport
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub imported: usize,
+    pub skipped: usize
+}
+
+#[derive(Serialize)]
+pub struct OutboundStatsResponse {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub queue_capacity: usize,
+    pub overflow_policy: String,
+    pub dropped_oldest: u64,
+    pub coalesced_presence: u64,
+    pub disconnected: u64
+}
+
+#[derive(Deserialize)]
+pub struct EventPostRequest {
+    pub token: String,
+    pub message: serde_json::Value,
+}
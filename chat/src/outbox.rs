@@ -0,0 +1,263 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use actix::Addr;
+use serde_json::json;
+use crate::config::OverflowPolicy;
+use crate::websocket::*;
+
+#[derive(Debug, Default)]
+pub struct OutboundMetrics {
+    pub dropped_oldest: AtomicU64,
+    pub coalesced_presence: AtomicU64,
+    pub disconnected: AtomicU64,
+}
+
+pub enum Outbound {
+    Message(String),
+    Presence { username: String, message: String },
+}
+
+impl Outbound {
+    fn into_text(self) -> String {
+        match self {
+            Outbound::Message(message) | Outbound::Presence { message, .. } => message,
+        }
+    }
+
+    fn presence_of(&self) -> Option<&str> {
+        match self {
+            Outbound::Presence { username, .. } => Some(username),
+            Outbound::Message(_) => None,
+        }
+    }
+}
+
+enum Pushed {
+    Wake,
+    Queued,
+    Overflowed,
+}
+
+#[derive(Default)]
+struct Queue {
+    items: VecDeque<Outbound>,
+    scheduled: bool,
+    closed: bool,
+}
+
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: Arc<OutboundMetrics>,
+}
+
+fn coalesce_presence(items: &mut VecDeque<Outbound>, incoming: &Outbound) -> usize {
+    let mut seen: HashSet<String> = incoming.presence_of().map(|username| username.to_string()).into_iter().collect();
+    let before = items.len();
+    let mut kept = VecDeque::with_capacity(items.len());
+    while let Some(item) = items.pop_back() {
+        let superseded = item.presence_of().is_some_and(|username| !seen.insert(username.to_string()));
+        if !superseded {
+            kept.push_front(item);
+        }
+    }
+    *items = kept;
+    before - items.len()
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy, metrics: Arc<OutboundMetrics>) -> Self {
+        Outbox {
+            queue: Mutex::new(Queue::default()),
+            capacity,
+            policy,
+            metrics,
+        }
+    }
+
+    fn push(&self, item: Outbound) -> Pushed {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return Pushed::Queued;
+        }
+
+        if queue.items.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    queue.items.pop_front();
+                    self.metrics.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                },
+                OverflowPolicy::CoalescePresence => {
+                    let coalesced = coalesce_presence(&mut queue.items, &item);
+                    self.metrics.coalesced_presence.fetch_add(coalesced as u64, Ordering::Relaxed);
+                },
+                OverflowPolicy::Disconnect => {},
+            }
+        }
+        // CoalescePresence falls back to disconnecting when the queue holds nothing to coalesce.
+        if queue.items.len() >= self.capacity {
+            queue.closed = true;
+            queue.items.clear();
+            self.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+            return Pushed::Overflowed;
+        }
+
+        queue.items.push_back(item);
+        if queue.scheduled {
+            return Pushed::Queued;
+        }
+        queue.scheduled = true;
+        Pushed::Wake
+    }
+
+    pub fn drain(&self) -> Vec<String> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.scheduled = false;
+        queue.items.drain(..).map(Outbound::into_text).collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Connection {
+    addr: Addr<ChatSession>,
+    outbox: Arc<Outbox>,
+    token: Option<String>,
+}
+
+impl Connection {
+    pub fn new(addr: Addr<ChatSession>, outbox: Arc<Outbox>, token: Option<String>) -> Self {
+        Connection { addr, outbox, token }
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn do_send<M: Deliver>(&self, msg: M) {
+        msg.deliver(self);
+    }
+
+    fn enqueue(&self, item: Outbound) {
+        match self.outbox.push(item) {
+            Pushed::Wake => self.addr.do_send(Flush),
+            Pushed::Queued => {},
+            Pushed::Overflowed => self.addr.do_send(Disconnect {
+                reason: "Клієнт не встигає отримувати повідомлення".to_string(),
+            }),
+        }
+    }
+}
+
+pub trait Deliver {
+    fn deliver(self, connection: &Connection);
+}
+
+impl Deliver for BroadcastMessage {
+    fn deliver(self, connection: &Connection) {
+        connection.enqueue(Outbound::Message(self.0));
+    }
+}
+
+impl Deliver for PrivateMessage {
+    fn deliver(self, connection: &Connection) {
+        connection.enqueue(Outbound::Message(self.content));
+    }
+}
+
+impl Deliver for UserConnected {
+    fn deliver(self, connection: &Connection) {
+        let message = json!({
+            "type": "user_connected",
+            "username": self.username
+        }).to_string();
+        connection.enqueue(Outbound::Presence { username: self.username, message });
+    }
+}
+
+impl Deliver for UserDisconnected {
+    fn deliver(self, connection: &Connection) {
+        let message = json!({
+            "type": "user_disconnected",
+            "username": self.username
+        }).to_string();
+        connection.enqueue(Outbound::Presence { username: self.username, message });
+    }
+}
+
+impl Deliver for Renamed {
+    fn deliver(self, connection: &Connection) {
+        connection.addr.do_send(self);
+    }
+}
+
+impl Deliver for Disconnect {
+    fn deliver(self, connection: &Connection) {
+        connection.addr.do_send(self);
+    }
+}
+
+impl Deliver for Shutdown {
+    fn deliver(self, connection: &Connection) {
+        connection.addr.do_send(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> Outbound {
+        Outbound::Message(text.to_string())
+    }
+
+    fn presence(username: &str, text: &str) -> Outbound {
+        Outbound::Presence { username: username.to_string(), message: text.to_string() }
+    }
+
+    fn outbox(policy: OverflowPolicy) -> Outbox {
+        Outbox::new(2, policy, Arc::new(OutboundMetrics::default()))
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_messages() {
+        let outbox = outbox(OverflowPolicy::DropOldest);
+        assert!(matches!(outbox.push(message("one")), Pushed::Wake));
+        assert!(matches!(outbox.push(message("two")), Pushed::Queued));
+        assert!(matches!(outbox.push(message("three")), Pushed::Queued));
+        assert_eq!(outbox.drain(), vec!["two", "three"]);
+        assert_eq!(outbox.metrics.dropped_oldest.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn coalesce_presence_keeps_the_latest_status_per_user() {
+        let outbox = outbox(OverflowPolicy::CoalescePresence);
+        outbox.push(presence("alice", "alice online"));
+        outbox.push(message("hello"));
+        assert!(matches!(outbox.push(presence("alice", "alice offline")), Pushed::Queued));
+        assert_eq!(outbox.drain(), vec!["hello", "alice offline"]);
+        assert_eq!(outbox.metrics.coalesced_presence.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn coalesce_presence_disconnects_when_nothing_can_be_coalesced() {
+        let outbox = outbox(OverflowPolicy::CoalescePresence);
+        outbox.push(message("one"));
+        outbox.push(presence("alice", "alice online"));
+        assert!(matches!(outbox.push(message("two")), Pushed::Overflowed));
+        assert!(outbox.drain().is_empty());
+        assert_eq!(outbox.metrics.disconnected.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn disconnect_closes_the_queue_on_overflow() {
+        let outbox = outbox(OverflowPolicy::Disconnect);
+        outbox.push(message("one"));
+        outbox.push(message("two"));
+        assert!(matches!(outbox.push(message("three")), Pushed::Overflowed));
+        assert!(matches!(outbox.push(message("four")), Pushed::Queued));
+        assert!(outbox.drain().is_empty());
+        assert_eq!(outbox.metrics.disconnected.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,34 @@
+use actix_web::web;
+use crate::models::*;
+use crate::AppState;
+
+pub enum Delivery {
+    Deliver,
+    Drop,
+    Refuse,
+}
+
+pub fn has_blocked(app_state: &web::Data<AppState>, owner: &str, other: &str) -> bool {
+    let privacy = app_state.privacy.lock().unwrap();
+    privacy.get(owner).map(|settings| settings.blocked.contains(other)).unwrap_or(false)
+}
+
+pub fn is_hidden(app_state: &web::Data<AppState>, username: &str) -> bool {
+    let privacy = app_state.privacy.lock().unwrap();
+    privacy.get(username).map(|settings| settings.hide_presence).unwrap_or(false)
+}
+
+pub fn direct_delivery(app_state: &web::Data<AppState>, recipient: &str, sender: &str) -> Delivery {
+    let privacy = app_state.privacy.lock().unwrap();
+    let Some(settings) = privacy.get(recipient) else {
+        return Delivery::Deliver;
+    };
+    if settings.blocked.contains(sender) {
+        return Delivery::Drop;
+    }
+    match settings.dm_policy {
+        DmPolicy::Everyone => Delivery::Deliver,
+        DmPolicy::Contacts if settings.contacts.contains(sender) => Delivery::Deliver,
+        DmPolicy::Contacts => Delivery::Refuse,
+    }
+}
@@ -0,0 +1,53 @@
+use actix_web::web;
+use serde_json::json;
+use crate::models::*;
+use crate::AppState;
+use crate::cluster;
+use crate::history;
+
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+pub const MAX_BIO_LEN: usize = 500;
+pub const MAX_TIMEZONE_LEN: usize = 64;
+pub const MAX_AVATAR_BYTES: usize = 2 * 1024 * 1024;
+
+pub fn validate(value: String, max_len: usize, label: &str) -> Result<Option<String>, String> {
+    let value = value.trim().to_string();
+    if value.chars().count() > max_len {
+        return Err(format!("{} задовге (максимум {} символів)", label, max_len));
+    }
+    Ok((!value.is_empty()).then_some(value))
+}
+
+pub fn validate_timezone(value: String) -> Result<Option<String>, String> {
+    let value = value.trim().to_string();
+    let valid = value.len() <= MAX_TIMEZONE_LEN
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "/_-+:".contains(c));
+    if !valid {
+        return Err("Некоректний часовий пояс".to_string());
+    }
+    Ok((!value.is_empty()).then_some(value))
+}
+
+pub fn update(
+    app_state: &web::Data<AppState>,
+    username: &str,
+    change: impl FnOnce(&mut Profile) -> Result<(), String>,
+) -> Result<(), String> {
+    let profile = {
+        let mut users = app_state.users.lock().unwrap();
+        let user = users.get_mut(username).ok_or_else(|| "Користувач не знайдений".to_string())?;
+        let mut profile = user.profile.clone();
+        change(&mut profile)?;
+        user.profile = profile.clone();
+        profile
+    };
+    history::touch(app_state);
+
+    let event = json!({
+        "type": "profile_updated",
+        "username": username,
+        "profile": profile
+    }).to_string();
+    cluster::broadcast(app_state, &event);
+    Ok(())
+}
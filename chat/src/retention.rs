@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use actix::prelude::*;
+use actix_web::web;
+use crate::config::Retention;
+use crate::models::*;
+use crate::AppState;
+use crate::history;
+use crate::threads;
+use crate::uploads;
+
+#[derive(Debug, Default)]
+pub struct CleanupReport {
+    pub messages: usize,
+    pub history_entries: usize,
+    pub mentions: usize,
+    pub released_files: usize,
+    pub orphaned_blobs: usize,
+}
+
+fn keep_flags(retention: &Retention, now: u64, items: Vec<(&str, String, u64)>) -> Vec<bool> {
+    let mut newer: HashMap<String, usize> = HashMap::new();
+    let mut keep = vec![true; items.len()];
+    for (position, (conversation, count_key, timestamp)) in items.into_iter().enumerate().rev() {
+        let seen = newer.entry(count_key).or_default();
+        keep[position] = retention.policy_for(conversation).keeps(timestamp, now, *seen);
+        *seen += 1;
+    }
+    keep
+}
+
+fn retain_recent<T>(items: &mut Vec<T>, retention: &Retention, now: u64, describe: impl Fn(&T) -> (&str, u64)) -> usize {
+    let described = items.iter().map(|item| {
+        let (conversation, timestamp) = describe(item);
+        (conversation, conversation.to_string(), timestamp)
+    }).collect();
+    let mut keep = keep_flags(retention, now, described).into_iter();
+    let before = items.len();
+    items.retain(|_| keep.next().unwrap_or(true));
+    before - items.len()
+}
+
+fn expire_index(app_state: &web::Data<AppState>, retention: &Retention, now: u64) -> (usize, Vec<String>) {
+    let mut index = app_state.message_index.lock().unwrap();
+    let mut messages: Vec<&StoredMessage> = index.values().collect();
+    messages.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+    let described = messages.iter()
+        .map(|message| (message.recipient.as_str(), threads::conversation_key(&message.from, &message.recipient), message.timestamp))
+        .collect();
+    let expired: Vec<String> = messages.iter().zip(keep_flags(retention, now, described))
+        .filter(|(_, keep)| !keep)
+        .map(|(message, _)| message.id.clone())
+        .collect();
+
+    let mut expired_files = Vec::new();
+    for id in expired.iter() {
+        if let Some(attachment) = index.remove(id).and_then(|message| message.attachment) {
+            expired_files.push(attachment.file_id);
+        }
+    }
+    app_state.threads.lock().unwrap().retain(|root, replies| {
+        replies.retain(|id| index.contains_key(id));
+        index.contains_key(root) && !replies.is_empty()
+    });
+    (expired.len(), expired_files)
+}
+
+pub fn cleanup(app_state: &web::Data<AppState>) -> CleanupReport {
+    let retention = &app_state.config.retention;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut report = CleanupReport::default();
+
+    let (messages, expired_files) = expire_index(app_state, retention, now);
+    report.messages = messages;
+    for history in app_state.messages.lock().unwrap().values_mut() {
+        report.history_entries += retain_recent(history, retention, now, |entry| (&entry.conversation, entry.timestamp));
+    }
+    for log in app_state.room_logs.lock().unwrap().values_mut() {
+        report.history_entries += retain_recent(log, retention, now, |entry| (&entry.conversation, entry.timestamp));
+    }
+    for group in app_state.groups.lock().unwrap().values_mut() {
+        report.history_entries += retain_recent(&mut group.messages, retention, now, |entry| (&entry.conversation, entry.timestamp));
+    }
+    for mentions in app_state.mentions.lock().unwrap().values_mut() {
+        report.mentions += retain_recent(mentions, retention, now, |mention| (&mention.conversation, mention.timestamp));
+    }
+
+    for file_id in expired_files.iter() {
+        uploads::release(app_state, file_id);
+    }
+    report.released_files = expired_files.len() + uploads::release_expired(app_state, now);
+    report.orphaned_blobs = uploads::collect_garbage(app_state);
+    if report.messages + report.history_entries > 0 {
+        history::touch(app_state);
+    }
+    report
+}
+
+pub struct Janitor {
+    pub app_state: web::Data<AppState>,
+}
+
+impl Actor for Janitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = self.app_state.config.retention.cleanup_interval.max(Duration::from_secs(1));
+        ctx.run_interval(interval, |janitor, ctx| {
+            let app_state = janitor.app_state.clone();
+            let started = Instant::now();
+            async move { web::block(move || cleanup(&app_state)).await }
+                .into_actor(janitor)
+                .map(move |report, _, _| match report {
+                    Ok(report) => tracing::info!(
+                        messages = report.messages,
+                        history_entries = report.history_entries,
+                        mentions = report.mentions,
+                        released_files = report.released_files,
+                        orphaned_blobs = report.orphaned_blobs,
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "retention cleanup finished",
+                    ),
+                    Err(error) => tracing::error!(%error, "retention cleanup failed"),
+                })
+                .wait(ctx);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetentionPolicy;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn retention() -> Retention {
+        Retention {
+            direct_messages: RetentionPolicy::MaxAge(Duration::from_secs(60)),
+            rooms: RetentionPolicy::MaxCount(2),
+            room_overrides: [("#ops".to_string(), RetentionPolicy::Forever)].into(),
+            cleanup_interval: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn parses_retention_policies() {
+        let cases = [
+            ("forever", Some(RetentionPolicy::Forever)),
+            (" 30d ", Some(RetentionPolicy::MaxAge(Duration::from_secs(30 * 86400)))),
+            ("12h", Some(RetentionPolicy::MaxAge(Duration::from_secs(12 * 3600)))),
+            ("90m", Some(RetentionPolicy::MaxAge(Duration::from_secs(90 * 60)))),
+            ("45s", Some(RetentionPolicy::MaxAge(Duration::from_secs(45)))),
+            ("100", Some(RetentionPolicy::MaxCount(100))),
+            ("", None),
+            ("d", None),
+            ("-1", None),
+            ("10w", None),
+            ("1.5h", None),
+            ("18446744073709551615d", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(value.parse::<RetentionPolicy>().ok(), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn expires_at_the_age_and_count_boundaries() {
+        let max_age = RetentionPolicy::MaxAge(Duration::from_secs(60));
+        assert!(max_age.keeps(NOW - 60, NOW, 1000));
+        assert!(!max_age.keeps(NOW - 61, NOW, 0));
+        assert!(max_age.keeps(NOW + 10, NOW, 0));
+
+        let max_count = RetentionPolicy::MaxCount(2);
+        assert!(max_count.keeps(0, NOW, 1));
+        assert!(!max_count.keeps(NOW, NOW, 2));
+        assert!(!RetentionPolicy::MaxCount(0).keeps(NOW, NOW, 0));
+        assert!(RetentionPolicy::Forever.keeps(0, NOW, usize::MAX));
+    }
+
+    #[test]
+    fn keeps_the_newest_entries_per_conversation() {
+        let items = vec![
+            ("#dev", "#dev".to_string(), NOW - 30),
+            ("bob", "alice:bob".to_string(), NOW - 120),
+            ("#dev", "#dev".to_string(), NOW - 20),
+            ("#ops", "#ops".to_string(), 0),
+            ("#dev", "#dev".to_string(), NOW - 10),
+            ("bob", "alice:bob".to_string(), NOW - 60),
+            ("#ops", "#ops".to_string(), 1),
+            ("#ops", "#ops".to_string(), 2),
+        ];
+        assert_eq!(keep_flags(&retention(), NOW, items), vec![false, false, true, true, true, true, true, true]);
+    }
+}
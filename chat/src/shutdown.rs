@@ -0,0 +1,148 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use actix_web::dev::{Server, ServerHandle};
+use actix_web::web;
+use crate::AppState;
+use crate::history;
+use crate::uploads;
+use crate::websocket::Shutdown;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[cfg(unix)]
+async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "ctrl-c"
+}
+
+pub fn is_shutting_down(app_state: &web::Data<AppState>) -> bool {
+    app_state.shutting_down.load(Ordering::SeqCst)
+}
+
+async fn close_sessions(app_state: &web::Data<AppState>) -> usize {
+    let reconnect_after = app_state.config.reconnect_after;
+    let connections: Vec<_> = app_state.connections.lock().unwrap().values().cloned().collect();
+    for connection in connections.iter() {
+        connection.do_send(Shutdown { reconnect_after });
+    }
+
+    let deadline = Instant::now() + app_state.config.shutdown_timeout;
+    loop {
+        let remaining = app_state.connections.lock().unwrap().len();
+        if remaining == 0 || Instant::now() >= deadline {
+            return remaining;
+        }
+        actix::clock::sleep(POLL_INTERVAL).await;
+    }
+}
+
+pub async fn watch(app_state: web::Data<AppState>, server: ServerHandle) {
+    let signal = signal().await;
+    tracing::info!(signal, "shutting down");
+    drain(&app_state, server).await;
+}
+
+async fn drain(app_state: &web::Data<AppState>, server: ServerHandle) {
+    app_state.shutting_down.store(true, Ordering::SeqCst);
+    server.pause().await;
+
+    let remaining = close_sessions(app_state).await;
+    if remaining > 0 {
+        tracing::warn!(remaining, "sessions still open after shutdown timeout");
+    }
+    server.stop(true).await;
+}
+
+pub async fn serve(app_state: &web::Data<AppState>, server: Server) -> std::io::Result<()> {
+    server.await?;
+    flush(app_state);
+    Ok(())
+}
+
+fn flush(app_state: &web::Data<AppState>) {
+    match uploads::persist(app_state).and_then(|_| uploads::sync()) {
+        Ok(()) => tracing::info!(blobs = app_state.blobs.lock().unwrap().len(), "upload storage flushed"),
+        Err(error) => tracing::error!(%error, "failed to flush upload storage"),
+    }
+    match history::save(app_state) {
+        Ok(()) => tracing::info!("history saved"),
+        Err(error) => tracing::error!(%error, "failed to save history"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpServer};
+    use actix_web_actors::ws::{CloseCode, Frame};
+    use futures_util::StreamExt;
+    use super::*;
+    use crate::config::Config;
+
+    #[actix_web::test]
+    async fn closes_sessions_before_flushing_state() {
+        let app_state = crate::test_state(Config::from_env());
+        app_state.sessions.lock().unwrap().insert("token".to_string(), "alice".to_string());
+        let server_state = app_state.clone();
+        let server = HttpServer::new(move || App::new()
+            .app_data(server_state.clone())
+            .route("/ws/", web::get().to(crate::websocket_handler)))
+            .workers(1)
+            .disable_signals()
+            .bind("127.0.0.1:0").unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        let served = actix::spawn({
+            let app_state = app_state.clone();
+            async move { serve(&app_state, server).await }
+        });
+
+        let (_, mut socket) = awc::Client::new().ws(format!("ws://{}/ws/?token=token", address)).connect().await.unwrap();
+        for _ in 0..100 {
+            if app_state.connections.lock().unwrap().contains_key("alice") {
+                break;
+            }
+            actix::clock::sleep(POLL_INTERVAL).await;
+        }
+        assert!(app_state.connections.lock().unwrap().contains_key("alice"));
+        history::touch(&app_state);
+
+        actix::spawn({
+            let app_state = app_state.clone();
+            async move { drain(&app_state, handle).await }
+        });
+        let mut notice = None;
+        while let Some(Ok(frame)) = socket.next().await {
+            match frame {
+                Frame::Text(text) => {
+                    let event: serde_json::Value = serde_json::from_slice(&text).unwrap();
+                    if event["type"] == "server_shutdown" {
+                        notice = Some(event);
+                    }
+                },
+                Frame::Close(reason) => {
+                    assert_eq!(reason.unwrap().code, CloseCode::Restart);
+                    break;
+                },
+                _ => {},
+            }
+        }
+        assert_eq!(notice.unwrap()["reconnectAfter"], app_state.config.reconnect_after.as_secs());
+
+        served.await.unwrap().unwrap();
+        assert!(is_shutting_down(&app_state));
+        assert!(app_state.connections.lock().unwrap().is_empty());
+        assert!(!app_state.history_dirty.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,226 @@
+use std::pin::Pin;
+use std::time::Duration;
+use actix_codec::Decoder;
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_http::ws::{Codec, Frame};
+use actix_web_actors::ws;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::json;
+use tokio::sync::mpsc;
+use crate::AppState;
+use crate::framing;
+use crate::websocket::ChatSession;
+
+const INBOX_CAPACITY: usize = 64;
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+pub struct EventStream {
+    pub owner: String,
+    pub token: String,
+    inbox: mpsc::Sender<String>,
+}
+
+struct Registration {
+    app_state: web::Data<AppState>,
+    stream_id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.app_state.event_streams.lock().unwrap().remove(&self.stream_id);
+    }
+}
+
+type Frames = Pin<Box<dyn Stream<Item = Result<Bytes, actix_web::Error>>>>;
+
+struct Outbound {
+    frames: Frames,
+    codec: Codec,
+    buffer: BytesMut,
+    finished: bool,
+    _registration: Registration,
+}
+
+fn event(name: &str, data: &str) -> Bytes {
+    let mut event = format!("event: {}\n", name);
+    for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    Bytes::from(event)
+}
+
+async fn next_event(outbound: &mut Outbound) -> Option<Bytes> {
+    loop {
+        match outbound.codec.decode(&mut outbound.buffer) {
+            Ok(Some(Frame::Text(text))) => return Some(event("message", &String::from_utf8_lossy(&text))),
+            Ok(Some(Frame::Close(reason))) => {
+                outbound.finished = true;
+                let (code, description) = reason
+                    .map(|reason| (u16::from(reason.code), reason.description))
+                    .unwrap_or((1000, None));
+                return Some(event("close", &json!({ "code": code, "reason": description }).to_string()));
+            },
+            Ok(Some(_)) => continue,
+            Ok(None) => {},
+            Err(error) => {
+                tracing::warn!(%error, "failed to decode session frame");
+                return None;
+            },
+        }
+
+        match tokio::time::timeout(KEEPALIVE, outbound.frames.next()).await {
+            Ok(Some(Ok(chunk))) => outbound.buffer.extend_from_slice(&chunk),
+            Ok(Some(Err(error))) => {
+                tracing::warn!(%error, "session stream failed");
+                return None;
+            },
+            Ok(None) => return None,
+            Err(_) => return Some(Bytes::from_static(b": keepalive\n\n")),
+        }
+    }
+}
+
+pub fn open(app_state: &web::Data<AppState>, username: &str, token: &str) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let stream_id = uuid::Uuid::new_v4().to_string();
+    let (sender, receiver) = mpsc::channel(INBOX_CAPACITY);
+    app_state.event_streams.lock().unwrap().insert(stream_id.clone(), EventStream {
+        owner: username.to_string(),
+        token: token.to_string(),
+        inbox: sender,
+    });
+
+    let session = ChatSession::new(app_state, username, Some(token));
+    let frames = ws::WebsocketContext::with_codec(session, framing::text_frames(receiver), Codec::new().max_size(app_state.config.ws_max_message_bytes));
+    let outbound = Outbound {
+        frames: Box::pin(frames),
+        codec: framing::session_decoder(),
+        buffer: BytesMut::new(),
+        finished: false,
+        _registration: Registration {
+            app_state: app_state.clone(),
+            stream_id: stream_id.clone(),
+        },
+    };
+
+    let hello = event("session", &json!({ "type": "session", "streamId": stream_id }).to_string());
+    let events = stream::unfold(outbound, |mut outbound| async move {
+        if outbound.finished {
+            return None;
+        }
+        let event = next_event(&mut outbound).await?;
+        Some((Ok(event), outbound))
+    });
+    stream::once(async move { Ok(hello) }).chain(events)
+}
+
+pub fn post(app_state: &web::Data<AppState>, stream_id: &str, username: &str, message: String) -> Result<(), String> {
+    let inbox = app_state.event_streams.lock().unwrap().get(stream_id)
+        .filter(|stream| stream.owner == username)
+        .map(|stream| stream.inbox.clone())
+        .ok_or_else(|| "Потік подій не знайдено".to_string())?;
+    inbox.try_send(message).map_err(|error| match error {
+        mpsc::error::TrySendError::Full(_) => "Забагато повідомлень, спробуйте пізніше".to_string(),
+        mpsc::error::TrySendError::Closed(_) => "Потік подій не знайдено".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use super::*;
+    use crate::config::Config;
+    use crate::handlers::{open_event_stream, post_event};
+
+    async fn hello(events: &mut (impl Stream<Item = Result<Bytes, actix_web::Error>> + Unpin)) -> String {
+        let hello = events.next().await.unwrap().unwrap();
+        let hello = String::from_utf8_lossy(&hello).to_string();
+        let data = hello.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+        serde_json::from_str::<serde_json::Value>(data).unwrap()["streamId"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn frames_every_payload_line_as_data() {
+        assert_eq!(event("message", "{}"), Bytes::from("event: message\ndata: {}\n\n"));
+        assert_eq!(
+            event("message", "перший\nдругий\r\n\nтретій\rчетвертий"),
+            Bytes::from("event: message\ndata: перший\ndata: другий\ndata: \ndata: третій\ndata: четвертий\n\n"),
+        );
+        assert_eq!(event("close", ""), Bytes::from("event: close\ndata: \n\n"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_unauthenticated_streams_and_foreign_posts() {
+        let app_state = crate::test_state(Config::from_env());
+        app_state.sessions.lock().unwrap().insert("bob-token".to_string(), "bob".to_string());
+        let app = init_service(App::new()
+            .app_data(app_state.clone())
+            .route("/events", web::get().to(open_event_stream))
+            .route("/events/{stream_id}", web::post().to(post_event))).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/events?token=guess").to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(app_state.event_streams.lock().unwrap().is_empty());
+
+        let mut events = Box::pin(open(&app_state, "alice", "alice-token"));
+        let stream_id = hello(&mut events).await;
+        let message = json!({ "type": "message", "recipient": "public", "content": "від імені alice" });
+        for (token, status) in [("guess", StatusCode::UNAUTHORIZED), ("bob-token", StatusCode::NOT_FOUND)] {
+            let request = TestRequest::post()
+                .uri(&format!("/events/{}", stream_id))
+                .set_json(json!({ "token": token, "message": message }))
+                .to_request();
+            assert_eq!(call_service(&app, request).await.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn unregisters_the_stream_and_session_on_disconnect() {
+        let app_state = crate::test_state(Config::from_env());
+        let mut events = Box::pin(open(&app_state, "alice", "alice-token"));
+        let stream_id = hello(&mut events).await;
+        let pending = tokio::time::timeout(Duration::from_millis(100), events.next()).await;
+        assert!(pending.is_err());
+        assert!(app_state.event_streams.lock().unwrap().contains_key(&stream_id));
+        assert!(app_state.connections.lock().unwrap().contains_key("alice"));
+
+        drop(events);
+        assert!(!app_state.event_streams.lock().unwrap().contains_key(&stream_id));
+        for _ in 0..100 {
+            if !app_state.connections.lock().unwrap().contains_key("alice") {
+                break;
+            }
+            actix::clock::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!app_state.connections.lock().unwrap().contains_key("alice"));
+        assert_eq!(post(&app_state, &stream_id, "alice", "{}".to_string()).unwrap_err(), "Потік подій не знайдено");
+    }
+
+    #[actix_web::test]
+    async fn delivers_messages_larger_than_the_default_frame_limit() {
+        let app_state = crate::test_state(Config::from_env());
+        let mut events = Box::pin(open(&app_state, "alice", "alice-token"));
+        let stream_id = hello(&mut events).await;
+
+        let content = "я".repeat(40 * 1024);
+        let message = json!({ "type": "message", "recipient": "public", "content": content }).to_string();
+        post(&app_state, &stream_id, "alice", message).unwrap();
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.next()).await
+                .expect("no event within 5 seconds")
+                .expect("event stream ended")
+                .unwrap();
+            let event = String::from_utf8_lossy(&event).to_string();
+            assert!(!event.starts_with("event: close"), "session closed: {}", event);
+            if event.contains(&content) {
+                assert!(event.len() > 64 * 1024);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use std::panic;
+use tracing_subscriber::EnvFilter;
+use crate::config::{Config, LogFormat};
+
+pub fn init(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_filter)
+        .unwrap_or_else(|error| panic!("invalid value for CHAT_LOG: '{}': {}", config.log_filter, error));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Pretty => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init(),
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|location| location.to_string()).unwrap_or_default();
+        let payload = info.payload().downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        tracing::error!(%location, panic = %payload, "panic");
+        default_hook(info);
+    }));
+}
+
+pub fn new_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::web;
+use crate::models::*;
+use crate::AppState;
+use crate::groups;
+use crate::history;
+use crate::privacy;
+
+const MAX_EMOJI_LEN: usize = 32;
+
+pub fn conversation_key(from: &str, recipient: &str) -> String {
+    if recipient == "public" || recipient.starts_with('#') || groups::is_group(recipient) {
+        return recipient.to_string();
+    }
+    let mut participants = [from, recipient];
+    participants.sort();
+    format!("{}:{}", participants[0], participants[1])
+}
+
+pub fn can_see(app_state: &web::Data<AppState>, message: &StoredMessage, username: &str) -> bool {
+    if message.recipient == "public" {
+        return true;
+    }
+    if message.recipient.starts_with('#') {
+        let rooms = app_state.rooms.lock().unwrap();
+        return rooms.get(&message.recipient).map(|members| members.contains(username)).unwrap_or(false);
+    }
+    if groups::is_group(&message.recipient) {
+        let groups = app_state.groups.lock().unwrap();
+        return groups.get(&message.recipient).map(|group| group.participants.contains(username)).unwrap_or(false);
+    }
+    if message.recipient == username && privacy::has_blocked(app_state, username, &message.from) {
+        return false;
+    }
+    message.from == username || message.recipient == username
+}
+
+pub fn viewers(app_state: &web::Data<AppState>, message: &StoredMessage) -> Vec<String> {
+    if message.recipient.starts_with('#') {
+        let rooms = app_state.rooms.lock().unwrap();
+        return rooms.get(&message.recipient).map(|members| members.iter().cloned().collect()).unwrap_or_default();
+    }
+    if groups::is_group(&message.recipient) {
+        let groups = app_state.groups.lock().unwrap();
+        return groups.get(&message.recipient).map(|group| group.participants.iter().cloned().collect()).unwrap_or_default();
+    }
+    [&message.from, &message.recipient].into_iter()
+        .filter(|user| can_see(app_state, message, user))
+        .cloned()
+        .collect()
+}
+
+pub fn record(
+    app_state: &web::Data<AppState>,
+    from: &str,
+    recipient: &str,
+    content: &str,
+    reply_to: Option<&str>,
+) -> Result<StoredMessage, String> {
+    let thread_id = match reply_to {
+        Some(parent_id) => {
+            let parent = app_state.message_index.lock().unwrap().get(parent_id).cloned()
+                .filter(|parent| can_see(app_state, parent, from))
+                .ok_or_else(|| "Повідомлення для відповіді не знайдено".to_string())?;
+            if conversation_key(&parent.from, &parent.recipient) != conversation_key(from, recipient) {
+                return Err("Відповідь має бути в тій самій розмові".to_string());
+            }
+            Some(parent.thread_id.clone().unwrap_or_else(|| parent.id.clone()))
+        },
+        None => None,
+    };
+
+    let message = StoredMessage {
+        id: uuid::Uuid::now_v7().to_string(),
+        from: from.to_string(),
+        recipient: recipient.to_string(),
+        content: content.to_string(),
+        reply_to: reply_to.map(|id| id.to_string()),
+        thread_id,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        reactions: BTreeMap::new(),
+        attachment: None,
+    };
+    insert(app_state, message.clone());
+    Ok(message)
+}
+
+pub fn record_file(app_state: &web::Data<AppState>, from: &str, recipient: &str, attachment: Attachment) -> StoredMessage {
+    let message = StoredMessage {
+        id: uuid::Uuid::now_v7().to_string(),
+        from: from.to_string(),
+        recipient: recipient.to_string(),
+        content: attachment.filename.clone(),
+        reply_to: None,
+        thread_id: None,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        reactions: BTreeMap::new(),
+        attachment: Some(attachment),
+    };
+    insert(app_state, message.clone());
+    message
+}
+
+pub fn insert(app_state: &web::Data<AppState>, message: StoredMessage) {
+    if let Some(thread_id) = &message.thread_id {
+        app_state.threads.lock().unwrap().entry(thread_id.clone()).or_default().push(message.id.clone());
+    }
+    app_state.message_index.lock().unwrap().insert(message.id.clone(), message);
+    history::touch(app_state);
+}
+
+pub fn thread(app_state: &web::Data<AppState>, message_id: &str, username: &str) -> Option<(MessageView, Vec<MessageView>)> {
+    let root = {
+        let index = app_state.message_index.lock().unwrap();
+        let message = index.get(message_id)?;
+        let root_id = message.thread_id.as_deref().unwrap_or(&message.id);
+        index.get(root_id)?.clone()
+    };
+    if !can_see(app_state, &root, username) {
+        return None;
+    }
+
+    let reply_ids = app_state.threads.lock().unwrap().get(&root.id).cloned().unwrap_or_default();
+    let index = app_state.message_index.lock().unwrap();
+    let replies = reply_ids.iter().filter_map(|id| index.get(id)).map(MessageView::from).collect();
+    Some((MessageView::from(&root), replies))
+}
+
+pub fn react(
+    app_state: &web::Data<AppState>,
+    username: &str,
+    message_id: &str,
+    emoji: &str,
+    add: bool,
+) -> Result<StoredMessage, String> {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LEN || emoji.chars().any(char::is_whitespace) {
+        return Err("Некоректна реакція".to_string());
+    }
+
+    let visible = app_state.message_index.lock().unwrap().get(message_id).cloned()
+        .is_some_and(|message| can_see(app_state, &message, username));
+    if !visible {
+        return Err("Повідомлення не знайдено".to_string());
+    }
+
+    let mut index = app_state.message_index.lock().unwrap();
+    let message = index.get_mut(message_id)
+        .ok_or_else(|| "Повідомлення не знайдено".to_string())?;
+
+    if add {
+        message.reactions.entry(emoji.to_string()).or_default().insert(username.to_string());
+    } else if let Some(users) = message.reactions.get_mut(emoji) {
+        users.remove(username);
+        if users.is_empty() {
+            message.reactions.remove(emoji);
+        }
+    }
+    history::touch(app_state);
+    Ok(message.clone())
+}
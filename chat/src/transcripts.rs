@@ -0,0 +1,375 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::web;
+use crate::models::*;
+use crate::AppState;
+use crate::groups;
+use crate::privacy;
+use crate::threads;
+use crate::history;
+
+pub const EXPORT_VERSION: u32 = 1;
+pub const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+pub enum Scope {
+    User(String),
+    Room(String),
+}
+
+impl Scope {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Scope::User(_) => "user",
+            Scope::Room(_) => "room",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Scope::User(name) | Scope::Room(name) => name,
+        }
+    }
+}
+
+fn is_member(app_state: &web::Data<AppState>, conversation: &str, username: &str) -> bool {
+    if conversation == "public" {
+        return true;
+    }
+    if conversation.starts_with('#') {
+        let rooms = app_state.rooms.lock().unwrap();
+        return rooms.get(conversation).map(|members| members.contains(username)).unwrap_or(false);
+    }
+    if groups::is_group(conversation) {
+        let groups = app_state.groups.lock().unwrap();
+        return groups.get(conversation).map(|group| group.participants.contains(username)).unwrap_or(false);
+    }
+    false
+}
+
+pub fn can_export(app_state: &web::Data<AppState>, requester: &str, scope: &Scope) -> bool {
+    if app_state.config.is_admin(requester) {
+        return true;
+    }
+    match scope {
+        Scope::User(username) => username == requester,
+        Scope::Room(room) => is_member(app_state, room, requester),
+    }
+}
+
+fn sort_messages(messages: &mut [StoredMessage]) {
+    messages.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+}
+
+pub fn export(app_state: &web::Data<AppState>, requester: &str, scope: &Scope) -> HistoryExport {
+    let all: Vec<StoredMessage> = app_state.message_index.lock().unwrap().values().cloned().collect();
+    let (viewer, admin_view) = match scope {
+        Scope::User(username) => (username.as_str(), false),
+        Scope::Room(_) => (requester, app_state.config.is_admin(requester)),
+    };
+    let mut messages: Vec<StoredMessage> = all.into_iter()
+        .filter(|message| match scope {
+            Scope::User(_) => true,
+            Scope::Room(room) => &message.recipient == room,
+        })
+        .filter(|message| admin_view || threads::can_see(app_state, message, viewer))
+        .filter(|message| !privacy::has_blocked(app_state, viewer, &message.from))
+        .collect();
+    sort_messages(&mut messages);
+
+    HistoryExport {
+        msg_type: "history_export".to_string(),
+        version: EXPORT_VERSION,
+        scope: scope.kind().to_string(),
+        name: scope.name().to_string(),
+        exported_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        messages,
+    }
+}
+
+fn push_history_lines(app_state: &web::Data<AppState>, message: &StoredMessage) {
+    let (sent, received) = match &message.attachment {
+        Some(attachment) => (
+            format!("Надіслав файл '{}'", attachment.filename),
+            format!("Отримано файл '{}'", attachment.filename),
+        ),
+        None => (message.content.clone(), message.content.clone()),
+    };
+    let entry = |conversation: &str, line: String| HistoryEntry {
+        timestamp: message.timestamp,
+        ..HistoryEntry::new(conversation, &message.from, line)
+    };
+
+    if message.recipient == "public" {
+        if message.attachment.is_some() {
+            return;
+        }
+        history::push_shared(app_state, entry(history::PUBLIC, format!("{}: {}", message.from, sent)));
+    } else if message.recipient.starts_with('#') {
+        history::push_shared(app_state, entry(&message.recipient, format!("[{}] {}: {}", message.recipient, message.from, sent)));
+    } else if groups::is_group(&message.recipient) {
+        let mut entry = entry(&message.recipient, format!("{}: {}", message.from, sent));
+        entry.seq = history::next_seq(app_state);
+        if let Some(group) = app_state.groups.lock().unwrap().get_mut(&message.recipient) {
+            group.messages.push(entry);
+        }
+    } else {
+        history::push(app_state, &message.from, entry(&message.recipient, format!("До {}: {}", message.recipient, sent)));
+        history::push(app_state, &message.recipient, entry(&message.from, format!("Від {}: {}", message.from, received)));
+    }
+}
+
+pub fn import(app_state: &web::Data<AppState>, export: HistoryExport) -> Result<(usize, usize), String> {
+    if export.version != EXPORT_VERSION {
+        return Err(format!("Непідтримувана версія експорту: {}", export.version));
+    }
+
+    let mut messages = export.messages;
+    sort_messages(&mut messages);
+    let mut imported = 0;
+    let mut skipped = 0;
+    for message in messages {
+        let invalid = message.id.is_empty() || message.from.is_empty() || message.recipient.is_empty();
+        let exists = app_state.message_index.lock().unwrap().contains_key(&message.id);
+        let missing_group = groups::is_group(&message.recipient)
+            && !app_state.groups.lock().unwrap().contains_key(&message.recipient);
+        if invalid || exists || missing_group {
+            skipped += 1;
+            continue;
+        }
+        push_history_lines(app_state, &message);
+        threads::insert(app_state, message);
+        imported += 1;
+    }
+    Ok((imported, skipped))
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60
+    )
+}
+
+fn format_size(size: usize) -> String {
+    match size {
+        size if size >= 1024 * 1024 => format!("{:.1} МБ", size as f64 / (1024.0 * 1024.0)),
+        size if size >= 1024 => format!("{:.1} КБ", size as f64 / 1024.0),
+        size => format!("{} Б", size),
+    }
+}
+
+fn render_message(message: &StoredMessage) -> String {
+    let mut html = format!(
+        "<li id=\"m-{id}\"><div class=\"meta\"><time>{time}</time> <b>{from}</b> → {to}</div>",
+        id = escape_html(&message.id),
+        time = format_timestamp(message.timestamp),
+        from = escape_html(&message.from),
+        to = escape_html(&message.recipient),
+    );
+    if let Some(reply_to) = &message.reply_to {
+        html.push_str(&format!("<div class=\"reply\"><a href=\"#m-{0}\">у відповідь на повідомлення</a></div>", escape_html(reply_to)));
+    }
+    match &message.attachment {
+        Some(attachment) => {
+            let dimensions = match (attachment.width, attachment.height) {
+                (Some(width), Some(height)) => format!(", {}×{}", width, height),
+                _ => String::new(),
+            };
+            html.push_str(&format!(
+                "<div class=\"file\">📎 {} ({}, {}{})</div>",
+                escape_html(&attachment.filename),
+                escape_html(&attachment.content_type),
+                format_size(attachment.size),
+                dimensions,
+            ));
+        },
+        None => html.push_str(&format!("<div class=\"content\">{}</div>", escape_html(&message.content))),
+    }
+    if !message.reactions.is_empty() {
+        let reactions: Vec<String> = message.reactions.iter()
+            .map(|(emoji, users)| format!("<span title=\"{}\">{} {}</span>", escape_html(&users.iter().cloned().collect::<Vec<_>>().join(", ")), escape_html(emoji), users.len()))
+            .collect();
+        html.push_str(&format!("<div class=\"reactions\">{}</div>", reactions.join(" ")));
+    }
+    html.push_str("</li>\n");
+    html
+}
+
+pub fn render_html(export: &HistoryExport) -> String {
+    let title = match export.scope.as_str() {
+        "user" => format!("Історія користувача {}", export.name),
+        _ => format!("Історія розмови {}", export.name),
+    };
+    let messages: String = export.messages.iter().map(render_message).collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="uk">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 800px; margin: 2em auto; color: #222; }}
+ol {{ list-style: none; padding: 0; }}
+li {{ border-bottom: 1px solid #eee; padding: 0.5em 0; }}
+.meta {{ color: #666; font-size: 0.85em; }}
+.content {{ white-space: pre-wrap; }}
+.reply, .reactions {{ font-size: 0.85em; color: #555; }}
+.file {{ background: #f4f4f4; padding: 0.25em 0.5em; border-radius: 4px; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+<p class="meta">Експортовано {exported_at}, повідомлень: {count}</p>
+<ol>
+{messages}</ol>
+</body>
+</html>
+"#,
+        title = escape_html(&title),
+        exported_at = format_timestamp(export.exported_at),
+        count = export.messages.len(),
+        messages = messages,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use super::*;
+    use crate::config::Config;
+
+    fn message(id: &str, from: &str, recipient: &str, content: &str) -> StoredMessage {
+        StoredMessage {
+            id: id.to_string(),
+            from: from.to_string(),
+            recipient: recipient.to_string(),
+            content: content.to_string(),
+            reply_to: None,
+            thread_id: None,
+            timestamp: 1_700_000_000,
+            reactions: BTreeMap::new(),
+            attachment: None,
+        }
+    }
+
+    fn transcript(messages: Vec<StoredMessage>) -> HistoryExport {
+        HistoryExport {
+            msg_type: "history_export".to_string(),
+            version: EXPORT_VERSION,
+            scope: "room".to_string(),
+            name: "#dev".to_string(),
+            exported_at: 0,
+            messages,
+        }
+    }
+
+    #[test]
+    fn formats_known_epochs() {
+        let cases = [
+            (0, "1970-01-01 00:00:00 UTC"),
+            (951_782_400, "2000-02-29 00:00:00 UTC"),
+            (1_700_000_000, "2023-11-14 22:13:20 UTC"),
+            (1_709_251_199, "2024-02-29 23:59:59 UTC"),
+            (4_107_542_400, "2100-03-01 00:00:00 UTC"),
+            (253_402_300_799, "9999-12-31 23:59:59 UTC"),
+        ];
+        for (timestamp, expected) in cases {
+            assert_eq!(format_timestamp(timestamp), expected);
+        }
+    }
+
+    #[test]
+    fn escapes_user_content_in_html() {
+        let mut hostile = message("1\"><b>", "<img src=x>", "#dev", "<script>alert('x')</script> & co");
+        hostile.reply_to = Some("\" onclick=\"evil".to_string());
+        hostile.reactions.insert("<3".to_string(), ["\"bob\"".to_string()].into());
+        let mut file = message("2", "alice", "#dev", "");
+        file.attachment = Some(Attachment {
+            file_id: "f".to_string(),
+            filename: "<svg onload=x>.png".to_string(),
+            content_type: "image/png".to_string(),
+            size: 2048,
+            width: Some(2),
+            height: Some(1),
+        });
+        let mut export = transcript(vec![hostile, file]);
+        export.name = "<#dev>".to_string();
+
+        let html = render_html(&export);
+        for raw in ["<script>", "<img", "<svg", "\"><b>", "\" onclick", "<3", "<#dev>"] {
+            assert!(!html.contains(raw), "{} left unescaped", raw);
+        }
+        assert!(html.contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; co"));
+        assert!(html.contains("&lt;svg onload=x&gt;.png (image/png, 2.0 КБ, 2×1)"));
+        assert!(html.contains("title=\"&quot;bob&quot;\""));
+        assert!(html.contains("Історія розмови &lt;#dev&gt;"));
+    }
+
+    #[actix_web::test]
+    async fn imports_new_messages_and_skips_the_rest() {
+        let app_state = crate::test_state(Config::from_env());
+        let mut old = transcript(Vec::new());
+        old.version = EXPORT_VERSION + 1;
+        assert_eq!(import(&app_state, old).unwrap_err(), format!("Непідтримувана версія експорту: {}", EXPORT_VERSION + 1));
+
+        threads::insert(&app_state, message("known", "bob", "alice", "вже є"));
+        let export = transcript(vec![
+            message("dm", "bob", "alice", "привіт"),
+            message("known", "bob", "alice", "дубль"),
+            message("dm", "bob", "alice", "дубль в експорті"),
+            message("lost", "bob", "group:missing", "у видаленій групі"),
+            message("", "bob", "alice", "без id"),
+            message("room", "bob", "#dev", "у кімнаті"),
+        ]);
+        assert_eq!(import(&app_state, export).unwrap(), (2, 4));
+        assert_eq!(app_state.message_index.lock().unwrap()["known"].content, "вже є");
+        assert_eq!(app_state.message_index.lock().unwrap()["dm"].content, "привіт");
+        assert_eq!(app_state.messages.lock().unwrap()["alice"][0].line, "Від bob: привіт");
+        assert_eq!(app_state.room_logs.lock().unwrap()["#dev"][0].line, "[#dev] bob: у кімнаті");
+    }
+
+    #[actix_web::test]
+    async fn room_exports_follow_the_requesters_visibility() {
+        let app_state = crate::test_state(Config::from_env());
+        app_state.rooms.lock().unwrap().insert("#dev".to_string(), ["alice".to_string(), "mallory".to_string()].into());
+        let mut settings = PrivacySettings::default();
+        settings.blocked.insert("mallory".to_string());
+        app_state.privacy.lock().unwrap().insert("alice".to_string(), settings);
+        threads::insert(&app_state, message("1", "mallory", "#dev", "спам"));
+        threads::insert(&app_state, message("2", "bob", "#dev", "збірка зелена"));
+        threads::insert(&app_state, message("3", "bob", "#ops", "інша кімната"));
+
+        let scope = Scope::Room("#dev".to_string());
+        let ids = |requester: &str| -> Vec<String> {
+            export(&app_state, requester, &scope).messages.into_iter().map(|message| message.id).collect()
+        };
+        assert_eq!(ids("alice"), vec!["2".to_string()]);
+        assert_eq!(ids("mallory"), vec!["1".to_string(), "2".to_string()]);
+        assert!(ids("carol").is_empty());
+    }
+}
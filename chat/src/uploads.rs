@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::models::*;
+use crate::AppState;
+use crate::media;
+use crate::crypto::{self, Keyring};
+
+pub const UPLOAD_DIR: &str = "uploads";
+const MANIFEST_FILE: &str = "manifest.json";
+const MANIFEST_PATH: &str = "uploads/manifest.json";
+
+pub fn blob_path(hash: &str) -> String {
+    format!("{}/{}", UPLOAD_DIR, hash)
+}
+
+pub fn resolve(app_state: &web::Data<AppState>, file_id: &str) -> Option<(String, String, bool)> {
+    let uploads = app_state.uploads.lock().unwrap();
+    let record = uploads.get(file_id)?;
+    let encrypted = app_state.blobs.lock().unwrap().get(&record.hash)?.key_id.is_some();
+    Some((blob_path(&record.hash), record.content_type.clone(), encrypted))
+}
+
+fn usage_of(uploads: &HashMap<String, UploadRecord>, owner: &str) -> usize {
+    uploads.values().filter(|record| record.owner == owner).map(|record| record.size).sum()
+}
+
+pub fn usage(app_state: &web::Data<AppState>, owner: &str) -> usize {
+    usage_of(&app_state.uploads.lock().unwrap(), owner)
+}
+
+pub fn write_atomic(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let temp_path = format!("{}.{}.tmp", path, uuid::Uuid::new_v4().simple());
+    std::fs::write(&temp_path, data)?;
+    std::fs::rename(&temp_path, path).inspect_err(|_| {
+        if let Err(error) = std::fs::remove_file(&temp_path) {
+            tracing::warn!(path = %temp_path, %error, "failed to remove temporary file");
+        }
+    })
+}
+
+fn write_blob(keyring: &Keyring, hash: &str, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(UPLOAD_DIR)?;
+    if keyring.is_enabled() {
+        let sealed = crypto::encrypt(keyring, data).map_err(std::io::Error::other)?;
+        write_atomic(&blob_path(hash), &sealed)
+    } else {
+        write_atomic(&blob_path(hash), data)
+    }
+}
+
+fn is_blob_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    uploads: HashMap<String, UploadRecord>,
+    blobs: HashMap<String, BlobRecord>,
+}
+
+pub fn load(app_state: &web::Data<AppState>) -> std::io::Result<usize> {
+    let manifest: Manifest = match std::fs::read(MANIFEST_PATH) {
+        Ok(data) => serde_json::from_slice(&data).map_err(std::io::Error::other)?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+        Err(error) => return Err(error),
+    };
+    let loaded = manifest.uploads.len();
+    *app_state.uploads.lock().unwrap() = manifest.uploads;
+    *app_state.blobs.lock().unwrap() = manifest.blobs;
+    Ok(loaded)
+}
+
+fn save(app_state: &web::Data<AppState>) -> std::io::Result<()> {
+    let manifest = Manifest {
+        uploads: app_state.uploads.lock().unwrap().clone(),
+        blobs: app_state.blobs.lock().unwrap().clone(),
+    };
+    std::fs::create_dir_all(UPLOAD_DIR)?;
+    write_atomic(MANIFEST_PATH, &serde_json::to_vec(&manifest).map_err(std::io::Error::other)?)
+}
+
+pub fn persist(app_state: &web::Data<AppState>) -> std::io::Result<()> {
+    app_state.manifest_dirty.store(true, Ordering::SeqCst);
+    let _io = app_state.manifest_io.lock().unwrap();
+    if app_state.manifest_dirty.swap(false, Ordering::SeqCst) {
+        save(app_state).inspect_err(|_| app_state.manifest_dirty.store(true, Ordering::SeqCst))?;
+    }
+    Ok(())
+}
+
+fn reference(app_state: &web::Data<AppState>, hash: &str, size: usize) -> bool {
+    let mut blobs = app_state.blobs.lock().unwrap();
+    let key_id = app_state.config.encryption.active_id().map(|key_id| key_id.to_string());
+    let blob = blobs.entry(hash.to_string()).or_insert(BlobRecord { size, references: 0, key_id });
+    blob.references += 1;
+    blob.references == 1
+}
+
+fn commit(app_state: &web::Data<AppState>, owner: &str, data: &[u8], content_type: String, image: Option<media::ImageInfo>) -> Result<(String, UploadRecord), String> {
+    let uploaded_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let thumbnail = image.as_ref().and_then(|image| image.thumbnail.as_deref());
+    let hash = hex::encode(Sha256::digest(data));
+    let thumbnail_hash = thumbnail.map(|thumbnail| hex::encode(Sha256::digest(thumbnail)));
+
+    let mut record = UploadRecord {
+        owner: owner.to_string(),
+        hash: hash.clone(),
+        size: data.len(),
+        content_type,
+        width: image.as_ref().map(|image| image.width),
+        height: image.as_ref().map(|image| image.height),
+        thumbnail: None,
+        uploaded_at,
+        expires_at: None,
+    };
+    let file_id = {
+        let mut uploads = app_state.uploads.lock().unwrap();
+        let requested = data.len() + thumbnail.map(|thumbnail| thumbnail.len()).unwrap_or(0);
+        if usage_of(&uploads, owner) + requested > app_state.config.user_storage_quota {
+            return Err("Перевищено квоту сховища".to_string());
+        }
+        if let (Some(thumbnail), Some(thumbnail_hash)) = (thumbnail, thumbnail_hash.as_ref()) {
+            let thumbnail_id = uuid::Uuid::new_v4().to_string();
+            uploads.insert(thumbnail_id.clone(), UploadRecord {
+                owner: owner.to_string(),
+                hash: thumbnail_hash.clone(),
+                size: thumbnail.len(),
+                content_type: "image/png".to_string(),
+                width: None,
+                height: None,
+                thumbnail: None,
+                uploaded_at,
+                expires_at: None,
+            });
+            record.thumbnail = Some(thumbnail_id);
+        }
+        let file_id = uuid::Uuid::new_v4().to_string();
+        uploads.insert(file_id.clone(), record.clone());
+        file_id
+    };
+
+    let written = {
+        let _io = app_state.upload_io.read().unwrap();
+        let mut written = Ok(());
+        if reference(app_state, &hash, data.len()) {
+            written = write_blob(&app_state.config.encryption, &hash, data);
+        }
+        if let (Some(thumbnail), Some(thumbnail_hash)) = (thumbnail, thumbnail_hash.as_ref()) {
+            if reference(app_state, thumbnail_hash, thumbnail.len()) {
+                written = written.and_then(|_| write_blob(&app_state.config.encryption, thumbnail_hash, thumbnail));
+            }
+        }
+        written
+    };
+    if let Err(error) = written {
+        tracing::error!(%hash, %error, "failed to write blob");
+        release(app_state, &file_id);
+        return Err("Не вдалося зберегти файл".to_string());
+    }
+    if let Err(error) = persist(app_state) {
+        tracing::error!(%error, "failed to save upload manifest");
+    }
+    Ok((file_id, record))
+}
+
+pub async fn store(app_state: &web::Data<AppState>, owner: &str, data: Vec<u8>) -> Result<(String, UploadRecord), String> {
+    let content_type = media::sniff(&data);
+    if media::is_denied(&app_state.config.denied_mime_types, &content_type) {
+        return Err(format!("Тип файлу {} заборонено", content_type));
+    }
+
+    let (app_state, owner) = (app_state.clone(), owner.to_string());
+    let (file_id, record) = web::block(move || {
+        let image = media::inspect_image(&data, &content_type);
+        commit(&app_state, &owner, &data, content_type, image)
+    }).await.map_err(|error| {
+        tracing::error!(%error, "failed to store upload");
+        "Не вдалося зберегти файл".to_string()
+    })??;
+    tracing::info!(%file_id, owner = %record.owner, size = record.size, content_type = %record.content_type, "upload stored");
+    Ok((file_id, record))
+}
+
+fn unreference(app_state: &web::Data<AppState>, file_id: &str, orphaned: &mut Vec<String>) -> bool {
+    let Some(record) = app_state.uploads.lock().unwrap().remove(file_id) else {
+        return false;
+    };
+    tracing::debug!(file_id, hash = %record.hash, "upload released");
+
+    {
+        let mut blobs = app_state.blobs.lock().unwrap();
+        if let Some(blob) = blobs.get_mut(&record.hash) {
+            blob.references = blob.references.saturating_sub(1);
+            if blob.references == 0 {
+                blobs.remove(&record.hash);
+                orphaned.push(record.hash.clone());
+            }
+        }
+    }
+    if let Some(thumbnail) = record.thumbnail {
+        unreference(app_state, &thumbnail, orphaned);
+    }
+    true
+}
+
+fn discard(app_state: &web::Data<AppState>, hashes: &[String]) {
+    {
+        let _io = app_state.upload_io.read().unwrap();
+        for hash in hashes {
+            let blobs = app_state.blobs.lock().unwrap();
+            if blobs.contains_key(hash) {
+                continue;
+            }
+            match std::fs::remove_file(blob_path(hash)) {
+                Ok(()) => tracing::debug!(%hash, "blob removed"),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {},
+                Err(error) => tracing::warn!(%hash, %error, "failed to remove blob"),
+            }
+        }
+    }
+    if let Err(error) = persist(app_state) {
+        tracing::error!(%error, "failed to save upload manifest");
+    }
+}
+
+pub fn release(app_state: &web::Data<AppState>, file_id: &str) {
+    let mut orphaned = Vec::new();
+    if !unreference(app_state, file_id, &mut orphaned) {
+        return;
+    }
+    let app_state = app_state.clone();
+    drop(web::block(move || discard(&app_state, &orphaned)));
+}
+
+pub fn schedule_release(app_state: &web::Data<AppState>, file_ids: &[String], at: u64) {
+    {
+        let mut uploads = app_state.uploads.lock().unwrap();
+        for file_id in file_ids {
+            if let Some(record) = uploads.get_mut(file_id) {
+                record.expires_at = Some(at);
+            }
+        }
+    }
+    if let Err(error) = persist(app_state) {
+        tracing::error!(%error, "failed to save upload manifest");
+    }
+}
+
+pub fn release_expired(app_state: &web::Data<AppState>, now: u64) -> usize {
+    let expired: Vec<String> = app_state.uploads.lock().unwrap().iter()
+        .filter(|(_, record)| record.expires_at.is_some_and(|at| at <= now))
+        .map(|(file_id, _)| file_id.clone())
+        .collect();
+    for file_id in expired.iter() {
+        release(app_state, file_id);
+    }
+    expired.len()
+}
+
+pub fn sync() -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(UPLOAD_DIR) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    for entry in entries.flatten() {
+        std::fs::File::open(entry.path())?.sync_all()?;
+    }
+    std::fs::File::open(UPLOAD_DIR)?.sync_all()
+}
+
+pub fn collect_garbage(app_state: &web::Data<AppState>) -> usize {
+    let _io = app_state.upload_io.write().unwrap();
+    let Ok(entries) = std::fs::read_dir(UPLOAD_DIR) else {
+        return 0;
+    };
+
+    let referenced: HashSet<String> = app_state.blobs.lock().unwrap().iter()
+        .filter(|(_, blob)| blob.references > 0)
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name == MANIFEST_FILE || (is_blob_name(&name) && referenced.contains(&name)) {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => removed += 1,
+            Err(error) => tracing::warn!(%name, %error, "failed to remove unreferenced file"),
+        }
+    }
+    if let Err(error) = persist(app_state) {
+        tracing::error!(%error, "failed to save upload manifest");
+    }
+    removed
+}
+
+pub fn rotate_keys(app_state: &web::Data<AppState>) -> usize {
+    let keyring = &app_state.config.encryption;
+    let Some(active) = keyring.active_id() else {
+        return 0;
+    };
+
+    let _io = app_state.upload_io.write().unwrap();
+    let stale: Vec<(String, Option<String>)> = app_state.blobs.lock().unwrap().iter()
+        .filter(|(_, blob)| blob.key_id.as_deref() != Some(active))
+        .map(|(hash, blob)| (hash.clone(), blob.key_id.clone()))
+        .collect();
+
+    let mut rotated = 0;
+    for (hash, key_id) in stale {
+        let path = blob_path(&hash);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(error) => {
+                tracing::warn!(%path, %error, "failed to read blob for key rotation");
+                continue;
+            },
+        };
+        let plain = match &key_id {
+            Some(key_id) => match crypto::decrypt(keyring, &data) {
+                Ok(plain) => plain,
+                Err(error) => {
+                    tracing::warn!(%path, %key_id, %error, "failed to decrypt blob for key rotation");
+                    continue;
+                },
+            },
+            None => data,
+        };
+        let result = crypto::encrypt(keyring, &plain)
+            .map_err(std::io::Error::other)
+            .and_then(|sealed| write_atomic(&path, &sealed));
+        match result {
+            Ok(()) => {
+                if let Some(blob) = app_state.blobs.lock().unwrap().get_mut(&hash) {
+                    blob.key_id = Some(active.to_string());
+                }
+                rotated += 1;
+            },
+            Err(error) => tracing::warn!(%path, %error, "failed to re-encrypt blob"),
+        }
+    }
+    if rotated > 0 {
+        if let Err(error) = persist(app_state) {
+            tracing::error!(%error, "failed to save upload manifest");
+        }
+    }
+    rotated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[actix_web::test]
+    async fn rejects_shell_scripts() {
+        let app_state = crate::test_state(Config::from_env());
+        let script = b"#!/bin/sh\nrm -rf ~\n".to_vec();
+        let error = store(&app_state, "alice", script).await.unwrap_err();
+        assert_eq!(error, "Тип файлу text/x-shellscript заборонено");
+        assert!(app_state.uploads.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn deletes_the_blob_and_persists_the_manifest_on_release() {
+        let app_state = crate::test_state(Config::from_env());
+        let data = uuid::Uuid::new_v4().to_string().repeat(8).into_bytes();
+        let (file_id, record) = store(&app_state, "alice", data).await.unwrap();
+        let path = blob_path(&record.hash);
+        assert!(std::path::Path::new(&path).exists());
+
+        release(&app_state, &file_id);
+        assert!(app_state.uploads.lock().unwrap().is_empty());
+        assert!(app_state.blobs.lock().unwrap().is_empty());
+        let released = || {
+            let manifest = std::fs::read_to_string(MANIFEST_PATH).unwrap_or_default();
+            !std::path::Path::new(&path).exists() && !manifest.contains(&file_id) && !manifest.contains(&record.hash)
+        };
+        for _ in 0..100 {
+            if released() {
+                break;
+            }
+            actix::clock::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(released());
+    }
+}
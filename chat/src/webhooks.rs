@@ -0,0 +1,310 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::web;
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use url::{Host, Url};
+use crate::models::*;
+use crate::AppState;
+use crate::groups;
+use crate::mentions;
+use crate::privacy;
+
+const MAX_ATTEMPTS: u32 = 4;
+const INITIAL_BACKOFF_MS: u64 = 500;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || (first == 100 && second & 0xc0 == 64))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            },
+        },
+    }
+}
+
+pub async fn resolve(url: &str, allow_private: bool) -> Result<SocketAddr, String> {
+    let invalid = || "Некоректна адреса вебхука".to_string();
+    let url = Url::parse(url).map_err(|_| invalid())?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid());
+    }
+    let port = url.port_or_known_default().ok_or_else(invalid)?;
+    let addresses: Vec<SocketAddr> = match url.host().ok_or_else(invalid)? {
+        Host::Ipv4(ip) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Host::Ipv6(ip) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        Host::Domain(domain) => tokio::net::lookup_host((domain, port)).await
+            .map_err(|_| "Не вдалося знайти адресу вебхука".to_string())?
+            .collect(),
+    };
+    addresses.into_iter()
+        .find(|address| allow_private || is_public(address.ip()))
+        .ok_or_else(|| "Вебхук не може вказувати на внутрішню адресу".to_string())
+}
+
+fn can_read(app_state: &web::Data<AppState>, owner: &str, conversation: &str) -> bool {
+    if conversation == "public" {
+        return true;
+    }
+    if conversation.starts_with('#') {
+        return app_state.rooms.lock().unwrap().get(conversation).is_some_and(|members| members.contains(owner));
+    }
+    if groups::is_group(conversation) {
+        return groups::participants(app_state, conversation, owner).is_ok();
+    }
+    conversation == owner
+}
+
+pub fn authorize(app_state: &web::Data<AppState>, owner: &str, room: Option<&str>, mention: Option<&str>) -> Result<(), String> {
+    if mention.is_some_and(|mention| mention != owner) {
+        return Err("Підписатися можна лише на власні згадки".to_string());
+    }
+    if room.is_some_and(|room| room == owner || !can_read(app_state, owner, room)) {
+        return Err("Ви не є учасником цієї розмови".to_string());
+    }
+    Ok(())
+}
+
+fn matches(webhook: &Webhook, from: &str, recipient: &str, mentions: &[String]) -> bool {
+    if webhook.owner == from {
+        return false;
+    }
+    if let Some(room) = &webhook.room {
+        if room == recipient && *room != webhook.owner {
+            return true;
+        }
+    }
+    if let Some(mention) = &webhook.mention {
+        if *mention == webhook.owner && (recipient == mention || mentions::is_mentioned(mentions, mention)) {
+            return true;
+        }
+    }
+    false
+}
+
+pub fn dispatch(app_state: &web::Data<AppState>, from: &str, recipient: &str, content: &str, mentions: &[String]) {
+    let targets: Vec<Webhook> = {
+        let webhooks = app_state.webhooks.lock().unwrap();
+        webhooks.values()
+            .filter(|webhook| matches(webhook, from, recipient, mentions))
+            .cloned()
+            .collect()
+    };
+    let targets: Vec<Webhook> = targets.into_iter()
+        .filter(|webhook| can_read(app_state, &webhook.owner, recipient) && !privacy::has_blocked(app_state, &webhook.owner, from))
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for webhook in targets {
+        let payload = json!({
+            "type": "message",
+            "webhookId": webhook.id,
+            "from": from,
+            "recipient": recipient,
+            "content": content,
+            "mentions": mentions,
+            "timestamp": timestamp
+        }).to_string();
+        actix::spawn(deliver(webhook, payload, app_state.config.webhook_allow_private));
+    }
+}
+
+async fn deliver(webhook: Webhook, payload: String, allow_private: bool) {
+    let address = match resolve(&webhook.url, allow_private).await {
+        Ok(address) => address,
+        Err(error) => {
+            tracing::warn!(webhook_id = %webhook.id, %error, "webhook target rejected");
+            return;
+        },
+    };
+    let client = awc::Client::default();
+    let signature = sign(&webhook.secret, &payload);
+    let mut backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client.post(&webhook.url)
+            .address(address)
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("X-Webhook-Signature", signature.clone()))
+            .send_body(payload.clone())
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                tracing::debug!(webhook_id = %webhook.id, attempt, "webhook delivered");
+                return;
+            },
+            Ok(response) if response.status().is_client_error() && response.status().as_u16() != 429 => {
+                tracing::warn!(webhook_id = %webhook.id, status = response.status().as_u16(), "webhook rejected delivery");
+                return;
+            },
+            _ if attempt == MAX_ATTEMPTS => {
+                tracing::warn!(webhook_id = %webhook.id, attempts = attempt, "webhook delivery failed");
+                return;
+            },
+            _ => {
+                actix::clock::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpRequest, HttpResponse, HttpServer};
+    use tokio::sync::mpsc;
+    use crate::config::Config;
+    use crate::handlers::create_webhook;
+
+    type Received = mpsc::UnboundedSender<(String, String)>;
+
+    async fn receive(req: HttpRequest, body: String, received: web::Data<Received>) -> HttpResponse {
+        let signature = req.headers().get("X-Webhook-Signature")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let _ = received.send((signature, body));
+        HttpResponse::Ok().finish()
+    }
+
+    fn receiver() -> (String, mpsc::UnboundedReceiver<(String, String)>) {
+        let (sender, received) = mpsc::unbounded_channel::<(String, String)>();
+        let sender = web::Data::new(sender);
+        let server = HttpServer::new(move || App::new().app_data(sender.clone()).route("/hook", web::post().to(receive)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        (format!("http://{}/hook", address), received)
+    }
+
+    fn webhook(owner: &str, url: &str, room: Option<&str>, mention: Option<&str>) -> Webhook {
+        Webhook {
+            id: "hook".to_string(),
+            owner: owner.to_string(),
+            url: url.to_string(),
+            room: room.map(str::to_string),
+            mention: mention.map(str::to_string),
+            secret: "s3cret".to_string(),
+        }
+    }
+
+    fn allow_private() -> web::Data<AppState> {
+        let mut config = Config::from_env();
+        config.webhook_allow_private = true;
+        crate::test_state(config)
+    }
+
+    #[actix_web::test]
+    async fn delivers_signed_payloads_to_subscribers() {
+        let (url, mut received) = receiver();
+        let app_state = allow_private();
+        app_state.webhooks.lock().unwrap().insert("hook".to_string(), webhook("bob", &url, Some("public"), None));
+
+        dispatch(&app_state, "alice", "public", "привіт", &[]);
+        dispatch(&app_state, "bob", "public", "own message", &[]);
+
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), received.recv()).await
+            .expect("webhook was not delivered")
+            .unwrap();
+        assert_eq!(signature, sign("s3cret", &body));
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["webhookId"], "hook");
+        assert_eq!(payload["from"], "alice");
+        assert_eq!(payload["content"], "привіт");
+        assert!(tokio::time::timeout(Duration::from_millis(200), received.recv()).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn refuses_subscriptions_to_other_peoples_conversations() {
+        let app_state = allow_private();
+        app_state.sessions.lock().unwrap().insert("token".to_string(), "mallory".to_string());
+        app_state.rooms.lock().unwrap().insert("#secret".to_string(), ["alice".to_string()].into_iter().collect());
+        let app = test::init_service(App::new().app_data(app_state.clone()).route("/webhooks", web::post().to(create_webhook))).await;
+
+        for (room, mention) in [(None, Some("alice")), (Some("alice"), None), (Some("mallory"), None), (Some("#secret"), None), (Some("group:missing"), None)] {
+            let request = test::TestRequest::post().uri("/webhooks").set_json(json!({
+                "token": "token",
+                "url": "http://127.0.0.1:9/hook",
+                "room": room,
+                "mention": mention
+            })).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?} {:?}", room, mention);
+        }
+        assert!(app_state.webhooks.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn never_delivers_conversations_the_owner_cannot_read() {
+        let (url, mut received) = receiver();
+        let app_state = allow_private();
+        app_state.rooms.lock().unwrap().insert("#secret".to_string(), ["alice".to_string()].into_iter().collect());
+        let subscriptions = [(Some("alice"), None), (None, Some("alice")), (Some("#secret"), None), (None, Some("mallory"))];
+        for (index, (room, mention)) in subscriptions.into_iter().enumerate() {
+            let mut webhook = webhook("mallory", &url, room, mention);
+            webhook.id = index.to_string();
+            app_state.webhooks.lock().unwrap().insert(webhook.id.clone(), webhook);
+        }
+
+        dispatch(&app_state, "bob", "alice", "private", &[]);
+        dispatch(&app_state, "bob", "#secret", "@mallory secret", &["mallory".to_string()]);
+        assert!(tokio::time::timeout(Duration::from_millis(300), received.recv()).await.is_err());
+
+        dispatch(&app_state, "bob", "mallory", "for you", &[]);
+        let (_, body) = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        assert!(body.contains("for you"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_private_targets() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.1.2.3/",
+            "http://192.168.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            assert!(resolve(url, false).await.is_err(), "{} was accepted", url);
+        }
+        assert!(resolve("ftp://93.184.215.14/", false).await.is_err());
+        assert_eq!(resolve("https://93.184.215.14/hook", false).await, Ok("93.184.215.14:443".parse().unwrap()));
+        assert!(resolve("http://127.0.0.1:8080/hook", true).await.is_ok());
+    }
+}
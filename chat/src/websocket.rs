@@ -1,65 +1,137 @@
+use std::sync::Arc;
+use std::time::Duration;
 use actix::prelude::*;
 use actix_web::web;
 use actix_web_actors::ws;
 use serde_json::json;
 use crate::models::*;
 use crate::AppState;
+use crate::webhooks;
+use crate::mentions;
+use crate::threads;
+use crate::groups;
+use crate::uploads;
+use crate::profiles;
+use crate::keys;
+use crate::history;
+use crate::shutdown;
+use crate::cluster::{self, ClusterEvent};
+use crate::privacy::{self, Delivery};
+use crate::commands::{self, CommandRegistry};
+use crate::outbox::{Connection, Outbox};
+use crate::telemetry;
 
-#[derive(Message)]
-#[rtype(result = "()")]
 pub struct BroadcastMessage(pub String);
 
-#[derive(Message)]
-#[rtype(result = "()")]
 pub struct PrivateMessage {
-    pub from: String,
     pub content: String,
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
 pub struct UserConnected {
     pub username: String,
 }
 
+pub struct UserDisconnected {
+    pub username: String,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct UserDisconnected {
+pub struct Flush;
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Renamed {
     pub username: String,
 }
 
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub reason: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub reconnect_after: Duration,
+}
+
 pub struct ChatSession {
     pub username: String,
+    pub token: Option<String>,
     pub app_state: web::Data<AppState>,
+    pub commands: Arc<CommandRegistry>,
+    pub outbox: Arc<Outbox>,
+    pub span: tracing::Span,
+}
+
+impl ChatSession {
+    pub fn new(app_state: &web::Data<AppState>, username: &str, token: Option<&str>) -> Self {
+        let connection_id = telemetry::new_id();
+        ChatSession {
+            username: username.to_string(),
+            token: token.map(str::to_string),
+            app_state: app_state.clone(),
+            commands: app_state.commands.clone(),
+            outbox: Arc::new(Outbox::new(
+                app_state.config.outbound_queue_capacity,
+                app_state.config.outbound_overflow,
+                app_state.outbound_metrics.clone(),
+            )),
+            span: tracing::info_span!(parent: None, "session", %connection_id, username = %username),
+        }
+    }
 }
 
 impl Actor for ChatSession {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::info!("session started");
+        self.app_state.metrics.session_opened();
         let addr = ctx.address();
         let username = self.username.clone();
         {
             let mut connections = self.app_state.connections.lock().unwrap();
-            connections.insert(username.clone(), addr);
+            connections.insert(username.clone(), Connection::new(addr, self.outbox.clone(), self.token.clone()));
         }
 
-        let connections = self.app_state.connections.lock().unwrap();
-        for (user, addr) in connections.iter() {
-            if user != &username {
-                addr.do_send(UserConnected {
-                    username: username.clone(),
-                });
+        if privacy::is_hidden(&self.app_state, &username) {
+            return;
+        }
+
+        {
+            let connections = self.app_state.connections.lock().unwrap();
+            for (user, addr) in connections.iter() {
+                if user != &username {
+                    addr.do_send(UserConnected {
+                        username: username.clone(),
+                    });
+                }
             }
         }
+        cluster::announce(&self.app_state, &username, true);
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::info!("session stopped");
+        self.app_state.metrics.session_closed();
         let username = self.username.clone();
         {
             let mut connections = self.app_state.connections.lock().unwrap();
             connections.remove(&username);
         }
+        self.app_state.away.lock().unwrap().remove(&username);
+        if privacy::is_hidden(&self.app_state, &username) {
+            return;
+        }
+        cluster::announce(&self.app_state, &username, false);
+        if shutdown::is_shutting_down(&self.app_state) {
+            return;
+        }
 
         let connections = self.app_state.connections.lock().unwrap();
         for (_user, addr) in connections.iter() {
@@ -70,59 +142,88 @@ impl Actor for ChatSession {
     }
 }
 
-impl Handler<BroadcastMessage> for ChatSession {
+impl Handler<Flush> for ChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: BroadcastMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, _: Flush, ctx: &mut Self::Context) {
+        for message in self.outbox.drain() {
+            ctx.text(message);
+        }
     }
 }
 
-impl Handler<PrivateMessage> for ChatSession {
+impl Handler<Renamed> for ChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: PrivateMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.content);
+    fn handle(&mut self, msg: Renamed, _: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::info!(new_username = %msg.username, "session renamed");
+        self.span.record("username", msg.username.as_str());
+        self.username = msg.username;
     }
 }
 
-impl Handler<UserConnected> for ChatSession {
+impl Handler<Disconnect> for ChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: UserConnected, ctx: &mut Self::Context) {
-        let notification = json!({
-            "type": "user_connected",
-            "username": msg.username
-        }).to_string();
-        ctx.text(notification);
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::warn!(reason = %msg.reason, "disconnecting session");
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
     }
 }
 
-impl Handler<UserDisconnected> for ChatSession {
+impl Handler<Shutdown> for ChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: UserDisconnected, ctx: &mut Self::Context) {
-        let notification = json!({
-            "type": "user_disconnected",
-            "username": msg.username
-        }).to_string();
-        ctx.text(notification);
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::debug!("closing session for shutdown");
+        for message in self.outbox.drain() {
+            ctx.text(message);
+        }
+        ctx.text(json!({
+            "type": "server_shutdown",
+            "message": "Сервер перезапускається",
+            "reconnectAfter": msg.reconnect_after.as_secs()
+        }).to_string());
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Restart,
+            description: Some("Сервер перезапускається".to_string()),
+        }));
+        ctx.stop();
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Text(text)) => {
                 let parsed: serde_json::Result<ClientMessage> = serde_json::from_str(&text);
                 match parsed {
                     Ok(client_msg) => {
+                        let is_command = client_msg.msg_type == "message" && commands::is_command(client_msg.content.as_deref().unwrap_or_default());
+                        self.app_state.metrics.record_message(if is_command { "command" } else { &client_msg.msg_type });
                         if client_msg.msg_type == "file" {
                             self.handle_file_message(client_msg, ctx);
-                        } else if client_msg.msg_type == "message" {
-                            self.handle_text_message(client_msg, ctx);
+                        } else if client_msg.msg_type == "avatar" {
+                            self.handle_avatar_message(client_msg, ctx);
+                        } else if client_msg.msg_type == "reaction_add" || client_msg.msg_type == "reaction_remove" {
+                            self.handle_reaction(client_msg, ctx);
+                        } else if client_msg.msg_type == "message" || client_msg.msg_type == "encrypted" {
+                            if is_command {
+                                self.handle_command(client_msg, ctx);
+                            } else {
+                                self.handle_text_message(client_msg, ctx);
+                            }
                         } else {
+                            tracing::debug!(msg_type = %client_msg.msg_type, "unknown message type");
                             let error = ErrorMessage {
                                 msg_type: "error".to_string(),
                                 message: "Невідомий тип повідомлення".to_string(),
@@ -130,7 +231,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
                             ctx.text(json!(error).to_string());
                         }
                     },
-                    Err(_) => {
+                    Err(error) => {
+                        self.app_state.metrics.record_message("invalid");
+                        tracing::debug!(%error, "malformed message");
                         let error = ErrorMessage {
                             msg_type: "error".to_string(),
                             message: "Невідомий формат повідомлення".to_string(),
@@ -140,6 +243,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
                 }
             },
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Err(error) => tracing::warn!(%error, "websocket protocol error"),
             _ => (),
         }
     }
@@ -148,109 +252,432 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSession {
 impl ChatSession {
     pub fn handle_text_message(&mut self, client_msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
         let recipient = client_msg.recipient.clone();
-        let content = client_msg.content.clone().unwrap_or_default();
-
-        if recipient == "public" {
-            let message = json!({
-                "type": "public",
-                "from": self.username,
-                "content": content
-            }).to_string();
-
-            {
-                let mut messages = self.app_state.messages.lock().unwrap();
-                for user in self.app_state.users.lock().unwrap().keys() {
-                    let user_history = messages.entry(user.clone()).or_insert(Vec::new());
-                    user_history.push(format!("{}: {}", self.username, content));
-                }
-            }
+        let content = commands::unescape(client_msg.content.as_deref().unwrap_or_default()).to_string();
+
+        let reply_to = client_msg.reply_to.as_deref();
+
+        let route = if client_msg.msg_type == "encrypted" {
+            "encrypted"
+        } else if recipient == "public" {
+            "public"
+        } else if recipient.starts_with('#') {
+            "room"
+        } else if groups::is_group(&recipient) {
+            "group"
+        } else {
+            "private"
+        };
+        let result = if client_msg.msg_type == "encrypted" {
+            keys::validate_ciphertext(client_msg.ciphertext.clone())
+                .and_then(|ciphertext| self.send_encrypted(&recipient, &ciphertext, ctx))
+        } else if recipient == "public" {
+            self.send_public(&content, reply_to)
+        } else if recipient.starts_with('#') {
+            self.send_room(&recipient, &content, reply_to)
+        } else if groups::is_group(&recipient) {
+            self.send_group(&recipient, &content, reply_to)
+        } else {
+            self.send_private(&recipient, &content, reply_to, ctx)
+        };
 
-            let connections = self.app_state.connections.lock().unwrap();
-            for (_user, addr) in connections.iter() {
-                addr.do_send(BroadcastMessage(message.clone()));
+        match result {
+            Ok(()) => tracing::debug!(to = %recipient, route, "message routed"),
+            Err(message) => {
+                tracing::info!(to = %recipient, route, error = %message, "message rejected");
+                let error = ErrorMessage {
+                    msg_type: "error".to_string(),
+                    message,
+                };
+                ctx.text(json!(error).to_string());
+            },
+        }
+    }
+
+    pub fn send_public(&mut self, content: &str, reply_to: Option<&str>) -> Result<(), String> {
+        let stored = threads::record(&self.app_state, &self.username, "public", content, reply_to)?;
+        let mentions = mentions::parse_mentions(content);
+        let message = json!({
+            "type": "public",
+            "id": stored.id,
+            "from": self.username,
+            "content": content,
+            "mentions": mentions,
+            "replyTo": stored.reply_to,
+            "threadId": stored.thread_id
+        }).to_string();
+
+        history::push_shared(&self.app_state, HistoryEntry::new(history::PUBLIC, &self.username, format!("{}: {}", self.username, content)));
+        cluster::broadcast(&self.app_state, &message);
+
+        let audience = mentions::audience(&self.app_state, &mentions);
+        mentions::track(&self.app_state, &self.username, &audience, history::PUBLIC, content, &mentions);
+        webhooks::dispatch(&self.app_state, &self.username, "public", content, &mentions);
+        Ok(())
+    }
+
+    pub fn send_room(&mut self, room: &str, content: &str, reply_to: Option<&str>) -> Result<(), String> {
+        let members: Vec<String> = {
+            let rooms = self.app_state.rooms.lock().unwrap();
+            match rooms.get(room) {
+                Some(members) if members.contains(&self.username) => members.iter().cloned().collect(),
+                _ => return Err("Ви не є учасником цієї кімнати".to_string()),
             }
+        };
+
+        let stored = threads::record(&self.app_state, &self.username, room, content, reply_to)?;
+        let mentions = mentions::parse_mentions(content);
+        let message = json!({
+            "type": "room",
+            "id": stored.id,
+            "room": room,
+            "from": self.username,
+            "content": content,
+            "mentions": mentions,
+            "replyTo": stored.reply_to,
+            "threadId": stored.thread_id
+        }).to_string();
+
+        history::push_shared(&self.app_state, HistoryEntry::new(room, &self.username, format!("[{}] {}: {}", room, self.username, content)));
+
+        cluster::send_to_room(&self.app_state, room, &members, &message);
+
+        mentions::track(&self.app_state, &self.username, &members, room, content, &mentions);
+        webhooks::dispatch(&self.app_state, &self.username, room, content, &mentions);
+        Ok(())
+    }
+
+    pub fn send_group(&mut self, group_id: &str, content: &str, reply_to: Option<&str>) -> Result<(), String> {
+        let participants = groups::participants(&self.app_state, group_id, &self.username)?;
+
+        let stored = threads::record(&self.app_state, &self.username, group_id, content, reply_to)?;
+        let mentions = mentions::parse_mentions(content);
+        let message = json!({
+            "type": "group",
+            "id": stored.id,
+            "group": group_id,
+            "from": self.username,
+            "content": content,
+            "mentions": mentions,
+            "replyTo": stored.reply_to,
+            "threadId": stored.thread_id
+        }).to_string();
+
+        let audience = groups::audience(&self.app_state, &participants, &self.username);
+        groups::push_history(&self.app_state, group_id, &self.username, format!("{}: {}", self.username, content));
+        groups::broadcast(&self.app_state, &audience, &message);
+
+        mentions::track(&self.app_state, &self.username, &audience, group_id, content, &mentions);
+        webhooks::dispatch(&self.app_state, &self.username, group_id, content, &mentions);
+        Ok(())
+    }
+
+    pub fn send_private(&mut self, to: &str, content: &str, reply_to: Option<&str>, ctx: &mut ws::WebsocketContext<Self>) -> Result<(), String> {
+        let is_remote = cluster::is_remote(&self.app_state, to);
+        if !is_remote && !self.user_exists(to) {
+            return Err("Користувач не знайдений".to_string());
+        }
+        let delivery = privacy::direct_delivery(&self.app_state, to, &self.username);
+        if let Delivery::Refuse = delivery {
+            return Err("Користувач приймає повідомлення лише від контактів".to_string());
+        }
+
+        let stored = threads::record(&self.app_state, &self.username, to, content, reply_to)?;
+        let mentions = mentions::parse_mentions(content);
+        let private_msg = json!({
+            "type": "private",
+            "id": stored.id,
+            "from": self.username,
+            "to": to,
+            "content": content,
+            "mentions": mentions,
+            "replyTo": stored.reply_to,
+            "threadId": stored.thread_id
+        }).to_string();
+
+        ctx.text(private_msg.clone());
+
+        history::push(&self.app_state, &self.username, HistoryEntry::new(to, &self.username, format!("До {}: {}", to, content)));
+
+        if let Delivery::Drop = delivery {
+            return Ok(());
+        }
+
+        if is_remote {
+            cluster::publish(&self.app_state, ClusterEvent::Direct {
+                from: self.username.clone(),
+                to: to.to_string(),
+                content: content.to_string(),
+                mentions: mentions.clone(),
+                message: private_msg,
+            });
         } else {
-            let to = recipient.clone();
-            let connections = self.app_state.connections.lock().unwrap();
+            if let Some(addr) = self.app_state.connections.lock().unwrap().get(to) {
+                addr.do_send(PrivateMessage { content: private_msg.clone() });
+            }
 
-            if let Some(addr) = connections.get(&to) {
-                let private_msg = json!({
-                    "type": "private",
-                    "from": self.username,
-                    "content": content
-                }).to_string();
+            history::push(&self.app_state, to, HistoryEntry::new(&self.username, &self.username, format!("Від {}: {}", self.username, content)));
+            mentions::track(&self.app_state, &self.username, &[to.to_string()], &self.username, content, &mentions);
+        }
+        webhooks::dispatch(&self.app_state, &self.username, to, content, &mentions);
+        Ok(())
+    }
 
-                addr.do_send(PrivateMessage { from: self.username.clone(), content: private_msg.clone() });
+    pub fn send_encrypted(&mut self, to: &str, ciphertext: &str, ctx: &mut ws::WebsocketContext<Self>) -> Result<(), String> {
+        if to == "public" || to.starts_with('#') || groups::is_group(to) {
+            return Err("Зашифровані повідомлення підтримуються лише в особистих чатах".to_string());
+        }
+        let is_remote = cluster::is_remote(&self.app_state, to);
+        if !is_remote && !self.user_exists(to) {
+            return Err("Користувач не знайдений".to_string());
+        }
+        if !is_remote && !keys::has_keys(&self.app_state, to) {
+            return Err("Користувач не опублікував ключі шифрування".to_string());
+        }
+        let delivery = privacy::direct_delivery(&self.app_state, to, &self.username);
+        if let Delivery::Refuse = delivery {
+            return Err("Користувач приймає повідомлення лише від контактів".to_string());
+        }
+        let recipient = self.app_state.connections.lock().unwrap().get(to).cloned();
+        if !is_remote && recipient.is_none() {
+            return Err("Користувач не в мережі, зашифроване повідомлення не доставлено".to_string());
+        }
 
-                ctx.text(private_msg.clone());
+        let encrypted_msg = json!({
+            "type": "encrypted",
+            "id": uuid::Uuid::new_v4().to_string(),
+            "from": self.username,
+            "to": to,
+            "ciphertext": ciphertext
+        }).to_string();
 
-                {
-                    let mut messages = self.app_state.messages.lock().unwrap();
-                    let sender_history = messages.entry(self.username.clone()).or_insert(Vec::new());
-                    sender_history.push(format!("До {}: {}", to, content));
-                    let recipient_history = messages.entry(to.clone()).or_insert(Vec::new());
-                    recipient_history.push(format!("Від {}: {}", self.username, content));
-                }
-            } else {
+        ctx.text(encrypted_msg.clone());
+
+        if let Delivery::Drop = delivery {
+            return Ok(());
+        }
+
+        match recipient {
+            Some(addr) => {
+                addr.do_send(PrivateMessage { content: encrypted_msg });
+                mentions::track(&self.app_state, &self.username, &[to.to_string()], &self.username, "", &[]);
+            },
+            None => cluster::publish(&self.app_state, ClusterEvent::Encrypted {
+                from: self.username.clone(),
+                to: to.to_string(),
+                message: encrypted_msg,
+            }),
+        }
+        Ok(())
+    }
+
+    pub fn handle_reaction(&mut self, client_msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let add = client_msg.msg_type == "reaction_add";
+        let message_id = client_msg.message_id.unwrap_or_default();
+        let emoji = client_msg.emoji.unwrap_or_default();
+
+        let message = match threads::react(&self.app_state, &self.username, &message_id, &emoji, add) {
+            Ok(message) => message,
+            Err(message) => {
                 let error = ErrorMessage {
                     msg_type: "error".to_string(),
-                    message: "Користувач не знайдений".to_string(),
+                    message,
                 };
                 ctx.text(json!(error).to_string());
+                return;
             }
+        };
+
+        let event = json!({
+            "type": "reaction",
+            "messageId": message.id,
+            "emoji": emoji,
+            "username": self.username,
+            "action": if add { "add" } else { "remove" },
+            "reactions": message.reaction_counts()
+        }).to_string();
+
+        if message.recipient == "public" {
+            cluster::broadcast(&self.app_state, &event);
+        } else if message.recipient.starts_with('#') {
+            cluster::send_to_room(&self.app_state, &message.recipient, &threads::viewers(&self.app_state, &message), &event);
+        } else {
+            cluster::send_to(&self.app_state, &threads::viewers(&self.app_state, &message), &event);
         }
     }
 
-    pub fn handle_file_message(&mut self, client_msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
-        if let Some(data) = client_msg.data {
-            let recipient = client_msg.recipient.clone();
-            let filename = client_msg.filename.clone().unwrap_or_default();
-
-            let file_id = uuid::Uuid::new_v4().to_string();
-            let file_path = format!("uploads/{}", file_id);
-            std::fs::create_dir_all("uploads").unwrap();
-            std::fs::write(&file_path, data).unwrap();
-
-            let metadata_message = json!({
-                "type": "file",
-                "from": self.username,
-                "fileId": file_id,
-                "filename": filename
-            }).to_string();
-
-            if recipient == "public" {
-                let connections = self.app_state.connections.lock().unwrap();
-                for (_user, addr) in connections.iter() {
-                    addr.do_send(BroadcastMessage(metadata_message.clone()));
-                }
-            } else {
-                let connections = self.app_state.connections.lock().unwrap();
-                if let Some(addr) = connections.get(&recipient) {
-                    addr.do_send(PrivateMessage { from: self.username.clone(), content: metadata_message.clone() });
-
-                    ctx.text(metadata_message.clone());
-
-                    {
-                        let mut messages = self.app_state.messages.lock().unwrap();
-                        let sender_history = messages.entry(self.username.clone()).or_insert(Vec::new());
-                        sender_history.push(format!("До {}: Надіслав файл '{}'", recipient, filename));
-                        let recipient_history = messages.entry(recipient.clone()).or_insert(Vec::new());
-                        recipient_history.push(format!("Від {}: Отримано файл '{}'", self.username, filename));
-                    }
-                } else {
-                    let error = ErrorMessage {
-                        msg_type: "error".to_string(),
-                        message: "Користувач не знайдений".to_string(),
-                    };
-                    ctx.text(json!(error).to_string());
+    pub fn handle_command(&mut self, client_msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let line = client_msg.content.clone().unwrap_or_default();
+        let line = line.trim_start_matches('/');
+        let (name, args) = match line.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, args.trim()),
+            None => (line, ""),
+        };
+
+        let commands = self.commands.clone();
+        let reply = match commands.get(name) {
+            Some(command) => match command.execute(self, &client_msg.recipient, args, ctx) {
+                Ok(result) => json!({
+                    "type": "command_result",
+                    "command": name,
+                    "result": result
+                }),
+                Err(message) => json!({
+                    "type": "command_error",
+                    "command": name,
+                    "message": message
+                }),
+            },
+            None => json!({
+                "type": "command_error",
+                "command": name,
+                "message": "Невідома команда, введіть /help"
+            }),
+        };
+        ctx.text(reply.to_string());
+    }
+
+    fn send_error(ctx: &mut ws::WebsocketContext<Self>, message: String) {
+        tracing::info!(error = %message, "request rejected");
+        let error = ErrorMessage {
+            msg_type: "error".to_string(),
+            message,
+        };
+        ctx.text(json!(error).to_string());
+    }
+
+    pub fn handle_avatar_message(&mut self, client_msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let data = match client_msg.data {
+            None => return Self::send_error(ctx, "Не вибрано жодного файлу".to_string()),
+            Some(data) if data.len() > profiles::MAX_AVATAR_BYTES => return Self::send_error(ctx, "Аватар завеликий".to_string()),
+            Some(data) => data,
+        };
+
+        let app_state = self.app_state.clone();
+        let username = self.username.clone();
+        let upload = async move { uploads::store(&app_state, &username, data).await };
+        ctx.wait(upload.into_actor(self).map(|stored, session, ctx| session.set_avatar(stored, ctx)));
+    }
+
+    fn set_avatar(&mut self, stored: Result<(String, UploadRecord), String>, ctx: &mut ws::WebsocketContext<Self>) {
+        let file_id = match stored {
+            Ok((file_id, record)) if record.width.is_some() => {
+                self.app_state.metrics.record_upload(record.size);
+                file_id
+            },
+            Ok((file_id, _)) => {
+                uploads::release(&self.app_state, &file_id);
+                return Self::send_error(ctx, "Аватар має бути зображенням".to_string());
+            },
+            Err(message) => return Self::send_error(ctx, message),
+        };
+        let mut previous = None;
+        let result = profiles::update(&self.app_state, &self.username, |profile| {
+            previous = profile.avatar.replace(file_id.clone());
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => {
+                if let Some(previous) = previous {
+                    uploads::release(&self.app_state, &previous);
                 }
-            }
+            },
+            Err(message) => {
+                uploads::release(&self.app_state, &file_id);
+                Self::send_error(ctx, message);
+            },
+        }
+    }
+
+    pub fn handle_file_message(&mut self, client_msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(data) = client_msg.data else {
+            return Self::send_error(ctx, "Не вибрано жодного файлу".to_string());
+        };
+        let recipient = client_msg.recipient.clone();
+        let filename = client_msg.filename.clone().unwrap_or_default();
+
+        let app_state = self.app_state.clone();
+        let username = self.username.clone();
+        let upload = async move { uploads::store(&app_state, &username, data).await };
+        ctx.wait(upload.into_actor(self).map(move |stored, session, ctx| session.send_file(stored, recipient, filename, ctx)));
+    }
+
+    fn send_file(&mut self, stored: Result<(String, UploadRecord), String>, recipient: String, filename: String, ctx: &mut ws::WebsocketContext<Self>) {
+        let (file_id, record) = match stored {
+            Ok(stored) => stored,
+            Err(message) => return Self::send_error(ctx, message),
+        };
+        self.app_state.metrics.record_upload(record.size);
+
+        let metadata_message = json!({
+            "type": "file",
+            "from": self.username,
+            "to": recipient,
+            "fileId": file_id,
+            "filename": filename,
+            "contentType": record.content_type,
+            "size": record.size,
+            "width": record.width,
+            "height": record.height,
+            "thumbnailId": record.thumbnail
+        }).to_string();
+
+        let result = if recipient == "public" {
+            cluster::broadcast(&self.app_state, &metadata_message);
+            Ok(())
+        } else if groups::is_group(&recipient) {
+            groups::participants(&self.app_state, &recipient, &self.username).map(|participants| {
+                groups::push_history(&self.app_state, &recipient, &self.username, format!("{}: Надіслав файл '{}'", self.username, filename));
+                groups::deliver(&self.app_state, &participants, &self.username, &metadata_message);
+            })
         } else {
-            let error = ErrorMessage {
-                msg_type: "error".to_string(),
-                message: "Не вибрано жодного файлу".to_string(),
-            };
-            ctx.text(json!(error).to_string());
+            self.send_private_file(&recipient, &filename, &metadata_message, ctx)
+        };
+
+        match result {
+            Ok(()) => {
+                threads::record_file(&self.app_state, &self.username, &recipient, Attachment {
+                    file_id,
+                    filename,
+                    content_type: record.content_type,
+                    size: record.size,
+                    width: record.width,
+                    height: record.height,
+                });
+            },
+            Err(message) => {
+                uploads::release(&self.app_state, &file_id);
+                Self::send_error(ctx, message);
+            },
+        }
+    }
+
+    fn user_exists(&self, username: &str) -> bool {
+        self.app_state.users.lock().unwrap().contains_key(username)
+    }
+
+    fn send_private_file(&mut self, recipient: &str, filename: &str, metadata_message: &str, ctx: &mut ws::WebsocketContext<Self>) -> Result<(), String> {
+        if !self.user_exists(recipient) {
+            return Err("Користувач не знайдений".to_string());
+        }
+        let delivery = privacy::direct_delivery(&self.app_state, recipient, &self.username);
+        if let Delivery::Refuse = delivery {
+            return Err("Користувач приймає повідомлення лише від контактів".to_string());
+        }
+
+        let delivered = matches!(delivery, Delivery::Deliver);
+        if delivered {
+            if let Some(addr) = self.app_state.connections.lock().unwrap().get(recipient) {
+                addr.do_send(PrivateMessage { content: metadata_message.to_string() });
+            }
+        }
+
+        ctx.text(metadata_message.to_string());
+
+        history::push(&self.app_state, &self.username, HistoryEntry::new(recipient, &self.username, format!("До {}: Надіслав файл '{}'", recipient, filename)));
+        if delivered {
+            history::push(&self.app_state, recipient, HistoryEntry::new(&self.username, &self.username, format!("Від {}: Отримано файл '{}'", self.username, filename)));
         }
+        Ok(())
     }
 }
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::path::Path;
+use chat_client::{Client, Connection, Event, Sender, ServerEvent, Session};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use futures_util::StreamExt;
+
+pub const PUBLIC: &str = "public";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Field {
+    Server,
+    Username,
+    Password,
+}
+
+pub struct LoginForm {
+    pub server: String,
+    pub username: String,
+    pub password: String,
+    pub focus: Field,
+    pub status: Option<String>,
+}
+
+impl LoginForm {
+    fn field(&mut self) -> &mut String {
+        match self.focus {
+            Field::Server => &mut self.server,
+            Field::Username => &mut self.username,
+            Field::Password => &mut self.password,
+        }
+    }
+
+    fn next(&mut self) {
+        self.focus = match self.focus {
+            Field::Server => Field::Username,
+            Field::Username => Field::Password,
+            Field::Password => Field::Server,
+        };
+    }
+
+    fn previous(&mut self) {
+        self.focus = match self.focus {
+            Field::Server => Field::Password,
+            Field::Username => Field::Server,
+            Field::Password => Field::Username,
+        };
+    }
+}
+
+pub struct Conversation {
+    pub key: String,
+    pub online: bool,
+    pub lines: Vec<String>,
+    pub unread: usize,
+    last_file: Option<String>,
+}
+
+impl Conversation {
+    fn new(key: &str) -> Self {
+        Conversation {
+            key: key.to_string(),
+            online: false,
+            lines: Vec::new(),
+            unread: 0,
+            last_file: None,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        if self.key == PUBLIC { "Загальний чат" } else { &self.key }
+    }
+}
+
+pub struct App {
+    pub login: LoginForm,
+    pub session: Option<Session>,
+    connection: Option<Connection>,
+    sender: Option<Sender>,
+    pub conversations: Vec<Conversation>,
+    pub selected: usize,
+    pub input: String,
+    pub status: String,
+    pub connected: bool,
+    pub scroll: usize,
+    files: HashMap<String, String>,
+    pub quit: bool,
+}
+
+impl App {
+    pub fn new(server: String) -> Self {
+        App {
+            login: LoginForm {
+                server,
+                username: String::new(),
+                password: String::new(),
+                focus: Field::Username,
+                status: None,
+            },
+            session: None,
+            connection: None,
+            sender: None,
+            conversations: vec![Conversation::new(PUBLIC)],
+            selected: 0,
+            input: String::new(),
+            status: String::new(),
+            connected: false,
+            scroll: 0,
+            files: HashMap::new(),
+            quit: false,
+        }
+    }
+
+    pub fn current(&self) -> &Conversation {
+        &self.conversations[self.selected]
+    }
+
+    fn username(&self) -> &str {
+        self.session.as_ref().map(|session| session.username()).unwrap_or_default()
+    }
+
+    fn conversation(&mut self, key: &str) -> &mut Conversation {
+        let index = match self.conversations.iter().position(|conversation| conversation.key == key) {
+            Some(index) => index,
+            None => {
+                self.conversations.push(Conversation::new(key));
+                self.conversations.len() - 1
+            },
+        };
+        &mut self.conversations[index]
+    }
+
+    fn push_line(&mut self, key: &str, line: String) {
+        let selected = self.current().key == key;
+        let conversation = self.conversation(key);
+        conversation.lines.push(line);
+        if !selected {
+            conversation.unread += 1;
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        self.selected = index;
+        self.scroll = 0;
+        self.conversations[index].unread = 0;
+    }
+
+    pub async fn next_event(&mut self) -> Option<Event> {
+        match self.connection.as_mut() {
+            Some(connection) => connection.next().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    pub async fn on_key(&mut self, key: KeyEvent) {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.quit = true;
+            return;
+        }
+        if self.session.is_some() {
+            self.on_chat_key(key).await;
+        } else {
+            self.on_login_key(key).await;
+        }
+    }
+
+    async fn on_login_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Tab | KeyCode::Down => self.login.next(),
+            KeyCode::BackTab | KeyCode::Up => self.login.previous(),
+            KeyCode::Enter => self.sign_in(false).await,
+            KeyCode::F(2) => self.sign_in(true).await,
+            KeyCode::Backspace => {
+                self.login.field().pop();
+            },
+            KeyCode::Char(c) => self.login.field().push(c),
+            _ => {},
+        }
+    }
+
+    async fn sign_in(&mut self, register: bool) {
+        let client = match Client::new(&self.login.server) {
+            Ok(client) => client,
+            Err(error) => return self.login.status = Some(error.to_string()),
+        };
+        if register {
+            if let Err(error) = client.signup(&self.login.username, &self.login.password).await {
+                return self.login.status = Some(error.to_string());
+            }
+        }
+        let session = match client.login(&self.login.username, &self.login.password).await {
+            Ok(session) => session,
+            Err(error) => return self.login.status = Some(error.to_string()),
+        };
+
+        match session.history().await {
+            Ok(history) => {
+                for line in history {
+                    self.push_history(session.username(), line);
+                }
+            },
+            Err(error) => self.status = format!("Не вдалося завантажити історію: {}", error),
+        }
+        for conversation in self.conversations.iter_mut() {
+            conversation.unread = 0;
+        }
+
+        let connection = session.connect();
+        self.sender = Some(connection.sender().clone());
+        self.connection = Some(connection);
+        self.session = Some(session);
+        self.login.password.clear();
+    }
+
+    fn push_history(&mut self, username: &str, line: String) {
+        if let Some(rest) = line.strip_prefix("Від ") {
+            if let Some((from, content)) = rest.split_once(": ") {
+                return self.push_line(from, format!("{}: {}", from, content));
+            }
+        }
+        if let Some(rest) = line.strip_prefix("До ") {
+            if let Some((to, content)) = rest.split_once(": ") {
+                return self.push_line(to, format!("{}: {}", username, content));
+            }
+        }
+        if let Some(rest) = line.strip_prefix('[') {
+            if let Some((room, content)) = rest.split_once("] ") {
+                return self.push_line(room, content.to_string());
+            }
+        }
+        self.push_line(PUBLIC, line);
+    }
+
+    async fn refresh_online(&mut self) {
+        let Some(session) = self.session.clone() else {
+            return;
+        };
+        match session.online_users().await {
+            Ok(users) => {
+                for conversation in self.conversations.iter_mut() {
+                    conversation.online = false;
+                }
+                for user in users.iter().filter(|user| user.as_str() != session.username()) {
+                    self.conversation(user).online = true;
+                }
+            },
+            Err(error) => self.status = format!("Не вдалося отримати список користувачів: {}", error),
+        }
+    }
+
+    async fn on_chat_key(&mut self, key: KeyEvent) {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Tab => self.select((self.selected + 1) % self.conversations.len()),
+            KeyCode::Char('n') if control => self.select((self.selected + 1) % self.conversations.len()),
+            KeyCode::BackTab => self.select((self.selected + self.conversations.len() - 1) % self.conversations.len()),
+            KeyCode::Char('p') if control => self.select((self.selected + self.conversations.len() - 1) % self.conversations.len()),
+            KeyCode::Up => self.scroll = (self.scroll + 1).min(self.current().lines.len()),
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll = (self.scroll + 10).min(self.current().lines.len()),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Enter => {
+                let input = std::mem::take(&mut self.input);
+                self.submit(input.trim()).await;
+            },
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Char(c) => self.input.push(c),
+            _ => {},
+        }
+    }
+
+    async fn submit(&mut self, input: &str) {
+        let Some(sender) = self.sender.clone() else {
+            return;
+        };
+        let recipient = self.current().key.clone();
+        let result = match input.split_once(' ').unwrap_or((input, "")) {
+            ("", _) => return,
+            ("/quit", _) => return self.quit = true,
+            ("/send", path) => self.send_file(&sender, &recipient, path.trim()).await,
+            ("/download", args) => self.download(args.trim()).await,
+            _ => sender.send_text(&recipient, input).map_err(|error| error.to_string()),
+        };
+        if let Err(error) = result {
+            self.status = error;
+        }
+        self.scroll = 0;
+    }
+
+    async fn send_file(&mut self, sender: &Sender, recipient: &str, path: &str) -> Result<(), String> {
+        if path.is_empty() {
+            return Err("Використання: /send <шлях до файлу>".to_string());
+        }
+        let data = tokio::fs::read(path).await.map_err(|error| format!("{}: {}", path, error))?;
+        let filename = Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        sender.send_file(recipient, &filename, data).map_err(|error| error.to_string())?;
+        self.status = format!("Файл '{}' надіслано", filename);
+        Ok(())
+    }
+
+    async fn download(&mut self, args: &str) -> Result<(), String> {
+        let mut args = args.split_whitespace();
+        let file_id = args.next().map(str::to_string)
+            .or_else(|| self.current().last_file.clone())
+            .ok_or_else(|| "Використання: /download [ідентифікатор файлу] [шлях]".to_string())?;
+        let path = args.next().map(str::to_string)
+            .or_else(|| self.files.get(&file_id).cloned())
+            .unwrap_or_else(|| file_id.clone());
+
+        let session = self.session.clone().ok_or_else(|| "Не виконано вхід".to_string())?;
+        let data = session.download(&file_id).await.map_err(|error| error.to_string())?;
+        tokio::fs::write(&path, &data).await.map_err(|error| format!("{}: {}", path, error))?;
+        self.status = format!("Збережено {} ({} байт)", path, data.len());
+        Ok(())
+    }
+
+    pub async fn on_event(&mut self, event: Event) {
+        match event {
+            Event::Connected => {
+                self.connected = true;
+                self.status = "Під'єднано".to_string();
+                self.refresh_online().await;
+            },
+            Event::Disconnected { reason } => {
+                self.connected = false;
+                self.status = format!("З'єднання втрачено: {}", reason);
+            },
+            Event::Server(event) => self.on_server_event(event),
+            Event::Unknown(_) => {},
+        }
+    }
+
+    fn partner(&self, from: &str, to: &str) -> String {
+        if from == self.username() { to.to_string() } else { from.to_string() }
+    }
+
+    fn on_server_event(&mut self, event: ServerEvent) {
+        match event {
+            ServerEvent::Public { from, content, .. } => self.push_line(PUBLIC, format!("{}: {}", from, content)),
+            ServerEvent::Room { room, from, content, .. } => self.push_line(&room, format!("{}: {}", from, content)),
+            ServerEvent::Group { group, from, content, .. } => self.push_line(&group, format!("{}: {}", from, content)),
+            ServerEvent::Private { from, to, content, .. } => {
+                let partner = self.partner(&from, &to);
+                self.push_line(&partner, format!("{}: {}", from, content));
+            },
+            ServerEvent::Encrypted { from, to, .. } => {
+                let partner = self.partner(&from, &to);
+                self.push_line(&partner, format!("{}: [зашифроване повідомлення]", from));
+            },
+            ServerEvent::File { from, to, file_id, filename, size, .. } => {
+                let key = if to == PUBLIC { PUBLIC.to_string() } else { self.partner(&from, &to) };
+                self.push_line(&key, format!("{}: [файл] {} ({} байт), /download {}", from, filename, size, file_id));
+                self.conversation(&key).last_file = Some(file_id.clone());
+                self.files.insert(file_id, filename);
+            },
+            ServerEvent::UserConnected { username } => self.conversation(&username).online = true,
+            ServerEvent::UserDisconnected { username } => self.conversation(&username).online = false,
+            ServerEvent::UserAway { username, message } => self.status = format!("{} відійшов: {}", username, message),
+            ServerEvent::UserBack { username } => self.status = format!("{} повернувся", username),
+            ServerEvent::UserRenamed { old, new } => {
+                if let Some(conversation) = self.conversations.iter_mut().find(|conversation| conversation.key == old) {
+                    conversation.key = new;
+                }
+            },
+            ServerEvent::Mention { from, conversation, .. } => self.status = format!("{} згадав вас у {}", from, conversation),
+            ServerEvent::CommandResult { command, result } => {
+                let key = self.current().key.clone();
+                self.push_line(&key, format!("* /{}: {}", command, result));
+            },
+            ServerEvent::CommandError { command, message } => {
+                let key = self.current().key.clone();
+                self.push_line(&key, format!("* /{}: {}", command, message));
+            },
+            ServerEvent::ServerShutdown { message, reconnect_after } => {
+                self.status = format!("{}, повторне під'єднання через {} с", message, reconnect_after);
+            },
+            ServerEvent::Error { message } => self.status = message,
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines<'a>(app: &'a App, key: &str) -> Vec<&'a str> {
+        let conversation = app.conversations.iter().find(|conversation| conversation.key == key).unwrap();
+        conversation.lines.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn history_lines_are_sorted_into_conversations() {
+        let mut app = App::new("http://127.0.0.1:8080".to_string());
+        app.push_history("alice", "Від bob: привіт".to_string());
+        app.push_history("alice", "До bob: і тобі".to_string());
+        app.push_history("alice", "[#rust] carol: hello".to_string());
+        app.push_history("alice", "dave: hi all".to_string());
+
+        assert_eq!(lines(&app, "bob"), vec!["bob: привіт", "alice: і тобі"]);
+        assert_eq!(lines(&app, "#rust"), vec!["carol: hello"]);
+        assert_eq!(lines(&app, PUBLIC), vec!["dave: hi all"]);
+        assert_eq!(app.conversations.iter().find(|conversation| conversation.key == "bob").unwrap().unread, 2);
+    }
+
+    #[test]
+    fn server_events_update_the_matching_conversation() {
+        let mut app = App::new("http://127.0.0.1:8080".to_string());
+        app.on_server_event(ServerEvent::Public {
+            id: "1".to_string(),
+            from: "bob".to_string(),
+            content: "hi".to_string(),
+            mentions: Vec::new(),
+            reply_to: None,
+            thread_id: None,
+        });
+        app.on_server_event(ServerEvent::UserConnected { username: "carol".to_string() });
+        app.on_server_event(ServerEvent::UserRenamed { old: "carol".to_string(), new: "caroline".to_string() });
+
+        assert_eq!(lines(&app, PUBLIC), vec!["bob: hi"]);
+        assert_eq!(app.current().unread, 0);
+        assert!(app.conversations.iter().any(|conversation| conversation.key == "caroline" && conversation.online));
+    }
+}
@@ -0,0 +1,37 @@
+mod app;
+mod ui;
+
+use std::io;
+use crossterm::event::{Event, EventStream, KeyEventKind};
+use futures_util::StreamExt;
+use ratatui::DefaultTerminal;
+use app::App;
+
+async fn run(terminal: &mut DefaultTerminal, mut app: App) -> io::Result<()> {
+    let mut input = EventStream::new();
+    while !app.quit {
+        terminal.draw(|frame| ui::draw(frame, &app))?;
+        tokio::select! {
+            event = input.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => app.on_key(key).await,
+                Some(Ok(_)) => {},
+                Some(Err(error)) => return Err(error),
+                None => return Ok(()),
+            },
+            Some(event) = app.next_event() => app.on_event(event).await,
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let server = std::env::args().nth(1)
+        .or_else(|| std::env::var("CHAT_SERVER").ok())
+        .unwrap_or_else(|| "http://127.0.0.1:8080".to_string());
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, App::new(server)).await;
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,96 @@
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::Frame;
+use crate::app::{App, Field};
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    if app.session.is_some() {
+        draw_chat(frame, app);
+    } else {
+        draw_login(frame, app);
+    }
+}
+
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let [area] = Layout::horizontal([Constraint::Length(width)]).flex(ratatui::layout::Flex::Center).areas(area);
+    let [area] = Layout::vertical([Constraint::Length(height)]).flex(ratatui::layout::Flex::Center).areas(area);
+    area
+}
+
+fn draw_login(frame: &mut Frame, app: &App) {
+    let form = &app.login;
+    let area = centered(frame.area(), 60, 11);
+    frame.render_widget(Block::bordered().title(" Вхід до чату "), area);
+
+    let [server, username, password, _, status, hint] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(1),
+        Constraint::Length(2),
+        Constraint::Length(1),
+    ]).margin(1).areas(area);
+
+    let masked = "*".repeat(form.password.chars().count());
+    let fields = [
+        (server, "Сервер:  ", form.server.as_str(), Field::Server),
+        (username, "Логін:   ", form.username.as_str(), Field::Username),
+        (password, "Пароль:  ", masked.as_str(), Field::Password),
+    ];
+    for (area, label, value, field) in fields {
+        let style = if form.focus == field { Style::default().add_modifier(Modifier::BOLD) } else { Style::default() };
+        frame.render_widget(Paragraph::new(Line::from(vec![Span::raw(label).dim(), Span::styled(value, style)])), area);
+        if form.focus == field {
+            let x = area.x + (label.chars().count() + value.chars().count()) as u16;
+            frame.set_cursor_position(Position::new(x.min(area.right().saturating_sub(1)), area.y));
+        }
+    }
+
+    if let Some(message) = &form.status {
+        frame.render_widget(Paragraph::new(message.as_str()).red().wrap(Wrap { trim: true }), status);
+    }
+    frame.render_widget(Paragraph::new("Enter — увійти, F2 — зареєструватися, Esc — вихід").dim(), hint);
+}
+
+fn draw_chat(frame: &mut Frame, app: &App) {
+    let [main, input, status] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let [sidebar, messages] = Layout::horizontal([Constraint::Length(28), Constraint::Min(10)]).areas(main);
+
+    let items: Vec<ListItem> = app.conversations.iter().map(|conversation| {
+        let marker = if conversation.online { Span::raw("● ").green() } else { Span::raw("○ ").dim() };
+        let mut line = vec![marker, Span::raw(conversation.title().to_string())];
+        if conversation.unread > 0 {
+            line.push(Span::raw(format!(" ({})", conversation.unread)).yellow());
+        }
+        ListItem::new(Line::from(line))
+    }).collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(" Розмови "))
+        .highlight_style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::BOLD));
+    frame.render_stateful_widget(list, sidebar, &mut ListState::default().with_selected(Some(app.selected)));
+
+    let conversation = app.current();
+    let lines: Vec<Line> = conversation.lines.iter().map(|line| Line::raw(line.as_str())).collect();
+    let block = Block::bordered().title(format!(" {} ", conversation.title()));
+    let inner = block.inner(messages);
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+    let total = paragraph.line_count(inner.width);
+    let offset = total.saturating_sub(inner.height as usize + app.scroll);
+    frame.render_widget(paragraph.block(block).scroll((offset.min(u16::MAX as usize) as u16, 0)), messages);
+
+    let block = Block::bordered().title(" Повідомлення (Tab — наступна розмова, /send, /download, /quit) ");
+    let inner = block.inner(input);
+    let visible = app.input.chars().count().saturating_sub(inner.width.saturating_sub(1) as usize);
+    let text: String = app.input.chars().skip(visible).collect();
+    frame.set_cursor_position(Position::new(inner.x + text.chars().count() as u16, inner.y));
+    frame.render_widget(Paragraph::new(text).block(block), input);
+
+    let state = if app.connected { Span::raw(" в мережі ").black().on_green() } else { Span::raw(" не в мережі ").black().on_red() };
+    frame.render_widget(Paragraph::new(Line::from(vec![state, Span::raw(" "), Span::raw(app.status.as_str())])), status);
+}